@@ -1,3 +1,6 @@
+use std::fmt;
+
+/// A mailbox `address@domain`, as in MAIL FROM and RCPT TO.
 #[derive(Debug, PartialEq, Eq)]
 pub struct Address {
     pub address: String,
@@ -21,3 +24,9 @@ impl Address {
         }
     }
 }
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.address, self.domain)
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use rand::{thread_rng, Rng};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use data::ParseError;
+use parse_util::read_line_bytes;
+use response::Response;
+use cram_md5::CramMd5;
+use scram::ScramSha256;
+use oauth::{OAuthBearer, XOAuth2};
+use plain::{Plain, Login};
+use auth_throttle::AuthThrottle;
+use session::SessionContext;
+
+pub use scram::ScramKeys;
+pub use oauth::{TokenError, TokenValidator};
+
+/// Channel binding data of the TLS connection a session runs over (RFC 5929),
+/// e.g. `tls-unique` or `tls-server-end-point`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChannelBinding {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl ChannelBinding {
+    pub fn new(name: &str, data: Vec<u8>) -> ChannelBinding {
+        ChannelBinding {
+            name: name.to_string(),
+            data: data,
+        }
+    }
+}
+
+/// What the server knows about a user. CRAM-MD5 needs the plaintext password,
+/// SCRAM only needs the salted keys.
+#[derive(Clone, Debug, Default)]
+pub struct StoredCredentials {
+    pub password: Option<String>,
+    pub scram_sha256: Option<ScramKeys>,
+}
+
+pub trait CredentialStore: Send + Sync {
+    fn lookup(&self, username: &str) -> Option<StoredCredentials>;
+
+    /// Whether `lookup` gives out the passwords or SCRAM keys CRAM-MD5 and
+    /// SCRAM need. Stores that can only check passwords, like ones holding
+    /// password hashes, return false so these mechanisms are not offered.
+    fn supports_challenge_response(&self) -> bool {
+        true
+    }
+
+    /// Checks a plaintext password as sent with PLAIN and LOGIN.
+    fn verify_password(&self, username: &str, password: &str) -> bool {
+        match self.lookup(username) {
+            Some(StoredCredentials { password: Some(ref stored), .. }) => {
+                constant_time_eq(stored.as_bytes(), password.as_bytes())
+            }
+            Some(StoredCredentials { scram_sha256: Some(ref keys), .. }) => {
+                let derived = ScramKeys::derive(password, &keys.salt, keys.iterations);
+                constant_time_eq(&derived.stored_key, &keys.stored_key)
+            }
+            _ => false,
+        }
+    }
+}
+
+pub struct StaticCredentialStore {
+    users: HashMap<String, StoredCredentials>,
+}
+
+impl StaticCredentialStore {
+    pub fn new() -> StaticCredentialStore {
+        StaticCredentialStore { users: HashMap::new() }
+    }
+
+    /// Stores the plaintext password together with freshly salted SCRAM keys.
+    pub fn add_password(&mut self, username: &str, password: &str) {
+        let salt = thread_rng().gen_iter::<u8>().take(16).collect::<Vec<u8>>();
+        let credentials = StoredCredentials {
+            password: Some(password.to_string()),
+            scram_sha256: Some(ScramKeys::derive(password, &salt, 4096)),
+        };
+        self.users.insert(username.to_string(), credentials);
+    }
+
+    /// Stores only SCRAM keys; the user cannot use CRAM-MD5.
+    pub fn add_scram_keys(&mut self, username: &str, keys: ScramKeys) {
+        let credentials = StoredCredentials {
+            password: None,
+            scram_sha256: Some(keys),
+        };
+        self.users.insert(username.to_string(), credentials);
+    }
+}
+
+impl CredentialStore for StaticCredentialStore {
+    fn lookup(&self, username: &str) -> Option<StoredCredentials> {
+        self.users.get(username).cloned()
+    }
+}
+
+pub enum SaslStep {
+    Challenge(Vec<u8>),
+    /// Authenticated identity plus optional additional data for the client.
+    Success(String, Option<Vec<u8>>),
+    Failure,
+    /// Error details sent as a last challenge; the exchange fails whatever
+    /// the client answers (RFC 7628 section 3.2.2).
+    ErrorChallenge(Vec<u8>),
+}
+
+pub trait SaslMechanism {
+    /// `response` is `None` when the client gave no initial response.
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep;
+
+    /// The user the client claims to be, once known. Failures are counted
+    /// against it.
+    fn username(&self) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    UnsupportedMechanism,
+    Cancelled,
+    MalformedResponse,
+    InvalidCredentials,
+    LockedOut,
+    IOError(io::Error),
+    ParseError(ParseError),
+}
+
+impl AuthError {
+    pub fn to_response(&self) -> Response {
+        match *self {
+            AuthError::UnsupportedMechanism => {
+                Response::new(504, "5.5.4 Unrecognized authentication type")
+            }
+            AuthError::Cancelled => Response::new(501, "5.0.0 Authentication cancelled"),
+            AuthError::MalformedResponse => Response::new(501, "5.5.2 Cannot decode response"),
+            AuthError::InvalidCredentials => {
+                Response::new(535, "5.7.8 Authentication credentials invalid")
+            }
+            AuthError::LockedOut => {
+                Response::new(454,
+                              "4.7.0 Too many authentication failures, try again later")
+            }
+            AuthError::IOError(_) | AuthError::ParseError(_) => {
+                Response::new(454, "4.7.0 Temporary authentication failure")
+            }
+        }
+    }
+}
+
+pub struct Authenticator {
+    credentials: Arc<CredentialStore>,
+    token_validator: Option<Arc<TokenValidator>>,
+    throttle: Option<Arc<AuthThrottle>>,
+    // Gives unknown users a stable SCRAM salt for as long as this lives.
+    salt_secret: Vec<u8>,
+}
+
+impl Authenticator {
+    pub fn new(credentials: Arc<CredentialStore>) -> Authenticator {
+        Authenticator {
+            credentials: credentials,
+            token_validator: None,
+            throttle: None,
+            salt_secret: thread_rng().gen_iter::<u8>().take(32).collect(),
+        }
+    }
+
+    /// Enables OAUTHBEARER and XOAUTH2, checking bearer tokens with `validator`.
+    pub fn with_token_validator(mut self, validator: Arc<TokenValidator>) -> Authenticator {
+        self.token_validator = Some(validator);
+        self
+    }
+
+    /// Counts failures and locks out clients and users that fail too often.
+    pub fn with_throttle(mut self, throttle: Arc<AuthThrottle>) -> Authenticator {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    pub fn throttle(&self) -> Option<&Arc<AuthThrottle>> {
+        self.throttle.as_ref()
+    }
+
+    /// Mechanisms to advertise in the EHLO response. PLAIN and LOGIN send the
+    /// password in the clear and are only offered over TLS, as are the bearer
+    /// token mechanisms (RFC 7628 section 5).
+    pub fn mechanisms(&self, session: &SessionContext) -> Vec<&'static str> {
+        let mut mechanisms = Vec::new();
+        if self.credentials.supports_challenge_response() {
+            mechanisms.push("CRAM-MD5");
+            mechanisms.push("SCRAM-SHA-256");
+            if session.channel_binding().is_some() {
+                mechanisms.push("SCRAM-SHA-256-PLUS");
+            }
+        }
+        if session.is_tls() {
+            mechanisms.push("PLAIN");
+            mechanisms.push("LOGIN");
+        }
+        if self.token_validator.is_some() && session.is_tls() {
+            mechanisms.push("OAUTHBEARER");
+            mechanisms.push("XOAUTH2");
+        }
+        mechanisms
+    }
+
+    pub fn start(&self,
+                 mechanism: &str,
+                 hostname: &str,
+                 session: &SessionContext)
+                 -> Option<Box<SaslMechanism>> {
+        let channel_binding = session.channel_binding();
+        let challenge_response = self.credentials.supports_challenge_response();
+        let mechanism = mechanism.to_ascii_uppercase();
+        match &mechanism[..] {
+            "CRAM-MD5" if challenge_response => {
+                Some(Box::new(CramMd5::new(self.credentials.clone(), hostname)))
+            }
+            "SCRAM-SHA-256" if challenge_response => {
+                Some(Box::new(ScramSha256::new(self.credentials.clone(),
+                                               channel_binding.cloned(),
+                                               false,
+                                               &self.salt_secret)))
+            }
+            "SCRAM-SHA-256-PLUS" if challenge_response && channel_binding.is_some() => {
+                Some(Box::new(ScramSha256::new(self.credentials.clone(),
+                                               channel_binding.cloned(),
+                                               true,
+                                               &self.salt_secret)))
+            }
+            "PLAIN" if session.is_tls() => {
+                Some(Box::new(Plain::new(self.credentials.clone())))
+            }
+            "LOGIN" if session.is_tls() => {
+                Some(Box::new(Login::new(self.credentials.clone())))
+            }
+            "OAUTHBEARER" if session.is_tls() => {
+                self.token_validator
+                    .as_ref()
+                    .map(|v| Box::new(OAuthBearer::new(v.clone())) as Box<SaslMechanism>)
+            }
+            "XOAUTH2" if session.is_tls() => {
+                self.token_validator
+                    .as_ref()
+                    .map(|v| Box::new(XOAuth2::new(v.clone())) as Box<SaslMechanism>)
+            }
+            _ => None,
+        }
+    }
+
+    /// Runs the AUTH exchange of RFC 4954 on `conn` and returns the
+    /// authenticated identity. The caller writes the final 235 or error reply.
+    pub fn authenticate<C: Read + Write>(&self,
+                                         conn: &mut C,
+                                         mechanism: &str,
+                                         initial_response: Option<&str>,
+                                         hostname: &str,
+                                         session: &SessionContext)
+                                         -> Result<String, AuthError> {
+        let peer = session.peer_addr.map(|addr| addr.ip());
+        if let (Some(throttle), Some(ip)) = (self.throttle.as_ref(), peer) {
+            if throttle.is_ip_locked(&ip) || throttle.is_ip_delayed(&ip) {
+                return Err(AuthError::LockedOut);
+            }
+        }
+
+        let mut mechanism = match self.start(mechanism, hostname, session) {
+            Some(mechanism) => mechanism,
+            None => return Err(AuthError::UnsupportedMechanism),
+        };
+
+        let result = self._run(conn, &mut *mechanism, initial_response);
+        if let Some(ref throttle) = self.throttle {
+            match result {
+                Ok(ref identity) => throttle.record_success(peer, identity),
+                Err(AuthError::InvalidCredentials) => {
+                    let username = mechanism.username();
+                    throttle.record_failure(peer, username.as_ref().map(|u| &u[..]));
+                }
+                _ => (),
+            }
+        }
+        result
+    }
+
+    fn _run<C: Read + Write>(&self,
+                             conn: &mut C,
+                             mechanism: &mut SaslMechanism,
+                             initial_response: Option<&str>)
+                             -> Result<String, AuthError> {
+        let initial_response = match initial_response {
+            Some("=") => Some(Vec::new()),
+            Some(encoded) => Some(try!(_decode_response(encoded.as_bytes()))),
+            None => None,
+        };
+
+        let mut step = mechanism.step(initial_response.as_ref().map(|r| &r[..]));
+        loop {
+            step = match step {
+                SaslStep::Challenge(challenge) => {
+                    let response = try!(_exchange(conn, &challenge));
+                    mechanism.step(Some(&response))
+                }
+                SaslStep::Success(ref identity, _) if self._is_user_locked(identity) => {
+                    return Err(AuthError::InvalidCredentials);
+                }
+                SaslStep::Success(identity, Some(additional_data)) => {
+                    // RFC 4954 section 4: additional data is sent as a final
+                    // challenge, which the client answers with an empty line.
+                    let response = try!(_exchange(conn, &additional_data));
+                    if !response.is_empty() {
+                        return Err(AuthError::MalformedResponse);
+                    }
+                    return Ok(identity);
+                }
+                SaslStep::Success(identity, None) => return Ok(identity),
+                SaslStep::Failure => return Err(AuthError::InvalidCredentials),
+                SaslStep::ErrorChallenge(error) => {
+                    return match _exchange(conn, &error) {
+                        Err(err @ AuthError::IOError(_)) => Err(err),
+                        Err(err @ AuthError::ParseError(_)) => Err(err),
+                        _ => Err(AuthError::InvalidCredentials),
+                    };
+                }
+            }
+        }
+    }
+
+    // A locked out user is refused like a wrong password, so the lockout
+    // does not tell an attacker that the password was right.
+    fn _is_user_locked(&self, username: &str) -> bool {
+        self.throttle.as_ref().map_or(false, |t| t.is_user_locked(username))
+    }
+}
+
+fn _exchange<C: Read + Write>(conn: &mut C, challenge: &[u8]) -> Result<Vec<u8>, AuthError> {
+    let challenge = Response::new(334, &challenge.to_base64(STANDARD));
+    try!(conn.write_all(&challenge.to_bytes()).map_err(AuthError::IOError));
+    try!(conn.flush().map_err(AuthError::IOError));
+
+    let mut line = try!(read_line_bytes(conn).map_err(AuthError::ParseError));
+    let without_crlf = line.len() - 2;
+    line.truncate(without_crlf);
+    if line == b"*" {
+        return Err(AuthError::Cancelled);
+    }
+    _decode_response(&line)
+}
+
+fn _decode_response(encoded: &[u8]) -> Result<Vec<u8>, AuthError> {
+    encoded.from_base64().map_err(|_| AuthError::MalformedResponse)
+}
+
+pub fn random_nonce(len: usize) -> String {
+    thread_rng().gen_ascii_chars().take(len).collect()
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (&x, &y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,272 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Thresholds for `AuthThrottle`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThrottleConfig {
+    /// Failures from one client address before it is locked out.
+    pub max_failures_per_ip: u32,
+    /// Failures for one username before it is locked out.
+    pub max_failures_per_user: u32,
+    /// Failures older than this are forgotten.
+    pub failure_window: Duration,
+    pub lockout_duration: Duration,
+    /// Each failure from an address makes it wait this much longer before
+    /// it may try again... Attempts during the wait are refused at once
+    /// rather than answered late, so that no worker thread sits idle.
+    pub delay_per_failure: Duration,
+    /// ...up to this limit.
+    pub max_delay: Duration,
+    /// The connection is closed after this many failures in one session.
+    pub max_failures_per_session: u32,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> ThrottleConfig {
+        ThrottleConfig {
+            max_failures_per_ip: 10,
+            max_failures_per_user: 5,
+            failure_window: Duration::from_secs(15 * 60),
+            lockout_duration: Duration::from_secs(15 * 60),
+            delay_per_failure: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            max_failures_per_session: 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    User(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct Lockout {
+    pub key: LockoutKey,
+    pub failures: u32,
+    pub remaining: Duration,
+}
+
+struct FailureRecord {
+    failures: u32,
+    first_failure: Instant,
+    locked_until: Option<Instant>,
+    delayed_until: Option<Instant>,
+}
+
+impl FailureRecord {
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.map_or(false, |until| until > now)
+    }
+
+    fn is_stale(&self, now: Instant, window: Duration) -> bool {
+        !self.is_locked(now) && now.duration_since(self.first_failure) > window
+    }
+}
+
+// Records for clients that stopped trying are pruned once a table grows this large.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Counts failed authentications per client address and per username, and
+/// locks out offenders for a while. Shared by all sessions of a server.
+pub struct AuthThrottle {
+    config: ThrottleConfig,
+    ips: Mutex<HashMap<IpAddr, FailureRecord>>,
+    users: Mutex<HashMap<String, FailureRecord>>,
+}
+
+impl AuthThrottle {
+    pub fn new(config: ThrottleConfig) -> AuthThrottle {
+        AuthThrottle {
+            config: config,
+            ips: Mutex::new(HashMap::new()),
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn config(&self) -> &ThrottleConfig {
+        &self.config
+    }
+
+    pub fn is_ip_locked(&self, ip: &IpAddr) -> bool {
+        let now = Instant::now();
+        self.ips.lock().unwrap().get(ip).map_or(false, |r| r.is_locked(now))
+    }
+
+    /// Whether the client still has to wait after its last failure.
+    pub fn is_ip_delayed(&self, ip: &IpAddr) -> bool {
+        let now = Instant::now();
+        self.ips
+            .lock()
+            .unwrap()
+            .get(ip)
+            .and_then(|r| r.delayed_until)
+            .map_or(false, |until| until > now)
+    }
+
+    pub fn is_user_locked(&self, username: &str) -> bool {
+        let now = Instant::now();
+        self.users.lock().unwrap().get(username).map_or(false, |r| r.is_locked(now))
+    }
+
+    /// Records a failed attempt and returns how long the client has to wait
+    /// before its next one.
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: Option<&str>) -> Duration {
+        let mut delay = Duration::from_secs(0);
+        if let Some(ip) = ip {
+            let mut ips = self.ips.lock().unwrap();
+            let failures = _record(&mut ips, ip, self.config.max_failures_per_ip, &self.config);
+            delay = min(self.config.delay_per_failure * failures, self.config.max_delay);
+            ips.get_mut(&ip).unwrap().delayed_until = Some(Instant::now() + delay);
+        }
+        if let Some(username) = username {
+            _record(&mut self.users.lock().unwrap(),
+                    username.to_string(),
+                    self.config.max_failures_per_user,
+                    &self.config);
+        }
+        delay
+    }
+
+    /// Forgets the failures of a client and user that just authenticated.
+    /// Lockouts stay in place.
+    pub fn record_success(&self, ip: Option<IpAddr>, username: &str) {
+        let now = Instant::now();
+        if let Some(ip) = ip {
+            let mut ips = self.ips.lock().unwrap();
+            if ips.get(&ip).map_or(false, |r| !r.is_locked(now)) {
+                ips.remove(&ip);
+            }
+        }
+        let mut users = self.users.lock().unwrap();
+        if users.get(username).map_or(false, |r| !r.is_locked(now)) {
+            users.remove(username);
+        }
+    }
+
+    /// All lockouts currently in force.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = Instant::now();
+        let mut lockouts = Vec::new();
+        for (ip, record) in self.ips.lock().unwrap().iter() {
+            if let Some(until) = record.locked_until.filter(|&until| until > now) {
+                lockouts.push(Lockout {
+                    key: LockoutKey::Ip(*ip),
+                    failures: record.failures,
+                    remaining: until - now,
+                });
+            }
+        }
+        for (username, record) in self.users.lock().unwrap().iter() {
+            if let Some(until) = record.locked_until.filter(|&until| until > now) {
+                lockouts.push(Lockout {
+                    key: LockoutKey::User(username.clone()),
+                    failures: record.failures,
+                    remaining: until - now,
+                });
+            }
+        }
+        lockouts
+    }
+
+    /// Clears the failures and any lockout of `key`. Returns whether there was any.
+    pub fn clear(&self, key: &LockoutKey) -> bool {
+        match *key {
+            LockoutKey::Ip(ref ip) => self.ips.lock().unwrap().remove(ip).is_some(),
+            LockoutKey::User(ref username) => self.users.lock().unwrap().remove(username).is_some(),
+        }
+    }
+
+    pub fn clear_all(&self) {
+        self.ips.lock().unwrap().clear();
+        self.users.lock().unwrap().clear();
+    }
+}
+
+// Returns the number of failures within the window, including this one.
+fn _record<K: Hash + Eq>(records: &mut HashMap<K, FailureRecord>,
+                         key: K,
+                         max_failures: u32,
+                         config: &ThrottleConfig)
+                         -> u32 {
+    let now = Instant::now();
+    if records.len() > PRUNE_THRESHOLD {
+        records.retain(|_, r| !r.is_stale(now, config.failure_window));
+    }
+
+    let record = records.entry(key).or_insert(FailureRecord {
+        failures: 0,
+        first_failure: now,
+        locked_until: None,
+        delayed_until: None,
+    });
+    if record.is_stale(now, config.failure_window) {
+        record.failures = 0;
+        record.first_failure = now;
+        record.locked_until = None;
+    }
+    record.failures += 1;
+    if record.failures >= max_failures && !record.is_locked(now) {
+        record.locked_until = Some(now + config.lockout_duration);
+    }
+    record.failures
+}
+
+pub mod tests {
+    use std::net::IpAddr;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use auth_throttle::{AuthThrottle, LockoutKey, ThrottleConfig};
+
+    fn throttle() -> AuthThrottle {
+        AuthThrottle::new(ThrottleConfig {
+            max_failures_per_ip: 3,
+            max_failures_per_user: 2,
+            delay_per_failure: Duration::from_millis(100),
+            max_delay: Duration::from_millis(250),
+            ..ThrottleConfig::default()
+        })
+    }
+
+    #[test]
+    fn locks_out_ip_with_progressive_delay() {
+        let throttle = throttle();
+        let ip = "192.0.2.1".parse::<IpAddr>().unwrap();
+        assert_eq!(Duration::from_millis(100), throttle.record_failure(Some(ip), None));
+        assert!(throttle.is_ip_delayed(&ip));
+        sleep(Duration::from_millis(150));
+        assert!(!throttle.is_ip_delayed(&ip));
+        assert_eq!(Duration::from_millis(200), throttle.record_failure(Some(ip), None));
+        assert!(!throttle.is_ip_locked(&ip));
+        assert_eq!(Duration::from_millis(250), throttle.record_failure(Some(ip), None));
+        assert!(throttle.is_ip_locked(&ip));
+
+        let lockouts = throttle.lockouts();
+        assert_eq!(1, lockouts.len());
+        assert_eq!(LockoutKey::Ip(ip), lockouts[0].key);
+        assert_eq!(3, lockouts[0].failures);
+
+        assert!(throttle.clear(&LockoutKey::Ip(ip)));
+        assert!(!throttle.is_ip_locked(&ip));
+    }
+
+    #[test]
+    fn locks_out_user_across_addresses() {
+        let throttle = throttle();
+        throttle.record_failure(Some("192.0.2.1".parse().unwrap()), Some("alice"));
+        throttle.record_failure(Some("192.0.2.2".parse().unwrap()), Some("alice"));
+        assert!(throttle.is_user_locked("alice"));
+
+        // a correct password does not lift the lockout
+        throttle.record_success(None, "alice");
+        assert!(throttle.is_user_locked("alice"));
+
+        throttle.clear_all();
+        assert!(!throttle.is_user_locked("alice"));
+    }
+}
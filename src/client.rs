@@ -0,0 +1,347 @@
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use native_tls::{self, TlsConnector, TlsStream};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use parse_util::read_line_bytes;
+use data::ParseError;
+
+/// A server reply, e.g. `250 OK`. Multiline replies keep one entry per line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn is_positive(&self) -> bool {
+        self.code < 400
+    }
+
+    /// A 4xx reply, the command may succeed later.
+    pub fn is_transient(&self) -> bool {
+        self.code >= 400 && self.code < 500
+    }
+}
+
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code, self.lines.join(" "))
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    IOError(io::Error),
+    Tls(String),
+    /// The server answered with something that is not an SMTP reply.
+    Malformed(String),
+    /// The server refused a command that has to succeed.
+    Rejected(Reply),
+}
+
+impl ClientError {
+    /// Whether trying again later might help.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            ClientError::Rejected(ref reply) => reply.is_transient(),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientError::IOError(ref err) => write!(f, "{}", err),
+            ClientError::Tls(ref message) => write!(f, "TLS: {}", message),
+            ClientError::Malformed(ref line) => write!(f, "malformed reply {:?}", line),
+            ClientError::Rejected(ref reply) => write!(f, "{}", reply),
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::IOError(err)
+    }
+}
+
+enum ClientStream {
+    Plain(TcpStream),
+    Tls(TlsStream<TcpStream>),
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Plain(ref mut stream) => stream.read(buf),
+            ClientStream::Tls(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            ClientStream::Plain(ref mut stream) => stream.write(buf),
+            ClientStream::Tls(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            ClientStream::Plain(ref mut stream) => stream.flush(),
+            ClientStream::Tls(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// The client side of an SMTP session, one command at a time.
+pub struct SmtpClient {
+    stream: ClientStream,
+    host: String,
+    extensions: Vec<String>,
+}
+
+impl SmtpClient {
+    /// Connects and reads the greeting. With `implicit_tls` the TLS
+    /// handshake happens first (RFC 8314).
+    pub fn connect(host: &str,
+                   port: u16,
+                   implicit_tls: bool,
+                   timeout: Duration)
+                   -> Result<SmtpClient, ClientError> {
+        let stream = try!(TcpStream::connect((host, port)));
+        SmtpClient::_start(stream, host, implicit_tls, timeout)
+    }
+
+    /// Connects to `address`, e.g. one of the addresses of an MX host, and
+    /// reads the greeting. `host` is the name TLS certificates are checked
+    /// against. Unlike `connect` gives up on unreachable hosts after
+    /// `connect_timeout`.
+    pub fn connect_to(address: &SocketAddr,
+                      host: &str,
+                      connect_timeout: Duration,
+                      timeout: Duration)
+                      -> Result<SmtpClient, ClientError> {
+        let stream = try!(TcpStream::connect_timeout(address, connect_timeout));
+        SmtpClient::_start(stream, host, false, timeout)
+    }
+
+    fn _start(stream: TcpStream,
+              host: &str,
+              implicit_tls: bool,
+              timeout: Duration)
+              -> Result<SmtpClient, ClientError> {
+        try!(stream.set_read_timeout(Some(timeout)));
+        try!(stream.set_write_timeout(Some(timeout)));
+        let stream = if implicit_tls {
+            ClientStream::Tls(try!(_tls_connect(host, stream)))
+        } else {
+            ClientStream::Plain(stream)
+        };
+        let mut client = SmtpClient {
+            stream: stream,
+            host: host.to_string(),
+            extensions: Vec::new(),
+        };
+        try!(client._expect(220));
+        Ok(client)
+    }
+
+    /// Sends EHLO, or HELO for servers that do not know EHLO.
+    pub fn hello(&mut self, name: &str) -> Result<(), ClientError> {
+        let reply = try!(self.command(&format!("EHLO {}", name)));
+        if reply.is_positive() {
+            self.extensions = reply.lines
+                                   .iter()
+                                   .skip(1)
+                                   .map(|line| line.to_uppercase())
+                                   .collect();
+            return Ok(());
+        }
+        self.extensions = Vec::new();
+        let reply = try!(self.command(&format!("HELO {}", name)));
+        _positive(reply).map(|_| ())
+    }
+
+    /// Whether the last EHLO reply announced `extension`, e.g. `STARTTLS`.
+    pub fn has_extension(&self, extension: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|line| line.split(' ').next() == Some(&extension.to_uppercase()[..]))
+    }
+
+    /// The parameters announced with `extension`, e.g. the mechanisms of
+    /// `AUTH`.
+    pub fn extension_parameters(&self, extension: &str) -> Vec<String> {
+        let extension = extension.to_uppercase();
+        self.extensions
+            .iter()
+            .filter(|line| line.split(' ').next() == Some(&extension[..]))
+            .flat_map(|line| line.split(' ').skip(1).map(|p| p.to_string()))
+            .collect()
+    }
+
+    /// Upgrades the connection (RFC 3207). EHLO has to be sent again
+    /// afterwards.
+    pub fn starttls(self) -> Result<SmtpClient, ClientError> {
+        let mut client = self;
+        let reply = try!(client.command("STARTTLS"));
+        if reply.code != 220 {
+            return Err(ClientError::Rejected(reply));
+        }
+        let stream = match client.stream {
+            ClientStream::Plain(stream) => try!(_tls_connect(&client.host, stream)),
+            ClientStream::Tls(_) => return Err(ClientError::Tls("already encrypted".to_string())),
+        };
+        Ok(SmtpClient {
+            stream: ClientStream::Tls(stream),
+            host: client.host,
+            extensions: Vec::new(),
+        })
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match self.stream {
+            ClientStream::Tls(_) => true,
+            ClientStream::Plain(_) => false,
+        }
+    }
+
+    /// AUTH PLAIN with an initial response (RFC 4616).
+    pub fn auth_plain(&mut self, username: &str, password: &str) -> Result<(), ClientError> {
+        let credentials = format!("\0{}\0{}", username, password);
+        let reply = try!(self.command(&format!("AUTH PLAIN {}",
+                                               credentials.as_bytes().to_base64(STANDARD))));
+        if reply.code == 235 {
+            Ok(())
+        } else {
+            Err(ClientError::Rejected(reply))
+        }
+    }
+
+    /// Sends one command line and returns the reply, whatever it is.
+    pub fn command(&mut self, command: &str) -> Result<Reply, ClientError> {
+        try!(self.stream.write_all(command.as_bytes()));
+        try!(self.stream.write_all(b"\r\n"));
+        try!(self.stream.flush());
+        self.read_reply()
+    }
+
+    /// Sends DATA and the dot-stuffed message, and returns the reply to the
+    /// final dot.
+    pub fn data(&mut self, data: &[u8]) -> Result<Reply, ClientError> {
+        let reply = try!(self.command("DATA"));
+        if reply.code != 354 {
+            return Ok(reply);
+        }
+        try!(self.stream.write_all(&dot_stuff(data)));
+        try!(self.stream.write_all(b".\r\n"));
+        try!(self.stream.flush());
+        self.read_reply()
+    }
+
+    /// Says goodbye, ignoring what the server thinks of it.
+    pub fn quit(mut self) {
+        let _ = self.command("QUIT");
+    }
+
+    pub fn read_reply(&mut self) -> Result<Reply, ClientError> {
+        let mut lines = Vec::new();
+        loop {
+            let line = try!(read_line_bytes(&mut self.stream).map_err(_read_error));
+            let line = String::from_utf8_lossy(&line[..line.len() - 2]).into_owned();
+            let code = match line.chars().take(3).collect::<String>().parse::<u16>() {
+                Ok(code) if code >= 200 && code < 600 => code,
+                _ => return Err(ClientError::Malformed(line)),
+            };
+            let (separator, text) = (line[3..].chars().next(), line[3..].chars().skip(1));
+            lines.push(text.collect());
+            match separator {
+                Some('-') => continue,
+                Some(' ') | None => {
+                    return Ok(Reply {
+                        code: code,
+                        lines: lines,
+                    })
+                }
+                Some(_) => return Err(ClientError::Malformed(line)),
+            }
+        }
+    }
+
+    fn _expect(&mut self, code: u16) -> Result<Reply, ClientError> {
+        let reply = try!(self.read_reply());
+        if reply.code == code {
+            Ok(reply)
+        } else {
+            Err(ClientError::Rejected(reply))
+        }
+    }
+}
+
+/// Escapes lines starting with a dot (RFC 5321 section 4.5.2) and makes sure
+/// the data ends with CRLF, so the final dot is on a line of its own.
+pub fn dot_stuff(data: &[u8]) -> Vec<u8> {
+    let mut stuffed = Vec::with_capacity(data.len() + 2);
+    let mut line_start = true;
+    for &byte in data {
+        if line_start && byte == b'.' {
+            stuffed.push(b'.');
+        }
+        stuffed.push(byte);
+        line_start = byte == b'\n';
+    }
+    if !stuffed.is_empty() && !stuffed.ends_with(b"\r\n") {
+        stuffed.extend(b"\r\n");
+    }
+    stuffed
+}
+
+fn _read_error(err: ParseError) -> ClientError {
+    match err {
+        ParseError::Timeout => {
+            ClientError::IOError(io::Error::new(io::ErrorKind::TimedOut, "no reply"))
+        }
+        ParseError::UnexpectedEndOfInput => {
+            ClientError::IOError(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                "connection closed"))
+        }
+        err => ClientError::Malformed(format!("{:?}", err)),
+    }
+}
+
+fn _positive(reply: Reply) -> Result<Reply, ClientError> {
+    if reply.is_positive() {
+        Ok(reply)
+    } else {
+        Err(ClientError::Rejected(reply))
+    }
+}
+
+fn _tls_connect(host: &str, stream: TcpStream) -> Result<TlsStream<TcpStream>, ClientError> {
+    let connector = try!(TlsConnector::new().map_err(|err| ClientError::Tls(err.to_string())));
+    match connector.connect(host, stream) {
+        Ok(stream) => Ok(stream),
+        Err(native_tls::HandshakeError::Failure(err)) => Err(ClientError::Tls(err.to_string())),
+        Err(native_tls::HandshakeError::WouldBlock(_)) => {
+            Err(ClientError::Tls("handshake interrupted".to_string()))
+        }
+    }
+}
+
+pub mod tests {
+    use client::dot_stuff;
+
+    #[test]
+    fn stuffs_dots_and_terminates_data() {
+        assert_eq!(b"..hidden\r\na.b\r\n...\r\n".to_vec(),
+                   dot_stuff(b".hidden\r\na.b\r\n..\r\n"));
+        assert_eq!(b"no newline\r\n".to_vec(), dot_stuff(b"no newline"));
+        assert_eq!(Vec::<u8>::new(), dot_stuff(b""));
+    }
+}
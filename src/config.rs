@@ -0,0 +1,1251 @@
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::u32;
+use toml::{Parser, Table, Value};
+use auth_throttle::ThrottleConfig;
+use local::is_mailbox_name;
+
+/// Everything the `smtp` binary needs to run a server. Build it in code,
+/// starting from `ServerConfig::default()`, or load it from a TOML file:
+///
+/// ```toml
+/// hostname = "mx.example.org"
+/// banner = "rust-smtp"
+///
+/// [[listeners]]
+/// address = "0.0.0.0:25"
+///
+/// [[listeners]]
+/// address = "0.0.0.0:587"
+/// role = "submission"
+///
+/// [[listeners]]
+/// address = "0.0.0.0:465"
+/// role = "smtps"
+/// policies = ["sender_matches_login"]
+///
+/// [limits]
+/// max_hops = 100
+/// max_connections = 100
+/// max_connections_per_ip = 20
+/// workers = 100
+///
+/// [timeouts]
+/// greeting = 300
+/// command = 300
+/// data_block = 180
+/// data_end = 600
+/// session = 1800
+/// shutdown_grace = 60
+///
+/// [tls]
+/// certificate = "/etc/smtp/cert.pem"
+/// private_key = "/etc/smtp/key.pem"
+///
+/// [auth]
+/// password_file = "/etc/smtp/passwd"
+/// max_failures_per_ip = 10
+/// max_failures_per_user = 5
+/// failure_window = 900
+/// lockout_duration = 900
+/// delay_per_failure = 1
+/// max_delay = 10
+/// max_failures_per_session = 3
+///
+/// [delivery]
+/// received_header = true
+///
+/// [relay]
+/// host = "smtp.example.net"
+/// port = 587
+/// tls = "starttls"
+/// username = "mx"
+/// password = "secret"
+/// hello_name = "mx.example.org"
+///
+/// [queue]
+/// directory = "/var/spool/smtp"
+/// backend = "filesystem"
+/// initial_retry = 300
+/// max_retry = 14400
+/// max_lifetime = 432000
+///
+/// [local]
+/// domains = ["example.org"]
+/// directory = "/home"
+/// format = "maildir"
+/// users = "static"
+///
+/// [local.mailboxes]
+/// marie = "marie"
+///
+/// [local.aliases]
+/// postmaster = ["marie", "max@example.net"]
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerConfig {
+    /// Our name in the greeting, EHLO response and Received headers.
+    pub hostname: String,
+    /// Software name shown after `ESMTP` in the greeting.
+    pub banner: String,
+    pub listeners: Vec<ListenerConfig>,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub tls: Option<TlsConfig>,
+    pub auth: AuthConfig,
+    pub delivery: DeliveryConfig,
+    /// Smarthost to hand outgoing mail to, needs `queue`. Without one queued
+    /// mail goes straight to the MX hosts of each recipient domain.
+    pub relay: Option<RelayConfig>,
+    /// Keeps accepted messages on disk until they were delivered.
+    pub queue: Option<QueueConfig>,
+    /// Domains whose mail is delivered to mailboxes on this host.
+    pub local: Option<LocalConfig>,
+}
+
+/// What a listener is for. Decides the defaults of its other settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListenerRole {
+    /// Port 25, mail from other servers.
+    Relay,
+    /// Port 587, mail from our own users (RFC 6409).
+    Submission,
+    /// Port 465, submission over implicit TLS (RFC 8314).
+    Smtps,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TlsMode {
+    Disabled,
+    /// Plaintext until the client sends STARTTLS (RFC 3207).
+    StartTls,
+    /// TLS from the first byte on.
+    Implicit,
+}
+
+/// Additional rules a listener enforces on MAIL FROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    /// The sender must be the authenticated user (RFC 6409 section 6.1).
+    SenderMatchesLogin,
+    /// No mail transaction before STARTTLS.
+    RequireTls,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    pub role: ListenerRole,
+    pub tls: TlsMode,
+    /// Refuse MAIL before a successful AUTH.
+    pub require_auth: bool,
+    pub policies: Vec<Policy>,
+}
+
+impl ListenerConfig {
+    /// A listener with the defaults of `role`: relay listeners are open and
+    /// plaintext, submission requires STARTTLS and AUTH, SMTPS requires AUTH.
+    pub fn new(address: SocketAddr, role: ListenerRole) -> ListenerConfig {
+        let (tls, require_auth, policies) = match role {
+            ListenerRole::Relay => (TlsMode::Disabled, false, vec![]),
+            ListenerRole::Submission => {
+                (TlsMode::StartTls,
+                 true,
+                 vec![Policy::SenderMatchesLogin, Policy::RequireTls])
+            }
+            ListenerRole::Smtps => (TlsMode::Implicit, true, vec![Policy::SenderMatchesLogin]),
+        };
+        ListenerConfig {
+            address: address,
+            role: role,
+            tls: tls,
+            require_auth: require_auth,
+            policies: policies,
+        }
+    }
+
+    pub fn has_policy(&self, policy: Policy) -> bool {
+        self.policies.contains(&policy)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Limits {
+    /// See `DefaultConnectionHandler::with_max_hops`.
+    pub max_hops: usize,
+    /// Connections beyond this get `421 Too many connections`...
+    pub max_connections: usize,
+    /// ...as do connections from a client address that has this many open.
+    pub max_connections_per_ip: usize,
+    /// Threads running sessions. Accepted connections wait for a free one.
+    pub workers: usize,
+}
+
+/// How long the server waits for the client (RFC 5321 section 4.5.3.2).
+/// Given in seconds in the configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Timeouts {
+    /// For the first command after the greeting.
+    pub greeting: Duration,
+    /// For each further command.
+    pub command: Duration,
+    /// For each line of message data.
+    pub data_block: Duration,
+    /// For the whole message, from the 354 reply to the final dot.
+    pub data_end: Duration,
+    /// For the whole session.
+    pub session: Duration,
+    /// How long running sessions get to finish after a shutdown was
+    /// requested. Connections still open after that are closed.
+    pub shutdown_grace: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            greeting: Duration::from_secs(5 * 60),
+            command: Duration::from_secs(5 * 60),
+            data_block: Duration::from_secs(3 * 60),
+            data_end: Duration::from_secs(10 * 60),
+            session: Duration::from_secs(30 * 60),
+            shutdown_grace: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub certificate: PathBuf,
+    /// PEM encoded private key.
+    pub private_key: PathBuf,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthConfig {
+    /// Enables AUTH with credentials from a `PasswordFile`.
+    pub password_file: Option<PathBuf>,
+    /// Limits on failed attempts, durations in seconds in the file.
+    pub throttle: ThrottleConfig,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeliveryConfig {
+    pub received_header: bool,
+}
+
+/// A smarthost that relays mail for us. `port` defaults to 587 and `tls` to
+/// STARTTLS, `hello_name` to the server's hostname.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelayConfig {
+    pub host: String,
+    pub port: u16,
+    pub tls: TlsMode,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub hello_name: String,
+}
+
+impl RelayConfig {
+    pub fn new(host: &str, hello_name: &str) -> RelayConfig {
+        RelayConfig {
+            host: host.to_string(),
+            port: 587,
+            tls: TlsMode::StartTls,
+            username: None,
+            password: None,
+            hello_name: hello_name.to_string(),
+        }
+    }
+
+    /// Username and password to authenticate with. Settings missing from the
+    /// configuration are taken from `SMTP_RELAY_USERNAME` and
+    /// `SMTP_RELAY_PASSWORD`, so the password need not be in the file.
+    pub fn credentials(&self) -> Option<(String, String)> {
+        let username = self.username.clone().or_else(|| env::var("SMTP_RELAY_USERNAME").ok());
+        let password = self.password.clone().or_else(|| env::var("SMTP_RELAY_PASSWORD").ok());
+        match (username, password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            _ => None,
+        }
+    }
+}
+
+/// How queued messages are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueBackend {
+    /// Two files per message in the queue directory, see `Spool`.
+    Filesystem,
+    /// `queue.sqlite` in the queue directory. Needs the `sqlite` feature.
+    Sqlite,
+}
+
+/// Where the spool lives and how often deliveries are retried. The delay
+/// between attempts doubles from `initial_retry` up to `max_retry`, and
+/// messages still undelivered after `max_lifetime` are given up.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueConfig {
+    pub directory: PathBuf,
+    pub backend: QueueBackend,
+    pub initial_retry: Duration,
+    pub max_retry: Duration,
+    pub max_lifetime: Duration,
+}
+
+impl QueueConfig {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> QueueConfig {
+        QueueConfig {
+            directory: directory.into(),
+            backend: QueueBackend::Filesystem,
+            initial_retry: Duration::from_secs(5 * 60),
+            max_retry: Duration::from_secs(4 * 60 * 60),
+            max_lifetime: Duration::from_secs(5 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// How local mailboxes are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MailboxFormat {
+    /// `<directory>/<user>/Maildir`, see `Maildir`.
+    Maildir,
+    /// The single file `<directory>/<user>`, see `Mbox`.
+    Mbox,
+}
+
+/// Where the local users are looked up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UserSource {
+    /// Every local part that is a safe file name, see `LocalPart`.
+    LocalPart,
+    /// `mailboxes` and `aliases`, see `StaticUsers`.
+    Static,
+    /// The login names in `users_file`, see `PasswdFile`.
+    Passwd,
+    /// The database `users_file`, see `SqliteUsers`. Needs the `sqlite`
+    /// feature.
+    Sqlite,
+}
+
+/// Local delivery: mail for `domains` goes to mailboxes below `directory`
+/// instead of being relayed. Recipients `users` does not know are refused.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalConfig {
+    pub domains: Vec<String>,
+    pub directory: PathBuf,
+    pub format: MailboxFormat,
+    pub users: UserSource,
+    pub users_file: Option<PathBuf>,
+    /// User names with their mailboxes, for `UserSource::Static`.
+    pub mailboxes: Vec<(String, String)>,
+    /// Alias names with their targets, for `UserSource::Static`.
+    pub aliases: Vec<(String, Vec<String>)>,
+}
+
+impl LocalConfig {
+    pub fn new<P: Into<PathBuf>>(domains: Vec<String>, directory: P) -> LocalConfig {
+        LocalConfig {
+            domains: domains,
+            directory: directory.into(),
+            format: MailboxFormat::Maildir,
+            users: UserSource::LocalPart,
+            users_file: None,
+            mailboxes: Vec::new(),
+            aliases: Vec::new(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            hostname: "localhost".to_string(),
+            banner: "rust-smtp".to_string(),
+            listeners: vec![ListenerConfig::new("127.0.0.1:25255".parse().unwrap(),
+                                                ListenerRole::Relay)],
+            limits: Limits {
+                max_hops: 100,
+                max_connections: 100,
+                max_connections_per_ip: 20,
+                workers: 100,
+            },
+            timeouts: Timeouts::default(),
+            tls: None,
+            auth: AuthConfig {
+                password_file: None,
+                throttle: ThrottleConfig::default(),
+            },
+            delivery: DeliveryConfig { received_header: true },
+            relay: None,
+            queue: None,
+            local: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(io::Error),
+    /// TOML syntax error at a 1-based line and column.
+    Syntax {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// A setting that is missing, of the wrong type or has a bad value.
+    Invalid {
+        key: String,
+        message: String,
+    },
+}
+
+impl ConfigError {
+    fn invalid(key: &str, message: &str) -> ConfigError {
+        ConfigError::Invalid {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::IOError(ref err) => write!(f, "{}", err),
+            ConfigError::Syntax { line, column, ref message } => {
+                write!(f, "line {}, column {}: {}", line, column, message)
+            }
+            ConfigError::Invalid { ref key, ref message } => write!(f, "{}: {}", key, message),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigError> {
+        let mut contents = String::new();
+        try!(File::open(path)
+                 .and_then(|mut file| file.read_to_string(&mut contents))
+                 .map_err(ConfigError::IOError));
+        ServerConfig::from_toml(&contents)
+    }
+
+    /// Settings missing from `toml` keep their default value.
+    pub fn from_toml(toml: &str) -> Result<ServerConfig, ConfigError> {
+        let mut parser = Parser::new(toml);
+        let table = match parser.parse() {
+            Some(table) => table,
+            None => {
+                let error = &parser.errors[0];
+                let (line, column) = parser.to_linecol(error.lo);
+                return Err(ConfigError::Syntax {
+                    line: line + 1,
+                    column: column + 1,
+                    message: error.desc.clone(),
+                });
+            }
+        };
+
+        let mut config = ServerConfig::default();
+        try!(_check_keys(&table,
+                         "",
+                         &["hostname", "banner", "listeners", "limits", "timeouts", "tls",
+                           "auth", "delivery", "relay", "queue", "local"]));
+        if let Some(hostname) = try!(_string(&table, "", "hostname")) {
+            config.hostname = hostname;
+        }
+        if let Some(banner) = try!(_string(&table, "", "banner")) {
+            config.banner = banner;
+        }
+        if let Some(listeners) = try!(_tables(&table, "", "listeners")) {
+            config.listeners = Vec::new();
+            for (i, listener) in listeners.iter().enumerate() {
+                let prefix = format!("listeners[{}].", i);
+                try!(_check_keys(listener,
+                                 &prefix,
+                                 &["address", "role", "tls", "require_auth", "policies"]));
+                let address = try!(_required(_socket_addr(listener, &prefix, "address"),
+                                             &format!("{}address", prefix)));
+                let role = try!(_choice(listener,
+                                        &prefix,
+                                        "role",
+                                        &[("relay", ListenerRole::Relay),
+                                          ("submission", ListenerRole::Submission),
+                                          ("smtps", ListenerRole::Smtps)]));
+                let mut listener_config = ListenerConfig::new(address,
+                                                              role.unwrap_or(ListenerRole::Relay));
+                if let Some(tls) = try!(_choice(listener,
+                                                &prefix,
+                                                "tls",
+                                                &[("none", TlsMode::Disabled),
+                                                  ("starttls", TlsMode::StartTls),
+                                                  ("implicit", TlsMode::Implicit)])) {
+                    listener_config.tls = tls;
+                }
+                if let Some(require_auth) = try!(_bool(listener, &prefix, "require_auth")) {
+                    listener_config.require_auth = require_auth;
+                }
+                if let Some(policies) = try!(_strings(listener, &prefix, "policies")) {
+                    listener_config.policies = Vec::new();
+                    for policy in policies {
+                        listener_config.policies.push(match &policy[..] {
+                            "sender_matches_login" => Policy::SenderMatchesLogin,
+                            "require_tls" => Policy::RequireTls,
+                            _ => {
+                                return Err(ConfigError::invalid(&format!("{}policies", prefix),
+                                                                &format!("unknown policy {}",
+                                                                         policy)))
+                            }
+                        });
+                    }
+                }
+                config.listeners.push(listener_config);
+            }
+        }
+        if let Some(limits) = try!(_table(&table, "", "limits")) {
+            try!(_check_keys(limits,
+                             "limits.",
+                             &["max_hops",
+                               "max_connections",
+                               "max_connections_per_ip",
+                               "workers"]));
+            if let Some(max_hops) = try!(_integer(limits, "limits.", "max_hops")) {
+                config.limits.max_hops = max_hops;
+            }
+            if let Some(max_connections) = try!(_integer(limits, "limits.", "max_connections")) {
+                config.limits.max_connections = max_connections;
+            }
+            if let Some(max_connections_per_ip) = try!(_integer(limits,
+                                                                "limits.",
+                                                                "max_connections_per_ip")) {
+                config.limits.max_connections_per_ip = max_connections_per_ip;
+            }
+            if let Some(workers) = try!(_integer(limits, "limits.", "workers")) {
+                config.limits.workers = workers;
+            }
+        }
+        if let Some(timeouts) = try!(_table(&table, "", "timeouts")) {
+            try!(_check_keys(timeouts,
+                             "timeouts.",
+                             &["greeting",
+                               "command",
+                               "data_block",
+                               "data_end",
+                               "session",
+                               "shutdown_grace"]));
+            let config_timeouts = &mut config.timeouts;
+            for &mut (key, ref mut timeout) in &mut [("greeting", &mut config_timeouts.greeting),
+                                                     ("command", &mut config_timeouts.command),
+                                                     ("data_block",
+                                                      &mut config_timeouts.data_block),
+                                                     ("data_end", &mut config_timeouts.data_end),
+                                                     ("session", &mut config_timeouts.session),
+                                                     ("shutdown_grace",
+                                                      &mut config_timeouts.shutdown_grace)] {
+                if let Some(seconds) = try!(_integer(timeouts, "timeouts.", key)) {
+                    **timeout = Duration::from_secs(seconds as u64);
+                }
+            }
+        }
+        if let Some(tls) = try!(_table(&table, "", "tls")) {
+            try!(_check_keys(tls, "tls.", &["certificate", "private_key"]));
+            let certificate = try!(_required(_string(tls, "tls.", "certificate"),
+                                             "tls.certificate"));
+            let private_key = try!(_required(_string(tls, "tls.", "private_key"),
+                                             "tls.private_key"));
+            config.tls = Some(TlsConfig {
+                certificate: PathBuf::from(certificate),
+                private_key: PathBuf::from(private_key),
+            });
+        }
+        if let Some(auth) = try!(_table(&table, "", "auth")) {
+            try!(_check_keys(auth,
+                             "auth.",
+                             &["password_file",
+                               "max_failures_per_ip",
+                               "max_failures_per_user",
+                               "failure_window",
+                               "lockout_duration",
+                               "delay_per_failure",
+                               "max_delay",
+                               "max_failures_per_session"]));
+            config.auth.password_file = try!(_string(auth, "auth.", "password_file"))
+                                            .map(PathBuf::from);
+            let throttle = &mut config.auth.throttle;
+            for &mut (key, ref mut count) in &mut [("max_failures_per_ip",
+                                                    &mut throttle.max_failures_per_ip),
+                                                   ("max_failures_per_user",
+                                                    &mut throttle.max_failures_per_user),
+                                                   ("max_failures_per_session",
+                                                    &mut throttle.max_failures_per_session)] {
+                if let Some(value) = try!(_integer(auth, "auth.", key)) {
+                    if value > u32::MAX as usize {
+                        return Err(ConfigError::invalid(&format!("auth.{}", key), "too large"));
+                    }
+                    **count = value as u32;
+                }
+            }
+            for &mut (key, ref mut duration) in &mut [("failure_window",
+                                                       &mut throttle.failure_window),
+                                                      ("lockout_duration",
+                                                       &mut throttle.lockout_duration),
+                                                      ("delay_per_failure",
+                                                       &mut throttle.delay_per_failure),
+                                                      ("max_delay", &mut throttle.max_delay)] {
+                if let Some(seconds) = try!(_integer(auth, "auth.", key)) {
+                    **duration = Duration::from_secs(seconds as u64);
+                }
+            }
+        }
+        if let Some(delivery) = try!(_table(&table, "", "delivery")) {
+            try!(_check_keys(delivery, "delivery.", &["received_header"]));
+            if let Some(received_header) = try!(_bool(delivery, "delivery.", "received_header")) {
+                config.delivery.received_header = received_header;
+            }
+        }
+        if let Some(relay) = try!(_table(&table, "", "relay")) {
+            try!(_check_keys(relay,
+                             "relay.",
+                             &["host", "port", "tls", "username", "password", "hello_name"]));
+            let host = try!(_required(_string(relay, "relay.", "host"), "relay.host"));
+            let mut relay_config = RelayConfig::new(&host, &config.hostname);
+            if let Some(port) = try!(_integer(relay, "relay.", "port")) {
+                if port > 65535 {
+                    return Err(ConfigError::invalid("relay.port", "must be at most 65535"));
+                }
+                relay_config.port = port as u16;
+            }
+            if let Some(tls) = try!(_choice(relay,
+                                            "relay.",
+                                            "tls",
+                                            &[("none", TlsMode::Disabled),
+                                              ("starttls", TlsMode::StartTls),
+                                              ("implicit", TlsMode::Implicit)])) {
+                relay_config.tls = tls;
+            }
+            relay_config.username = try!(_string(relay, "relay.", "username"));
+            relay_config.password = try!(_string(relay, "relay.", "password"));
+            if let Some(hello_name) = try!(_string(relay, "relay.", "hello_name")) {
+                relay_config.hello_name = hello_name;
+            }
+            config.relay = Some(relay_config);
+        }
+        if let Some(queue) = try!(_table(&table, "", "queue")) {
+            try!(_check_keys(queue,
+                             "queue.",
+                             &["directory",
+                               "backend",
+                               "initial_retry",
+                               "max_retry",
+                               "max_lifetime"]));
+            let directory = try!(_required(_string(queue, "queue.", "directory"),
+                                           "queue.directory"));
+            let mut queue_config = QueueConfig::new(directory);
+            if let Some(backend) = try!(_choice(queue,
+                                                "queue.",
+                                                "backend",
+                                                &[("filesystem", QueueBackend::Filesystem),
+                                                  ("sqlite", QueueBackend::Sqlite)])) {
+                queue_config.backend = backend;
+            }
+            for &mut (key, ref mut duration) in &mut [("initial_retry",
+                                                       &mut queue_config.initial_retry),
+                                                      ("max_retry", &mut queue_config.max_retry),
+                                                      ("max_lifetime",
+                                                       &mut queue_config.max_lifetime)] {
+                if let Some(seconds) = try!(_integer(queue, "queue.", key)) {
+                    **duration = Duration::from_secs(seconds as u64);
+                }
+            }
+            config.queue = Some(queue_config);
+        }
+        if let Some(local) = try!(_table(&table, "", "local")) {
+            try!(_check_keys(local,
+                             "local.",
+                             &["domains",
+                               "directory",
+                               "format",
+                               "users",
+                               "users_file",
+                               "mailboxes",
+                               "aliases"]));
+            let domains = try!(_required(_strings(local, "local.", "domains"), "local.domains"));
+            let directory = try!(_required(_string(local, "local.", "directory"),
+                                           "local.directory"));
+            let mut local_config = LocalConfig::new(domains, directory);
+            if let Some(format) = try!(_choice(local,
+                                               "local.",
+                                               "format",
+                                               &[("maildir", MailboxFormat::Maildir),
+                                                 ("mbox", MailboxFormat::Mbox)])) {
+                local_config.format = format;
+            }
+            if let Some(users) = try!(_choice(local,
+                                              "local.",
+                                              "users",
+                                              &[("local-part", UserSource::LocalPart),
+                                                ("static", UserSource::Static),
+                                                ("passwd", UserSource::Passwd),
+                                                ("sqlite", UserSource::Sqlite)])) {
+                local_config.users = users;
+            }
+            local_config.users_file = try!(_string(local, "local.", "users_file"))
+                                          .map(PathBuf::from);
+            if let Some(mailboxes) = try!(_table(local, "local.", "mailboxes")) {
+                for name in mailboxes.keys() {
+                    let mailbox = try!(_string(mailboxes, "local.mailboxes.", name)).unwrap();
+                    local_config.mailboxes.push((name.clone(), mailbox));
+                }
+            }
+            if let Some(aliases) = try!(_table(local, "local.", "aliases")) {
+                for name in aliases.keys() {
+                    let targets = try!(_strings(aliases, "local.aliases.", name)).unwrap();
+                    local_config.aliases.push((name.clone(), targets));
+                }
+            }
+            config.local = Some(local_config);
+        }
+
+        try!(config.validate());
+        Ok(config)
+    }
+
+    /// Checks values that are well-typed but unusable, for configurations
+    /// built in code as well as loaded ones.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.hostname.is_empty() ||
+           self.hostname.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(ConfigError::invalid("hostname", "must be a domain name"));
+        }
+        if self.banner.contains(|c: char| c.is_control()) {
+            return Err(ConfigError::invalid("banner", "must not contain control characters"));
+        }
+        if self.listeners.is_empty() {
+            return Err(ConfigError::invalid("listeners", "at least one listener is required"));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if listener.tls != TlsMode::Disabled && self.tls.is_none() {
+                return Err(ConfigError::invalid(&format!("listeners[{}].tls", i),
+                                                "requires a certificate in [tls]"));
+            }
+        }
+        for &(key, value) in &[("limits.max_hops", self.limits.max_hops),
+                               ("limits.max_connections", self.limits.max_connections),
+                               ("limits.max_connections_per_ip",
+                                self.limits.max_connections_per_ip),
+                               ("limits.workers", self.limits.workers)] {
+            if value == 0 {
+                return Err(ConfigError::invalid(key, "must be at least 1"));
+            }
+        }
+        for &(key, value) in &[("timeouts.greeting", self.timeouts.greeting),
+                               ("timeouts.command", self.timeouts.command),
+                               ("timeouts.data_block", self.timeouts.data_block),
+                               ("timeouts.data_end", self.timeouts.data_end),
+                               ("timeouts.session", self.timeouts.session)] {
+            if value == Duration::from_secs(0) {
+                return Err(ConfigError::invalid(key, "must be at least 1 second"));
+            }
+        }
+        if let Some(ref tls) = self.tls {
+            try!(_check_file(&tls.certificate, "tls.certificate"));
+            try!(_check_file(&tls.private_key, "tls.private_key"));
+        }
+        if let Some(ref password_file) = self.auth.password_file {
+            try!(_check_file(password_file, "auth.password_file"));
+        }
+        let throttle = &self.auth.throttle;
+        for &(key, value) in &[("auth.max_failures_per_ip", throttle.max_failures_per_ip),
+                               ("auth.max_failures_per_user", throttle.max_failures_per_user),
+                               ("auth.max_failures_per_session",
+                                throttle.max_failures_per_session)] {
+            if value == 0 {
+                return Err(ConfigError::invalid(key, "must be at least 1"));
+            }
+        }
+        for &(key, value) in &[("auth.failure_window", throttle.failure_window),
+                               ("auth.lockout_duration", throttle.lockout_duration)] {
+            if value == Duration::from_secs(0) {
+                return Err(ConfigError::invalid(key, "must be at least 1 second"));
+            }
+        }
+        if throttle.max_delay < throttle.delay_per_failure {
+            return Err(ConfigError::invalid("auth.max_delay",
+                                            "must not be less than delay_per_failure"));
+        }
+        if let Some(ref relay) = self.relay {
+            if relay.host.is_empty() {
+                return Err(ConfigError::invalid("relay.host", "must not be empty"));
+            }
+            if relay.port == 0 {
+                return Err(ConfigError::invalid("relay.port", "must be at least 1"));
+            }
+            if relay.hello_name.is_empty() ||
+               relay.hello_name.contains(|c: char| c.is_whitespace() || c.is_control()) {
+                return Err(ConfigError::invalid("relay.hello_name", "must be a domain name"));
+            }
+            if relay.tls == TlsMode::Disabled && relay.credentials().is_some() {
+                return Err(ConfigError::invalid("relay.tls",
+                                                "must be enabled to send the password"));
+            }
+            // messages must not be accepted before they are safe on disk
+            if self.queue.is_none() {
+                return Err(ConfigError::invalid("relay", "requires a [queue] to retry from"));
+            }
+        }
+        if let Some(ref local) = self.local {
+            if local.domains.is_empty() || local.domains.iter().any(|domain| domain.is_empty()) {
+                return Err(ConfigError::invalid("local.domains", "must list domain names"));
+            }
+            if local.directory.as_os_str().is_empty() {
+                return Err(ConfigError::invalid("local.directory", "must not be empty"));
+            }
+            if self.queue.is_none() {
+                return Err(ConfigError::invalid("local", "requires a [queue] to retry from"));
+            }
+            match (local.users, local.users_file.as_ref()) {
+                (UserSource::Passwd, None) |
+                (UserSource::Sqlite, None) => {
+                    return Err(ConfigError::invalid("local.users_file",
+                                                    "required for passwd and sqlite users"));
+                }
+                (UserSource::Passwd, Some(users_file)) |
+                (UserSource::Sqlite, Some(users_file)) => {
+                    try!(_check_file(users_file, "local.users_file"))
+                }
+                (_, Some(_)) => {
+                    return Err(ConfigError::invalid("local.users_file",
+                                                    "only used for passwd and sqlite users"));
+                }
+                (_, None) => (),
+            }
+            if local.users == UserSource::Sqlite && !cfg!(feature = "sqlite") {
+                return Err(ConfigError::invalid("local.users",
+                                                "sqlite support was not compiled in"));
+            }
+            if local.users != UserSource::Static {
+                for &(key, empty) in &[("local.mailboxes", local.mailboxes.is_empty()),
+                                       ("local.aliases", local.aliases.is_empty())] {
+                    if !empty {
+                        return Err(ConfigError::invalid(key, "only used for static users"));
+                    }
+                }
+            }
+            for &(ref name, ref mailbox) in &local.mailboxes {
+                if !is_mailbox_name(mailbox) {
+                    return Err(ConfigError::invalid(&format!("local.mailboxes.{}", name),
+                                                    "must be a plain file name"));
+                }
+            }
+            for &(ref name, ref targets) in &local.aliases {
+                if targets.is_empty() || targets.iter().any(|target| target.trim().is_empty()) {
+                    return Err(ConfigError::invalid(&format!("local.aliases.{}", name),
+                                                    "must list addresses or user names"));
+                }
+            }
+        }
+        if let Some(ref queue) = self.queue {
+            if queue.directory.as_os_str().is_empty() {
+                return Err(ConfigError::invalid("queue.directory", "must not be empty"));
+            }
+            if queue.backend == QueueBackend::Sqlite && !cfg!(feature = "sqlite") {
+                return Err(ConfigError::invalid("queue.backend",
+                                                "sqlite support was not compiled in"));
+            }
+            for &(key, value) in &[("queue.initial_retry", queue.initial_retry),
+                                   ("queue.max_retry", queue.max_retry)] {
+                if value == Duration::from_secs(0) {
+                    return Err(ConfigError::invalid(key, "must be at least 1 second"));
+                }
+            }
+            if queue.max_retry < queue.initial_retry {
+                return Err(ConfigError::invalid("queue.max_retry",
+                                                "must not be less than initial_retry"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks what `smtp serve` needs on top of `validate`: a queue, so that
+    /// no accepted message is lost, and, as relay listeners only take mail
+    /// for `local.domains` without AUTH, local domains or a password file.
+    pub fn validate_serve(&self) -> Result<(), ConfigError> {
+        if self.queue.is_none() {
+            return Err(ConfigError::invalid("queue", "required to keep accepted messages"));
+        }
+        if self.local.is_none() && self.auth.password_file.is_none() {
+            return Err(ConfigError::invalid("local",
+                                            "required to accept mail without auth.password_file"));
+        }
+        Ok(())
+    }
+}
+
+fn _check_file(path: &Path, key: &str) -> Result<(), ConfigError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(ConfigError::invalid(key, &format!("{} is not a file", path.display())))
+    }
+}
+
+fn _check_keys(table: &Table, prefix: &str, allowed: &[&str]) -> Result<(), ConfigError> {
+    match table.keys().find(|key| !allowed.contains(&&key[..])) {
+        Some(key) => Err(ConfigError::invalid(&format!("{}{}", prefix, key), "unknown setting")),
+        None => Ok(()),
+    }
+}
+
+fn _required<T>(value: Result<Option<T>, ConfigError>, key: &str) -> Result<T, ConfigError> {
+    match try!(value) {
+        Some(value) => Ok(value),
+        None => Err(ConfigError::invalid(key, "missing")),
+    }
+}
+
+fn _get<'a, T, F>(table: &'a Table,
+                  prefix: &str,
+                  key: &str,
+                  expected: &str,
+                  convert: F)
+                  -> Result<Option<T>, ConfigError>
+    where F: Fn(&'a Value) -> Option<T>
+{
+    match table.get(key) {
+        None => Ok(None),
+        Some(value) => {
+            match convert(value) {
+                Some(value) => Ok(Some(value)),
+                None => {
+                    Err(ConfigError::invalid(&format!("{}{}", prefix, key),
+                                             &format!("expected {}, found {}",
+                                                      expected,
+                                                      value.type_str())))
+                }
+            }
+        }
+    }
+}
+
+fn _string(table: &Table, prefix: &str, key: &str) -> Result<Option<String>, ConfigError> {
+    _get(table, prefix, key, "string", |v| v.as_str().map(|s| s.to_string()))
+}
+
+fn _bool(table: &Table, prefix: &str, key: &str) -> Result<Option<bool>, ConfigError> {
+    _get(table, prefix, key, "boolean", Value::as_bool)
+}
+
+fn _integer(table: &Table, prefix: &str, key: &str) -> Result<Option<usize>, ConfigError> {
+    _get(table,
+         prefix,
+         key,
+         "non-negative integer",
+         |v| v.as_integer().and_then(|i| if i >= 0 { Some(i as usize) } else { None }))
+}
+
+fn _socket_addr(table: &Table,
+                prefix: &str,
+                key: &str)
+                -> Result<Option<SocketAddr>, ConfigError> {
+    _get(table,
+         prefix,
+         key,
+         "address like \"0.0.0.0:25\"",
+         |v| v.as_str().and_then(|s| s.parse().ok()))
+}
+
+fn _strings(table: &Table, prefix: &str, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+    _get(table, prefix, key, "array of strings", |v| {
+        v.as_slice().and_then(|values| {
+            values.iter().map(|v| v.as_str().map(|s| s.to_string())).collect()
+        })
+    })
+}
+
+/// One of the values named in `choices`.
+fn _choice<T: Copy>(table: &Table,
+                    prefix: &str,
+                    key: &str,
+                    choices: &[(&str, T)])
+                    -> Result<Option<T>, ConfigError> {
+    let value = match try!(_string(table, prefix, key)) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    match choices.iter().find(|&&(name, _)| name == value) {
+        Some(&(_, choice)) => Ok(Some(choice)),
+        None => {
+            let names = choices.iter().map(|&(name, _)| name).collect::<Vec<_>>();
+            Err(ConfigError::invalid(&format!("{}{}", prefix, key),
+                                     &format!("expected one of {}", names.join(", "))))
+        }
+    }
+}
+
+fn _table<'a>(table: &'a Table,
+              prefix: &str,
+              key: &str)
+              -> Result<Option<&'a Table>, ConfigError> {
+    _get(table, prefix, key, "table", Value::as_table)
+}
+
+fn _tables<'a>(table: &'a Table,
+               prefix: &str,
+               key: &str)
+               -> Result<Option<Vec<&'a Table>>, ConfigError> {
+    _get(table, prefix, key, "array of tables", |v| {
+        v.as_slice().and_then(|values| values.iter().map(Value::as_table).collect())
+    })
+}
+
+pub mod tests {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use auth_throttle::ThrottleConfig;
+    use config::{ServerConfig, ConfigError, ListenerConfig, ListenerRole, TlsMode, Policy,
+                 Timeouts, RelayConfig, QueueConfig, LocalConfig, MailboxFormat, UserSource};
+
+    fn invalid_key(toml: &str) -> String {
+        match ServerConfig::from_toml(toml) {
+            Err(ConfigError::Invalid { key, .. }) => key,
+            other => panic!("expected invalid setting, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn loads_settings_and_keeps_defaults() {
+        let config = ServerConfig::from_toml("hostname = \"mx.example.org\"\n\n[[listeners]]\n\
+                                              address = \"0.0.0.0:25\"\n\n[[listeners]]\n\
+                                              address = \"[::]:587\"\n\n[timeouts]\n\
+                                              shutdown_grace = 0\n\n[delivery]\n\
+                                              received_header = false\n")
+                         .unwrap();
+        assert_eq!("mx.example.org", config.hostname);
+        assert_eq!("rust-smtp", config.banner);
+        assert_eq!(vec![ListenerConfig::new("0.0.0.0:25".parse().unwrap(), ListenerRole::Relay),
+                        ListenerConfig::new("[::]:587".parse().unwrap(), ListenerRole::Relay)],
+                   config.listeners);
+        assert_eq!(100, config.limits.max_hops);
+        assert_eq!(Timeouts { shutdown_grace: Duration::from_secs(0), ..Timeouts::default() },
+                   config.timeouts);
+        assert!(!config.delivery.received_header);
+        assert_eq!(None, config.tls);
+        assert_eq!(None, config.relay);
+    }
+
+    #[test]
+    fn loads_relay_settings() {
+        let config = ServerConfig::from_toml("hostname = \"mx.example.org\"\n[relay]\nhost = \
+                                              \"smtp.example.net\"\ntls = \"implicit\"\nport = \
+                                              465\nusername = \"mx\"\npassword = \"secret\"\n\
+                                              [queue]\ndirectory = \"q\"\n")
+                         .unwrap();
+        let relay = config.relay.unwrap();
+        assert_eq!(RelayConfig {
+                       host: "smtp.example.net".to_string(),
+                       port: 465,
+                       tls: TlsMode::Implicit,
+                       username: Some("mx".to_string()),
+                       password: Some("secret".to_string()),
+                       hello_name: "mx.example.org".to_string(),
+                   },
+                   relay);
+        assert_eq!(Some(("mx".to_string(), "secret".to_string())), relay.credentials());
+
+        assert_eq!("relay.host", invalid_key("[relay]\nport = 25\n"));
+        assert_eq!("relay.port", invalid_key("[relay]\nhost = \"a\"\nport = 70000\n"));
+        assert_eq!("relay.tls", invalid_key("[relay]\nhost = \"a\"\ntls = \"ssl\"\n"));
+        assert_eq!("relay", invalid_key("[relay]\nhost = \"a\"\n"));
+        assert_eq!("relay.tls",
+                   invalid_key("[relay]\nhost = \"a\"\ntls = \"none\"\nusername = \"mx\"\n\
+                                password = \"secret\"\n[queue]\ndirectory = \"q\"\n"));
+    }
+
+    #[test]
+    fn loads_queue_settings() {
+        let config = ServerConfig::from_toml("[relay]\nhost = \"smtp.example.net\"\n[queue]\n\
+                                              directory = \"/var/spool/smtp\"\nmax_retry = \
+                                              3600\n")
+                         .unwrap();
+        assert_eq!(Some(QueueConfig {
+                       max_retry: Duration::from_secs(3600),
+                       ..QueueConfig::new("/var/spool/smtp")
+                   }),
+                   config.queue);
+
+        assert_eq!("queue.directory", invalid_key("[relay]\nhost = \"a\"\n[queue]\n"));
+        assert_eq!("queue.max_retry",
+                   invalid_key("[relay]\nhost = \"a\"\n[queue]\ndirectory = \"q\"\n\
+                                initial_retry = 600\nmax_retry = 60\n"));
+        // delivered to MX hosts
+        assert!(ServerConfig::from_toml("[queue]\ndirectory = \"q\"\n").unwrap().relay.is_none());
+        assert_eq!("queue.backend",
+                   invalid_key("[relay]\nhost = \"a\"\n[queue]\ndirectory = \"q\"\nbackend = \
+                                \"mysql\"\n"));
+    }
+
+    #[test]
+    fn loads_local_settings() {
+        let config = ServerConfig::from_toml("[queue]\ndirectory = \"q\"\n[local]\ndomains = \
+                                              [\"example.org\"]\ndirectory = \"/home\"\n")
+                         .unwrap();
+        assert_eq!(Some(LocalConfig::new(vec!["example.org".to_string()], "/home")),
+                   config.local);
+
+        assert_eq!("local.domains", invalid_key("[queue]\ndirectory = \"q\"\n[local]\n"));
+        assert_eq!("local.domains",
+                   invalid_key("[queue]\ndirectory = \"q\"\n[local]\ndomains = []\n\
+                                directory = \"/home\"\n"));
+        let config = ServerConfig::from_toml("[queue]\ndirectory = \"q\"\n[local]\ndomains = \
+                                              [\"example.org\"]\ndirectory = \
+                                              \"/var/mail\"\nformat = \"mbox\"\n")
+                         .unwrap();
+        assert_eq!(MailboxFormat::Mbox, config.local.unwrap().format);
+        assert_eq!("local.format",
+                   invalid_key("[queue]\ndirectory = \"q\"\n[local]\ndomains = [\"a\"]\n\
+                                directory = \"/home\"\nformat = \"mh\"\n"));
+        assert_eq!("local",
+                   invalid_key("[local]\ndomains = [\"example.org\"]\ndirectory = \"/home\"\n"));
+    }
+
+    #[test]
+    fn loads_local_users() {
+        let local = "[queue]\ndirectory = \"q\"\n[local]\ndomains = [\"example.org\"]\ndirectory \
+                     = \"/home\"\n";
+        let config = ServerConfig::from_toml(&format!("{}users = \"static\"\n[local.mailboxes]\n\
+                                                       marie = \"marie\"\n[local.aliases]\n\
+                                                       postmaster = [\"marie\", \
+                                                       \"max@example.net\"]\n",
+                                                      local))
+                         .unwrap();
+        let local_config = config.local.unwrap();
+        assert_eq!(UserSource::Static, local_config.users);
+        assert_eq!(vec![("marie".to_string(), "marie".to_string())],
+                   local_config.mailboxes);
+        assert_eq!(vec![("postmaster".to_string(),
+                         vec!["marie".to_string(), "max@example.net".to_string()])],
+                   local_config.aliases);
+
+        let config = ServerConfig::from_toml(&format!("{}users = \"passwd\"\nusers_file = \
+                                                       \"Cargo.toml\"\n",
+                                                      local))
+                         .unwrap();
+        assert_eq!(UserSource::Passwd, config.local.unwrap().users);
+        assert_eq!("local.users_file",
+                   invalid_key(&format!("{}users = \"passwd\"\n", local)));
+        assert_eq!("local.users_file",
+                   invalid_key(&format!("{}users = \"passwd\"\nusers_file = \"nowhere\"\n",
+                                        local)));
+        assert_eq!("local.users",
+                   invalid_key(&format!("{}users = \"ldap\"\n", local)));
+        assert_eq!("local.aliases",
+                   invalid_key(&format!("{}[local.aliases]\npostmaster = [\"marie\"]\n", local)));
+        assert_eq!("local.mailboxes.marie",
+                   invalid_key(&format!("{}users = \"static\"\n[local.mailboxes]\nmarie = \
+                                         \"../marie\"\n",
+                                        local)));
+        assert_eq!("local.aliases.postmaster",
+                   invalid_key(&format!("{}users = \"static\"\n[local.aliases]\npostmaster = \
+                                         []\n",
+                                        local)));
+    }
+
+    #[test]
+    fn checks_what_serve_needs() {
+        let config = ServerConfig { queue: Some(QueueConfig::new("q")), ..ServerConfig::default() };
+        match config.validate_serve() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!("local", key),
+            other => panic!("expected invalid setting, got {:?}", other),
+        }
+        match ServerConfig::default().validate_serve() {
+            Err(ConfigError::Invalid { key, .. }) => assert_eq!("queue", key),
+            other => panic!("expected invalid setting, got {:?}", other),
+        }
+        let mut with_auth = config.clone();
+        with_auth.auth.password_file = Some(PathBuf::from("/etc/smtp/passwd"));
+        assert!(with_auth.validate_serve().is_ok());
+        let mut with_local = config.clone();
+        with_local.local = Some(LocalConfig::new(vec!["example.org".to_string()], "/home"));
+        assert!(with_local.validate_serve().is_ok());
+    }
+
+    #[test]
+    fn loads_auth_settings() {
+        let config = ServerConfig::from_toml("[auth]\nmax_failures_per_ip = 20\n\
+                                              max_failures_per_user = 3\nlockout_duration = \
+                                              3600\ndelay_per_failure = 2\nmax_delay = 30\n\
+                                              max_failures_per_session = 1\n")
+                         .unwrap();
+        assert_eq!(ThrottleConfig {
+                       max_failures_per_ip: 20,
+                       max_failures_per_user: 3,
+                       lockout_duration: Duration::from_secs(3600),
+                       delay_per_failure: Duration::from_secs(2),
+                       max_delay: Duration::from_secs(30),
+                       max_failures_per_session: 1,
+                       ..ThrottleConfig::default()
+                   },
+                   config.auth.throttle);
+
+        assert_eq!("auth.max_failures_per_ip", invalid_key("[auth]\nmax_failures_per_ip = 0\n"));
+        assert_eq!("auth.max_failures_per_user",
+                   invalid_key("[auth]\nmax_failures_per_user = 10000000000\n"));
+        assert_eq!("auth.failure_window", invalid_key("[auth]\nfailure_window = 0\n"));
+        assert_eq!("auth.max_delay",
+                   invalid_key("[auth]\ndelay_per_failure = 5\nmax_delay = 4\n"));
+    }
+
+    #[test]
+    fn applies_listener_role_defaults() {
+        let config = ServerConfig::from_toml("[[listeners]]\naddress = \"0.0.0.0:587\"\nrole = \
+                                              \"submission\"\ntls = \"none\"\n\n[[listeners]]\n\
+                                              address = \"0.0.0.0:2525\"\nrequire_auth = \
+                                              true\npolicies = [\"sender_matches_login\"]\n")
+                         .unwrap();
+        let submission = &config.listeners[0];
+        assert_eq!(ListenerRole::Submission, submission.role);
+        assert_eq!(TlsMode::Disabled, submission.tls);
+        assert!(submission.require_auth);
+        assert!(submission.has_policy(Policy::SenderMatchesLogin));
+        assert!(submission.has_policy(Policy::RequireTls));
+        let relay = &config.listeners[1];
+        assert_eq!(ListenerRole::Relay, relay.role);
+        assert!(relay.require_auth);
+        assert_eq!(vec![Policy::SenderMatchesLogin], relay.policies);
+    }
+
+    #[test]
+    fn names_offending_key() {
+        assert_eq!("hostname", invalid_key("hostname = 25\n"));
+        assert_eq!("hostname", invalid_key("hostname = \"mx example\"\n"));
+        assert_eq!("listeners[1].address",
+                   invalid_key("[[listeners]]\naddress = \"0.0.0.0:25\"\n[[listeners]]\naddress \
+                                = \"localhost\"\n"));
+        assert_eq!("listeners[0].role",
+                   invalid_key("[[listeners]]\naddress = \"0.0.0.0:25\"\nrole = \"mx\"\n"));
+        assert_eq!("listeners[0].tls",
+                   invalid_key("[[listeners]]\naddress = \"0.0.0.0:465\"\nrole = \"smtps\"\n"));
+        assert_eq!("limits.max_hops", invalid_key("[limits]\nmax_hops = -1\n"));
+        assert_eq!("timeouts.data_end", invalid_key("[timeouts]\ndata_end = 0\n"));
+        assert_eq!("timeouts.command", invalid_key("[timeouts]\ncommand = \"5m\"\n"));
+        assert_eq!("limits.workers", invalid_key("[limits]\nworkers = 0\n"));
+        assert_eq!("limits.max_hop", invalid_key("[limits]\nmax_hop = 10\n"));
+        assert_eq!("tls.private_key",
+                   invalid_key("[tls]\ncertificate = \"/etc/smtp/cert.pem\"\n"));
+        assert_eq!("auth.password_file",
+                   invalid_key("[auth]\npassword_file = \"/nonexistent/passwd\"\n"));
+    }
+
+    #[test]
+    fn reports_syntax_errors_with_position() {
+        match ServerConfig::from_toml("hostname = \"mx.example.org\"\nbanner = \n") {
+            Err(ConfigError::Syntax { line, .. }) => assert_eq!(2, line),
+            other => panic!("expected syntax error, got {:?}", other),
+        }
+    }
+}
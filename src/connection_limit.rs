@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Counts open connections, in total and per client address.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    counts: Arc<Mutex<Counts>>,
+}
+
+/// Held for as long as a connection is open (or waiting for a worker).
+pub struct ConnectionSlot {
+    ip: Option<IpAddr>,
+    counts: Arc<Mutex<Counts>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_connections: usize, max_connections_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_connections: max_connections,
+            max_connections_per_ip: max_connections_per_ip,
+            counts: Arc::new(Mutex::new(Counts {
+                total: 0,
+                per_ip: HashMap::new(),
+            })),
+        }
+    }
+
+    /// `None` if either limit is reached.
+    pub fn try_acquire(&self, ip: Option<IpAddr>) -> Option<ConnectionSlot> {
+        let mut counts = self.counts.lock().unwrap();
+        if counts.total >= self.max_connections {
+            return None;
+        }
+        if let Some(ip) = ip {
+            let count = counts.per_ip.entry(ip).or_insert(0);
+            if *count >= self.max_connections_per_ip {
+                return None;
+            }
+            *count += 1;
+        }
+        counts.total += 1;
+        Some(ConnectionSlot {
+            ip: ip,
+            counts: self.counts.clone(),
+        })
+    }
+
+    pub fn connections(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = self.ip {
+            let remove = match counts.per_ip.get_mut(&ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if remove {
+                counts.per_ip.remove(&ip);
+            }
+        }
+    }
+}
+
+pub mod tests {
+    use connection_limit::ConnectionLimiter;
+
+    #[test]
+    fn limits_total_and_per_ip() {
+        let limiter = ConnectionLimiter::new(3, 2);
+        let a = "192.0.2.1".parse().ok();
+        let b = "192.0.2.2".parse().ok();
+
+        let first = limiter.try_acquire(a);
+        let second = limiter.try_acquire(a);
+        assert!(first.is_some() && second.is_some());
+        assert!(limiter.try_acquire(a).is_none());
+
+        let third = limiter.try_acquire(b);
+        assert!(third.is_some());
+        assert!(limiter.try_acquire(b).is_none());
+        assert_eq!(3, limiter.connections());
+
+        drop(first);
+        assert_eq!(2, limiter.connections());
+        assert!(limiter.try_acquire(a).is_some());
+    }
+}
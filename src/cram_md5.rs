@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use rustc_serialize::hex::ToHex;
+use auth::{CredentialStore, SaslMechanism, SaslStep, random_nonce, constant_time_eq};
+
+/// CRAM-MD5 (RFC 2195). Requires the plaintext password in the credential store.
+pub struct CramMd5 {
+    credentials: Arc<CredentialStore>,
+    challenge: String,
+    challenge_sent: bool,
+    username: Option<String>,
+}
+
+impl CramMd5 {
+    pub fn new(credentials: Arc<CredentialStore>, hostname: &str) -> CramMd5 {
+        let timestamp = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|d| d.as_secs())
+                            .unwrap_or(0);
+        let challenge = format!("<{}.{}@{}>", random_nonce(16), timestamp, hostname);
+        CramMd5::with_challenge(credentials, &challenge)
+    }
+
+    fn with_challenge(credentials: Arc<CredentialStore>, challenge: &str) -> CramMd5 {
+        CramMd5 {
+            credentials: credentials,
+            challenge: challenge.to_string(),
+            challenge_sent: false,
+            username: None,
+        }
+    }
+
+    fn _verify(&mut self, response: &[u8]) -> SaslStep {
+        let response = match String::from_utf8(response.to_vec()) {
+            Ok(response) => response,
+            Err(_) => return SaslStep::Failure,
+        };
+        let (username, digest) = match response.rfind(' ') {
+            Some(index) => (&response[..index], &response[index + 1..]),
+            None => return SaslStep::Failure,
+        };
+        self.username = Some(username.to_string());
+
+        let password = match self.credentials.lookup(username).and_then(|c| c.password) {
+            Some(password) => password,
+            None => return SaslStep::Failure,
+        };
+
+        let mut mac = Hmac::<Md5>::new_varkey(password.as_bytes()).unwrap();
+        mac.input(self.challenge.as_bytes());
+        let expected = mac.result().code().to_hex();
+
+        if constant_time_eq(expected.as_bytes(), digest.to_lowercase().as_bytes()) {
+            SaslStep::Success(username.to_string(), None)
+        } else {
+            SaslStep::Failure
+        }
+    }
+}
+
+impl SaslMechanism for CramMd5 {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        match (self.challenge_sent, response) {
+            (false, None) => {
+                self.challenge_sent = true;
+                SaslStep::Challenge(self.challenge.clone().into_bytes())
+            }
+            (true, Some(response)) => self._verify(response),
+            // CRAM-MD5 is server-first, an initial response is a protocol error
+            _ => SaslStep::Failure,
+        }
+    }
+
+    fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
+
+pub mod tests {
+    use std::sync::Arc;
+    use auth::{StaticCredentialStore, SaslMechanism, SaslStep};
+    use cram_md5::CramMd5;
+
+    // RFC 2195 section 2
+    const CHALLENGE: &'static str = "<1896.697170952@postoffice.reston.mci.net>";
+
+    fn mechanism() -> CramMd5 {
+        let mut store = StaticCredentialStore::new();
+        store.add_password("tim", "tanstaaftanstaaf");
+        CramMd5::with_challenge(Arc::new(store), CHALLENGE)
+    }
+
+    #[test]
+    fn accepts_rfc_example() {
+        let mut cram = mechanism();
+        match cram.step(None) {
+            SaslStep::Challenge(challenge) => assert_eq!(CHALLENGE.as_bytes(), &challenge[..]),
+            _ => panic!("expected challenge"),
+        }
+        match cram.step(Some(b"tim b913a602c7eda7a495b4e6e7334d3890")) {
+            SaslStep::Success(identity, None) => assert_eq!("tim", identity),
+            _ => panic!("expected success"),
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_digest() {
+        let mut cram = mechanism();
+        cram.step(None);
+        match cram.step(Some(b"tim 00000000000000000000000000000000")) {
+            SaslStep::Failure => (),
+            _ => panic!("expected failure"),
+        }
+    }
+}
@@ -5,8 +5,12 @@ use address::Address;
 pub enum Command {
     HELO(String),
     EHLO(String),
-    MAIL_FROM(Address),
-    RCPT_TO(Address),
+    /// `None` for the null reverse-path `<>` of notifications.
+    MAIL_FROM(Option<Address>, Parameters),
+    RCPT_TO(Address, Parameters),
+    /// Mechanism name and optional initial response (RFC 4954)
+    AUTH(String, Option<String>),
+    STARTTLS,
     DATA,
     QUIT,
     VERIFY,
@@ -14,6 +18,29 @@ pub enum Command {
     NOOP
 }
 
+/// ESMTP parameters of MAIL FROM or RCPT TO, e.g. `BODY=8BITMIME`
+/// (RFC 5321 section 4.1.2). Keywords are kept in upper case.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Parameters(pub Vec<(String, Option<String>)>);
+
+impl Parameters {
+    pub fn new() -> Parameters {
+        Parameters(Vec::new())
+    }
+
+    /// `Some(None)` for a parameter without value.
+    pub fn get(&self, keyword: &str) -> Option<Option<&str>> {
+        self.0
+            .iter()
+            .find(|&&(ref k, _)| k.eq_ignore_ascii_case(keyword))
+            .map(|&(_, ref v)| v.as_ref().map(|v| &v[..]))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Eq, PartialEq, Debug)]
 pub enum ParseError {
@@ -22,4 +49,6 @@ pub enum ParseError {
     UnknownCommand,
     MalformedCommand(&'static str),
     UnexpectedEndOfInput,
+    /// The connection's read timeout expired.
+    Timeout,
 }
@@ -0,0 +1,382 @@
+use std::time::SystemTime;
+use address::Address;
+use data::Parameters;
+use envelope::BodyType;
+use payload::Payload;
+use received::rfc5322_date;
+
+/// What the sender is told about a recipient (RFC 3464 section 2.3.3).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Failed,
+    /// Stored in the recipient's mailbox.
+    Delivered,
+    /// Handed to a server that was not asked for notifications, so this is
+    /// the last one the sender gets.
+    Relayed,
+}
+
+impl Action {
+    fn name(&self) -> &'static str {
+        match *self {
+            Action::Failed => "failed",
+            Action::Delivered => "delivered",
+            Action::Relayed => "relayed",
+        }
+    }
+
+    /// The NOTIFY keyword asking for this action (RFC 3461 section 4.1).
+    fn keyword(&self) -> &'static str {
+        match *self {
+            Action::Failed => "FAILURE",
+            Action::Delivered | Action::Relayed => "SUCCESS",
+        }
+    }
+}
+
+/// One recipient of a `DeliveryReport`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecipientReport {
+    pub recipient: Address,
+    /// Its RCPT parameters, for NOTIFY and ORCPT.
+    pub parameters: Parameters,
+    pub action: Action,
+    /// Enhanced status code (RFC 3463), e.g. `5.1.1`.
+    pub status: String,
+    /// The last reply of the receiving server, or why there was none.
+    pub diagnostic: Option<String>,
+}
+
+/// What became of some recipients of a message, to be sent back to its
+/// sender as a `multipart/report` (RFC 3464).
+#[derive(Clone, Debug)]
+pub struct DeliveryReport {
+    /// Our host name.
+    pub reporting_mta: String,
+    /// Queue id of the message reported on.
+    pub queue_id: String,
+    pub sender: Option<Address>,
+    /// MAIL parameters of the message, for RET and ENVID.
+    pub mail_parameters: Parameters,
+    pub arrival_date: SystemTime,
+    pub recipients: Vec<RecipientReport>,
+}
+
+impl DeliveryReport {
+    /// The notification for the sender of the message whose content is
+    /// `data`. `None` for the null reverse-path, which must never get one,
+    /// and if no recipient asked for one with NOTIFY.
+    pub fn notification(&self, data: &[u8], now: SystemTime) -> Option<Payload> {
+        let sender = match self.sender {
+            Some(ref sender) => sender,
+            None => return None,
+        };
+        let recipients = self.recipients
+                             .iter()
+                             .filter(|r| wants_notification(&r.parameters, r.action))
+                             .collect::<Vec<_>>();
+        if recipients.is_empty() {
+            return None;
+        }
+        let failed = recipients.iter().any(|r| r.action == Action::Failed);
+        // RET only applies to failures
+        let full = failed &&
+                   self.mail_parameters
+                       .get("RET")
+                       .map_or(false, |ret| ret.map_or(false, |r| r.eq_ignore_ascii_case("FULL")));
+        let returned = if full { data } else { _header_section(data) };
+
+        let mut payload = Payload::new();
+        payload.envelope.received_at = now;
+        let boundary = format!("{}/{}", payload.envelope.queue_id, self.reporting_mta);
+        let mut text = String::new();
+        text.push_str(&format!("From: Mail Delivery System <MAILER-DAEMON@{}>\r\n",
+                               self.reporting_mta));
+        text.push_str(&format!("To: <{}>\r\n", sender));
+        if failed {
+            text.push_str("Subject: Undelivered Mail Returned to Sender\r\n");
+        } else {
+            text.push_str("Subject: Successful Mail Delivery Report\r\n");
+        }
+        text.push_str(&format!("Date: {}\r\n", rfc5322_date(now)));
+        text.push_str(&format!("Message-ID: <{}@{}>\r\n",
+                               payload.envelope.queue_id,
+                               self.reporting_mta));
+        // RFC 3834, keeps vacation responders quiet
+        text.push_str("Auto-Submitted: auto-replied\r\n");
+        text.push_str("MIME-Version: 1.0\r\n");
+        text.push_str(&format!("Content-Type: multipart/report; \
+                                report-type=delivery-status;\r\n\tboundary=\"{}\"\r\n\r\n",
+                               boundary));
+        text.push_str("This is a MIME-encapsulated message.\r\n\r\n");
+
+        text.push_str(&format!("--{}\r\n", boundary));
+        text.push_str("Content-Type: text/plain; charset=utf-8\r\n\r\n");
+        text.push_str(&format!("This is the mail system at host {}.\r\n", self.reporting_mta));
+        for &(action, summary) in &[(Action::Failed,
+                                     "Your message could not be delivered to the following \
+                                      recipients."),
+                                    (Action::Delivered,
+                                     "Your message was delivered to the mailboxes of the \
+                                      following recipients."),
+                                    (Action::Relayed,
+                                     "Your message was handed on to the mail servers of the \
+                                      following recipients, which will not send further \
+                                      notifications.")] {
+            if !recipients.iter().any(|r| r.action == action) {
+                continue;
+            }
+            text.push_str(&format!("\r\n{}\r\n\r\n", summary));
+            for recipient in recipients.iter().filter(|r| r.action == action) {
+                match recipient.diagnostic {
+                    Some(ref diagnostic) => {
+                        text.push_str(&format!("<{}>: {}\r\n", recipient.recipient, diagnostic))
+                    }
+                    None => text.push_str(&format!("<{}>\r\n", recipient.recipient)),
+                }
+            }
+        }
+
+        text.push_str(&format!("\r\n--{}\r\n", boundary));
+        text.push_str("Content-Type: message/delivery-status\r\n\r\n");
+        text.push_str(&format!("Reporting-MTA: dns; {}\r\n", self.reporting_mta));
+        if let Some(Some(envid)) = self.mail_parameters.get("ENVID") {
+            text.push_str(&format!("Original-Envelope-Id: {}\r\n", _decode_xtext(envid)));
+        }
+        text.push_str(&format!("X-Queue-ID: {}\r\n", self.queue_id));
+        text.push_str(&format!("Arrival-Date: {}\r\n", rfc5322_date(self.arrival_date)));
+        for recipient in recipients.iter() {
+            text.push_str("\r\n");
+            if let Some(Some(orcpt)) = recipient.parameters.get("ORCPT") {
+                let mut parts = orcpt.splitn(2, ';');
+                let address_type = parts.next().unwrap_or("");
+                let address = parts.next().unwrap_or("");
+                text.push_str(&format!("Original-Recipient: {};{}\r\n",
+                                       address_type,
+                                       _decode_xtext(address)));
+            }
+            text.push_str(&format!("Final-Recipient: rfc822; {}\r\n", recipient.recipient));
+            text.push_str(&format!("Action: {}\r\n", recipient.action.name()));
+            text.push_str(&format!("Status: {}\r\n", recipient.status));
+            if let Some(ref diagnostic) = recipient.diagnostic {
+                if status_code(diagnostic).is_some() {
+                    text.push_str(&format!("Diagnostic-Code: smtp; {}\r\n", diagnostic));
+                }
+            }
+            text.push_str(&format!("Last-Attempt-Date: {}\r\n", rfc5322_date(now)));
+        }
+
+        text.push_str(&format!("\r\n--{}\r\n", boundary));
+        if full {
+            text.push_str("Content-Type: message/rfc822\r\n\r\n");
+        } else {
+            text.push_str("Content-Type: text/rfc822-headers\r\n\r\n");
+        }
+        let mut data = text.into_bytes();
+        data.extend(returned.iter());
+        if !data.ends_with(b"\r\n") {
+            data.extend(b"\r\n".iter());
+        }
+        data.extend(format!("\r\n--{}--\r\n", boundary).bytes());
+
+        if data.iter().any(|&b| b >= 0x80) {
+            payload.envelope.body_type = BodyType::EightBitMime;
+            payload.envelope.mail_parameters = Parameters(vec![("BODY".to_string(),
+                                                                Some("8BITMIME".to_string()))]);
+        }
+        payload.add_recipient(sender.clone());
+        payload.envelope.recipient_parameters.push(Parameters::new());
+        payload.data = data;
+        Some(payload)
+    }
+}
+
+/// Whether RCPT `parameters` ask for a notification about `action`. Without
+/// NOTIFY only failures are reported (RFC 3461 section 4.1).
+pub fn wants_notification(parameters: &Parameters, action: Action) -> bool {
+    match parameters.get("NOTIFY") {
+        Some(Some(notify)) => notify.split(',').any(|k| k.eq_ignore_ascii_case(action.keyword())),
+        _ => action == Action::Failed,
+    }
+}
+
+/// The enhanced status code of an SMTP `reply` like `550 5.1.1 No such
+/// user`, made up from the reply code if it has none. `None` if `reply`
+/// is not an SMTP reply.
+pub fn status_code(reply: &str) -> Option<String> {
+    let mut words = reply.split_whitespace();
+    let code = words.next().unwrap_or("");
+    let class = match code.chars().next() {
+        Some(class) if code.len() == 3 && code.chars().all(|c| c.is_ascii_digit()) &&
+                       "245".contains(class) => class,
+        _ => return None,
+    };
+    match words.next() {
+        Some(word) if word.starts_with(class) && word.split('.').count() == 3 &&
+                      word.split('.').all(|part| {
+                          !part.is_empty() && part.chars().all(|c| c.is_ascii_digit())
+                      }) => Some(word.to_string()),
+        _ => Some(format!("{}.0.0", class)),
+    }
+}
+
+/// Everything up to and including the line ending the header section.
+fn _header_section(data: &[u8]) -> &[u8] {
+    match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(position) => &data[..position + 2],
+        None => data,
+    }
+}
+
+/// Undoes the `+XX` escapes of ENVID and ORCPT (RFC 3461 section 4). Control
+/// characters become spaces, as the result goes into a header line.
+fn _decode_xtext(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'+' && i + 2 < bytes.len() {
+            let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]);
+            if let Ok(byte) = u8::from_str_radix(&hex, 16) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded)
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+pub mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use address::Address;
+    use data::Parameters;
+    use dsn::{Action, DeliveryReport, RecipientReport, status_code, wants_notification};
+
+    fn parameters(pairs: &[(&str, &str)]) -> Parameters {
+        Parameters(pairs.iter().map(|&(k, v)| (k.to_string(), Some(v.to_string()))).collect())
+    }
+
+    fn report() -> DeliveryReport {
+        DeliveryReport {
+            reporting_mta: "mx.example.org".to_string(),
+            queue_id: "A00001".to_string(),
+            sender: Some(Address::new("matt", "example.org")),
+            mail_parameters: parameters(&[("ENVID", "QQ314159+2B1")]),
+            arrival_date: UNIX_EPOCH + Duration::from_secs(1546344000),
+            recipients: vec![RecipientReport {
+                                 recipient: Address::new("gone", "example.net"),
+                                 parameters: parameters(&[("ORCPT",
+                                                           "rfc822;Gone+2BHome@example.net")]),
+                                 action: Action::Failed,
+                                 status: "5.1.1".to_string(),
+                                 diagnostic: Some("550 5.1.1 No such user".to_string()),
+                             },
+                             RecipientReport {
+                                 recipient: Address::new("marie", "example.net"),
+                                 parameters: parameters(&[("NOTIFY", "SUCCESS,FAILURE")]),
+                                 action: Action::Relayed,
+                                 status: "2.0.0".to_string(),
+                                 diagnostic: None,
+                             },
+                             RecipientReport {
+                                 recipient: Address::new("quiet", "example.net"),
+                                 parameters: parameters(&[("NOTIFY", "NEVER")]),
+                                 action: Action::Failed,
+                                 status: "5.0.0".to_string(),
+                                 diagnostic: None,
+                             }],
+        }
+    }
+
+    const MESSAGE: &'static [u8] = b"Subject: hi\r\nFrom: matt@example.org\r\n\r\nSecret\r\n";
+
+    #[test]
+    fn reports_to_the_sender() {
+        let now = UNIX_EPOCH + Duration::from_secs(1546347600);
+        let notification = report().notification(MESSAGE, now).unwrap();
+        assert_eq!(None, notification.sender);
+        assert_eq!(vec![Address::new("matt", "example.org")], notification.recipients);
+        let text = String::from_utf8(notification.data).unwrap();
+        assert!(text.contains("Subject: Undelivered Mail Returned to Sender\r\n"));
+        assert!(text.contains("Content-Type: multipart/report; report-type=delivery-status;"));
+        assert!(text.contains("<gone@example.net>: 550 5.1.1 No such user\r\n"));
+        assert!(text.contains("Reporting-MTA: dns; mx.example.org\r\nOriginal-Envelope-Id: \
+                               QQ314159+1\r\n"));
+        assert!(text.contains("Arrival-Date: Tue, 01 Jan 2019 12:00:00 +0000\r\n"));
+        assert!(text.contains("\r\n\r\nOriginal-Recipient: rfc822;Gone+Home@example.net\r\n\
+                               Final-Recipient: rfc822; gone@example.net\r\nAction: \
+                               failed\r\nStatus: 5.1.1\r\nDiagnostic-Code: smtp; 550 5.1.1 No \
+                               such user\r\nLast-Attempt-Date: Tue, 01 Jan 2019 13:00:00 \
+                               +0000\r\n"));
+        assert!(text.contains("Final-Recipient: rfc822; marie@example.net\r\nAction: \
+                               relayed\r\nStatus: 2.0.0\r\n"));
+        assert!(!text.contains("quiet@example.net"));
+        assert!(text.contains("Content-Type: text/rfc822-headers\r\n\r\nSubject: hi\r\nFrom: \
+                               matt@example.org\r\n\r\n--"));
+        assert!(!text.contains("Secret"));
+        assert!(text.ends_with("--\r\n"));
+    }
+
+    #[test]
+    fn keeps_decoded_parameters_on_one_line() {
+        let mut report = report();
+        report.mail_parameters = parameters(&[("ENVID", "x+0D+0ABcc:+20victim@example.com")]);
+        report.recipients[0].parameters = parameters(&[("ORCPT", "rfc822;a+0ABcc:@example.net")]);
+        let text = String::from_utf8(report.notification(MESSAGE, UNIX_EPOCH).unwrap().data)
+                       .unwrap();
+        assert!(text.contains("Original-Envelope-Id: x  Bcc: victim@example.com\r\n"));
+        assert!(text.contains("Original-Recipient: rfc822;a Bcc:@example.net\r\n"));
+        assert!(!text.contains("\nBcc:"));
+    }
+
+    #[test]
+    fn returns_the_full_message_on_request() {
+        let mut report = report();
+        report.mail_parameters = parameters(&[("RET", "FULL")]);
+        let text = String::from_utf8(report.notification(MESSAGE, UNIX_EPOCH).unwrap().data)
+                       .unwrap();
+        assert!(text.contains("Content-Type: message/rfc822\r\n\r\nSubject: hi\r\n"));
+        assert!(text.contains("\r\n\r\nSecret\r\n"));
+
+        // no failure, nothing to return
+        report.recipients.remove(0);
+        let text = String::from_utf8(report.notification(MESSAGE, UNIX_EPOCH).unwrap().data)
+                       .unwrap();
+        assert!(text.contains("Subject: Successful Mail Delivery Report\r\n"));
+        assert!(!text.contains("Secret"));
+    }
+
+    #[test]
+    fn never_notifies_the_null_sender() {
+        let mut report = report();
+        report.recipients.remove(0);
+        report.recipients.remove(0);
+        assert!(report.notification(MESSAGE, UNIX_EPOCH).is_none());
+        let mut report = self::report();
+        report.sender = None;
+        assert!(report.notification(MESSAGE, UNIX_EPOCH).is_none());
+    }
+
+    #[test]
+    fn honours_notify() {
+        assert!(wants_notification(&Parameters::new(), Action::Failed));
+        assert!(!wants_notification(&Parameters::new(), Action::Relayed));
+        assert!(wants_notification(&parameters(&[("NOTIFY", "delay,success")]),
+                                   Action::Relayed));
+        assert!(!wants_notification(&parameters(&[("NOTIFY", "NEVER")]), Action::Failed));
+    }
+
+    #[test]
+    fn finds_status_codes() {
+        assert_eq!(Some("5.1.1".to_string()), status_code("550 5.1.1 No such user"));
+        assert_eq!(Some("4.0.0".to_string()), status_code("451 Try again later"));
+        assert_eq!(Some("5.0.0".to_string()), status_code("554 4.4.1 Mixed up"));
+        assert_eq!(None, status_code("connection refused"));
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use rand::{thread_rng, Rng};
+use data::Parameters;
+use session::SessionContext;
+
+/// Body type declared with the BODY parameter of MAIL FROM (RFC 6152).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyType {
+    SevenBit,
+    EightBitMime,
+}
+
+impl BodyType {
+    pub fn from_parameter(value: &str) -> Option<BodyType> {
+        match &value.to_uppercase()[..] {
+            "7BIT" => Some(BodyType::SevenBit),
+            "8BITMIME" => Some(BodyType::EightBitMime),
+            _ => None,
+        }
+    }
+}
+
+/// Everything known about a message besides sender, recipients and data.
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    /// Unique id of the message on this server, also used in log lines.
+    pub queue_id: String,
+    /// When the end of the data was received.
+    pub received_at: SystemTime,
+    /// Client HELO name, addresses, TLS and authenticated user.
+    pub session: SessionContext,
+    pub mail_parameters: Parameters,
+    /// Parameters of each RCPT TO, in the order of `Payload::recipients`.
+    pub recipient_parameters: Vec<Parameters>,
+    pub body_type: BodyType,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            queue_id: new_queue_id(),
+            received_at: SystemTime::now(),
+            session: SessionContext::new(),
+            mail_parameters: Parameters::new(),
+            recipient_parameters: Vec::new(),
+            body_type: BodyType::SevenBit,
+        }
+    }
+}
+
+/// Time based and sortable, with a random suffix for messages received in
+/// the same microsecond, e.g. `00652F1A3C0E2B1A4HT9XQ`.
+pub fn new_queue_id() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let suffix = thread_rng()
+                     .gen_ascii_chars()
+                     .filter(|c| c.is_ascii_digit() || c.is_ascii_uppercase())
+                     .take(6)
+                     .collect::<String>();
+    format!("{:010X}{:05X}{}", now.as_secs(), now.subsec_micros(), suffix)
+}
@@ -1,19 +1,55 @@
 #[macro_use]
 extern crate log;
-extern crate email;
-extern crate lettre;
 extern crate rand;
+extern crate rustc_serialize;
+extern crate md5;
+extern crate sha2;
+extern crate hmac;
+extern crate pwhash;
+extern crate argon2;
+extern crate time;
+extern crate toml;
+extern crate native_tls;
+extern crate libc;
+extern crate dns_parser;
+#[cfg(feature = "sqlite")]
+#[macro_use]
+extern crate rusqlite;
 
 pub mod smtp;
 pub mod parser;
 pub mod data;
-mod address;
-mod payload;
-mod payload_handler;
+pub mod auth;
+pub mod password_file;
+pub mod auth_throttle;
+pub mod session;
+pub mod envelope;
+pub mod received;
+pub mod config;
+pub mod payload;
+pub mod tls;
+pub mod server;
+pub mod shutdown;
+pub mod sink;
+pub mod client;
+pub mod payload_handler;
+pub mod resolver;
+pub mod mx;
+pub mod dsn;
+pub mod local;
+pub mod spool;
+pub mod address;
 mod parse_util;
 mod smtp_state;
 mod response;
 mod smtp_error;
+mod cram_md5;
+mod scram;
+mod oauth;
+mod plain;
+mod connection_limit;
+mod worker_pool;
+mod watched_file;
 
 fn ascii_upcase(ascii: u8) -> u8 {
     if ascii >= b'a' && ascii <= b'z' {
@@ -0,0 +1,454 @@
+use std::io;
+use std::sync::Arc;
+use address::Address;
+use client::Reply;
+use payload::Payload;
+use payload_handler::{RecipientStatus, RelayError, Transport};
+use received::return_path_header;
+
+pub mod maildir;
+pub mod mbox;
+pub mod users;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// What a local address stands for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum User {
+    /// A user whose mail goes to this mailbox.
+    Mailbox(String),
+    /// An alias whose mail goes to these addresses instead, local or not.
+    Alias(Vec<Address>),
+    Unknown,
+}
+
+/// Knows the local users. Consulted at RCPT time, so that mail for unknown
+/// users is refused right away, and again when the mail is delivered.
+pub trait UserDirectory: Send + Sync {
+    /// Errors mean the directory could not be read, the address is tried
+    /// again later.
+    fn lookup(&self, address: &Address) -> io::Result<User>;
+}
+
+/// Every address whose local part is a safe mailbox name is a user, the
+/// mailbox being the local part in lower case and without a `+detail`
+/// suffix.
+pub struct LocalPart;
+
+impl UserDirectory for LocalPart {
+    fn lookup(&self, address: &Address) -> io::Result<User> {
+        let name = user_name(address);
+        if is_mailbox_name(&name) {
+            Ok(User::Mailbox(name))
+        } else {
+            Ok(User::Unknown)
+        }
+    }
+}
+
+/// What directories look `address` up by: the local part in lower case and
+/// without a `+detail` suffix.
+pub fn user_name(address: &Address) -> String {
+    address.address.split('+').next().unwrap_or("").to_lowercase()
+}
+
+/// An alias target as written in a directory, either a full address or
+/// just a user name in the domain of the alias `address`.
+pub fn alias_target(target: &str, address: &Address) -> Option<Address> {
+    let target = target.trim();
+    let mut parts = target.rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) if !local.is_empty() && !domain.is_empty() => {
+            Some(Address::new(local, domain))
+        }
+        (Some(local), None) if !local.is_empty() => Some(Address::new(local, &address.domain)),
+        _ => None,
+    }
+}
+
+/// Whether `domain` is one of `domains`, ignoring case.
+pub fn is_local_domain(domains: &[String], domain: &str) -> bool {
+    domains.iter().any(|local| local.eq_ignore_ascii_case(domain))
+}
+
+/// Whether `name` can be used as a file name below a mail directory.
+pub fn is_mailbox_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') &&
+    name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// Where messages for local users are kept, e.g. a `Maildir` per user.
+pub trait MailStore: Send + Sync {
+    /// Adds the copy of `payload` for `recipient` to `mailbox`, durably
+    /// before returning.
+    fn store(&self, mailbox: &str, payload: &Payload, recipient: &Address) -> io::Result<()>;
+}
+
+/// The message as it is stored for `recipient`: with `Return-Path` and
+/// `Delivered-To` header fields and Unix line endings.
+pub fn local_copy(payload: &Payload, recipient: &Address) -> Vec<u8> {
+    let mut data = return_path_header(payload.sender.as_ref()).into_bytes();
+    data.extend(format!("Delivered-To: {}\r\n", recipient).bytes());
+    data.extend(payload.data.iter());
+    let mut copy = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b != b'\r' || data.get(i + 1) != Some(&b'\n') {
+            copy.push(b);
+        }
+    }
+    copy
+}
+
+/// Delivers to local users: looks up the mailbox of each recipient and
+/// puts a copy into `store`. Aliases have to be expanded before, e.g. by a
+/// `LocalRouter`.
+pub struct LocalDelivery {
+    users: Arc<UserDirectory>,
+    store: Arc<MailStore>,
+}
+
+impl LocalDelivery {
+    pub fn new(users: Arc<UserDirectory>, store: Arc<MailStore>) -> LocalDelivery {
+        LocalDelivery {
+            users: users,
+            store: store,
+        }
+    }
+}
+
+impl Transport for LocalDelivery {
+    fn deliver(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        let queue_id = &payload.envelope.queue_id;
+        let mut delivered = Vec::new();
+        let mut statuses = Vec::new();
+        for recipient in payload.recipients.iter() {
+            let status = match self.users.lookup(recipient) {
+                Ok(User::Unknown) => {
+                    RecipientStatus::Failed(_reply(550,
+                                                   &format!("5.1.1 <{}>: No such user",
+                                                            recipient)))
+                }
+                Ok(User::Alias(_)) => {
+                    error!("{}: alias {} was not expanded", queue_id, recipient);
+                    RecipientStatus::Deferred(_reply(451, "4.3.5 Alias not expanded"))
+                }
+                Err(err) => {
+                    error!("{}: could not look up {}: {}", queue_id, recipient, err);
+                    RecipientStatus::Deferred(_reply(451,
+                                                     &format!("4.3.0 User lookup failed: {}",
+                                                              err)))
+                }
+                // the directory must not send us outside of the mail directory
+                Ok(User::Mailbox(ref mailbox)) if !is_mailbox_name(mailbox) => {
+                    error!("{}: invalid mailbox {:?} for {}", queue_id, mailbox, recipient);
+                    RecipientStatus::Deferred(_reply(451, "4.3.5 Invalid mailbox name"))
+                }
+                // e.g. two addresses of the same user
+                Ok(User::Mailbox(ref mailbox)) if delivered.contains(mailbox) => {
+                    RecipientStatus::Delivered
+                }
+                Ok(User::Mailbox(mailbox)) => {
+                    match self.store.store(&mailbox, payload, recipient) {
+                        Ok(()) => {
+                            info!("{}: stored for {} in {}", queue_id, recipient, mailbox);
+                            delivered.push(mailbox);
+                            RecipientStatus::Delivered
+                        }
+                        Err(err) => {
+                            error!("{}: could not store for {}: {}", queue_id, recipient, err);
+                            RecipientStatus::Deferred(_reply(451,
+                                                             &format!("4.3.0 Could not store \
+                                                                       message: {}",
+                                                                      err)))
+                        }
+                    }
+                }
+            };
+            statuses.push((recipient.clone(), status));
+        }
+        Ok(statuses)
+    }
+}
+
+/// Aliases that still point to aliases after this many expansions are
+/// taken for a loop.
+const MAX_ALIAS_DEPTH: usize = 10;
+
+/// Hands recipients in `domains` to `local` and everybody else to
+/// `remote`, after expanding local aliases with `users`.
+///
+/// A recipient whose alias has several targets is deferred if any target
+/// is, and delivered otherwise if any target is. When it is tried again
+/// later, the other targets may get the message twice.
+pub struct LocalRouter {
+    domains: Vec<String>,
+    users: Arc<UserDirectory>,
+    local: Arc<Transport>,
+    remote: Arc<Transport>,
+}
+
+impl LocalRouter {
+    pub fn new(domains: Vec<String>,
+               users: Arc<UserDirectory>,
+               local: Arc<Transport>,
+               remote: Arc<Transport>)
+               -> LocalRouter {
+        LocalRouter {
+            domains: domains,
+            users: users,
+            local: local,
+            remote: remote,
+        }
+    }
+
+    pub fn is_local(&self, address: &Address) -> bool {
+        is_local_domain(&self.domains, &address.domain)
+    }
+
+    /// The addresses mail for `recipient` goes to: `recipient` itself
+    /// unless it is a local alias.
+    fn _expand(&self, recipient: &Address) -> Result<Vec<Address>, RecipientStatus> {
+        let mut expanded = Vec::new();
+        let mut seen = vec![_alias_key(recipient)];
+        let mut pending = vec![(recipient.clone(), 0)];
+        while let Some((address, depth)) = pending.pop() {
+            if !self.is_local(&address) {
+                expanded.push(address);
+                continue;
+            }
+            let targets = match self.users.lookup(&address) {
+                Ok(User::Alias(targets)) => targets,
+                Ok(_) => {
+                    expanded.push(address);
+                    continue;
+                }
+                Err(err) => {
+                    error!("Could not look up {}: {}", address, err);
+                    return Err(RecipientStatus::Deferred(_reply(451,
+                                                                &format!("4.3.0 User lookup \
+                                                                          failed: {}",
+                                                                         err))));
+                }
+            };
+            if depth == MAX_ALIAS_DEPTH {
+                return Err(RecipientStatus::Failed(_reply(554,
+                                                          &format!("5.4.6 <{}>: Alias loop",
+                                                                   recipient))));
+            }
+            for target in targets.into_iter().rev() {
+                let key = _alias_key(&target);
+                if !seen.contains(&key) {
+                    seen.push(key);
+                    pending.push((target, depth + 1));
+                }
+            }
+        }
+        if expanded.is_empty() {
+            return Err(RecipientStatus::Failed(_reply(554,
+                                                      &format!("5.4.6 <{}>: Alias expands to \
+                                                                no one",
+                                                               recipient))));
+        }
+        Ok(expanded)
+    }
+}
+
+impl Transport for LocalRouter {
+    fn deliver(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        let mut statuses = vec![Vec::new(); payload.recipients.len()];
+        // the recipient each target is for, and the target
+        let mut targets = Vec::new();
+        for (i, recipient) in payload.recipients.iter().enumerate() {
+            match self._expand(recipient) {
+                Ok(expanded) => targets.extend(expanded.into_iter().map(|target| (i, target))),
+                Err(status) => statuses[i].push(status),
+            }
+        }
+        let (local, remote): (Vec<_>, Vec<_>) =
+            targets.into_iter().partition(|&(_, ref target)| self.is_local(target));
+
+        for &(ref targets, ref transport) in &[(local, &self.local), (remote, &self.remote)] {
+            if targets.is_empty() {
+                continue;
+            }
+            let mut part = payload.subset(&[]);
+            for &(i, ref target) in targets {
+                part.recipients.push(target.clone());
+                if let Some(parameters) = payload.envelope.recipient_parameters.get(i) {
+                    part.envelope.recipient_parameters.push(parameters.clone());
+                }
+            }
+            let part_statuses = match transport.deliver(&part) {
+                Ok(part_statuses) => part_statuses.into_iter().map(|(_, s)| s).collect(),
+                // the other part may have gone through already
+                Err(ref err) if err.is_transient() => {
+                    let reply = _reply(451, &format!("4.4.0 {}", err));
+                    vec![RecipientStatus::Deferred(reply); targets.len()]
+                }
+                Err(err) => {
+                    let reply = _reply(554, &format!("5.4.0 {}", err));
+                    vec![RecipientStatus::Failed(reply); targets.len()]
+                }
+            };
+            for (&(i, _), status) in targets.iter().zip(part_statuses) {
+                statuses[i].push(status);
+            }
+        }
+        Ok(payload.recipients
+                  .iter()
+                  .cloned()
+                  .zip(statuses.into_iter().map(_combine))
+                  .collect())
+    }
+}
+
+/// The status of a recipient from those of its alias targets.
+fn _combine(statuses: Vec<RecipientStatus>) -> RecipientStatus {
+    if let Some(deferred) = statuses.iter().find(|status| match **status {
+        RecipientStatus::Deferred(_) => true,
+        _ => false,
+    }) {
+        return deferred.clone();
+    }
+    if let Some(accepted) = statuses.iter().find(|status| status.is_accepted()) {
+        return accepted.clone();
+    }
+    statuses.into_iter().next().unwrap()
+}
+
+fn _alias_key(address: &Address) -> (String, String) {
+    (address.address.to_lowercase(), address.domain.to_lowercase())
+}
+
+fn _reply(code: u16, line: &str) -> Reply {
+    Reply {
+        code: code,
+        lines: vec![line.to_string()],
+    }
+}
+
+pub mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use address::Address;
+    use local::{LocalDelivery, LocalPart, LocalRouter, MailStore, User, UserDirectory,
+                alias_target, local_copy};
+    use local::users::StaticUsers;
+    use payload::Payload;
+    use payload_handler::{RecipientStatus, RelayError, Transport};
+
+    /// Keeps what it is given, and fails for the mailbox `full`.
+    pub struct MemoryStore {
+        pub messages: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    impl MailStore for MemoryStore {
+        fn store(&self, mailbox: &str, payload: &Payload, recipient: &Address) -> io::Result<()> {
+            if mailbox == "full" {
+                return Err(io::Error::new(io::ErrorKind::Other, "disk full"));
+            }
+            self.messages
+                .lock()
+                .unwrap()
+                .push((mailbox.to_string(), local_copy(payload, recipient)));
+            Ok(())
+        }
+    }
+
+    /// Defers everybody.
+    struct Unreachable;
+
+    impl Transport for Unreachable {
+        fn deliver(&self, _: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+            Err(RelayError::StartTlsUnavailable)
+        }
+    }
+
+    fn code(status: &RecipientStatus) -> u16 {
+        match *status {
+            RecipientStatus::Delivered |
+            RecipientStatus::Relayed |
+            RecipientStatus::RelayedWithDsn => 250,
+            RecipientStatus::Deferred(ref reply) |
+            RecipientStatus::Failed(ref reply) => reply.code,
+        }
+    }
+
+    #[test]
+    fn maps_local_parts_to_mailboxes() {
+        assert_eq!(User::Mailbox("marie".to_string()),
+                   LocalPart.lookup(&Address::new("Marie+lists", "example.org")).unwrap());
+        assert_eq!(User::Unknown,
+                   LocalPart.lookup(&Address::new("../etc", "example.org")).unwrap());
+        assert_eq!(User::Unknown, LocalPart.lookup(&Address::new("+x", "example.org")).unwrap());
+
+        let alias = Address::new("postmaster", "example.org");
+        assert_eq!(Some(Address::new("marie", "example.org")),
+                   alias_target(" marie", &alias));
+        assert_eq!(Some(Address::new("max", "example.net")),
+                   alias_target("max@example.net", &alias));
+        assert_eq!(None, alias_target("@example.net", &alias));
+    }
+
+    #[test]
+    fn stores_local_copies_and_routes_the_rest() {
+        let store = Arc::new(MemoryStore { messages: Mutex::new(Vec::new()) });
+        let router = LocalRouter::new(vec!["example.org".to_string()],
+                                      Arc::new(LocalPart),
+                                      Arc::new(LocalDelivery::new(Arc::new(LocalPart),
+                                                                  store.clone())),
+                                      Arc::new(Unreachable));
+        let mut payload = Payload::new();
+        payload.sender = Some(Address::new("matt", "example.net"));
+        payload.add_recipient(Address::new("marie", "Example.ORG"));
+        payload.add_recipient(Address::new("max", "example.net"));
+        payload.add_recipient(Address::new("marie+lists", "example.org"));
+        payload.add_recipient(Address::new("full", "example.org"));
+        payload.add_recipient(Address::new("../etc", "example.org"));
+        payload.data = b"Subject: hi\r\n\r\nHi\r\n".to_vec();
+
+        let statuses = router.deliver(&payload).unwrap();
+        assert_eq!(vec![250, 451, 250, 451, 550],
+                   statuses.iter().map(|&(_, ref s)| code(s)).collect::<Vec<_>>());
+        let messages = store.messages.lock().unwrap();
+        assert_eq!(1, messages.len());
+        assert_eq!("marie", messages[0].0);
+        assert_eq!(b"Return-Path: <matt@example.net>\nDelivered-To: marie@Example.ORG\nSubject: \
+                     hi\n\nHi\n"
+                       .to_vec(),
+                   messages[0].1);
+    }
+
+    #[test]
+    fn expands_aliases() {
+        let users = Arc::new(StaticUsers::new()
+                                 .with_mailbox("marie", "marie")
+                                 .with_mailbox("max", "maxim")
+                                 .with_alias("postmaster", &["Marie", "abuse"])
+                                 .with_alias("abuse", &["max", "postmaster", "max@example.net"])
+                                 .with_alias("loop", &["loop"])
+                                 .with_alias("team", &["marie", "ghost"]));
+        let store = Arc::new(MemoryStore { messages: Mutex::new(Vec::new()) });
+        let router = LocalRouter::new(vec!["example.org".to_string()],
+                                      users.clone(),
+                                      Arc::new(LocalDelivery::new(users, store.clone())),
+                                      Arc::new(Unreachable));
+        let mut payload = Payload::new();
+        for name in &["postmaster", "loop", "team", "nobody"] {
+            payload.add_recipient(Address::new(name, "example.org"));
+        }
+
+        let statuses = router.deliver(&payload).unwrap();
+        // max@example.net is unreachable, ghost of the team is unknown
+        assert_eq!(vec![451, 554, 250, 550],
+                   statuses.iter().map(|&(_, ref s)| code(s)).collect::<Vec<_>>());
+        let mailboxes = store.messages
+                             .lock()
+                             .unwrap()
+                             .iter()
+                             .map(|&(ref mailbox, _)| mailbox.clone())
+                             .collect::<Vec<_>>();
+        // one copy each, though marie is on two lists
+        assert_eq!(vec!["marie", "maxim"], mailboxes);
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use address::Address;
+use local::{MailStore, local_copy};
+use payload::Payload;
+
+/// Deliveries by this process so far, for unique file names.
+static DELIVERIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Stores messages in `<root>/<mailbox>/Maildir`. Each message is written
+/// to `tmp` and moved to `new` once it is on disk, so readers never see
+/// half a message and nothing needs locking.
+pub struct Maildir {
+    root: PathBuf,
+    hostname: String,
+}
+
+impl Maildir {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Maildir {
+        Maildir {
+            root: root.into(),
+            hostname: "localhost".to_string(),
+        }
+    }
+
+    /// Our name in file names, to keep them unique when several hosts
+    /// deliver to the same directory.
+    pub fn with_hostname(mut self, hostname: &str) -> Maildir {
+        // the two characters Maildir readers treat specially
+        self.hostname = hostname.replace('/', "\\057").replace(':', "\\072");
+        self
+    }
+
+    /// The Maildir of `mailbox`.
+    pub fn path(&self, mailbox: &str) -> PathBuf {
+        self.root.join(mailbox).join("Maildir")
+    }
+
+    /// `<seconds>.M<microseconds>P<pid>Q<deliveries>.<host>`, as suggested
+    /// at https://cr.yp.to/proto/maildir.html
+    fn _unique_name(&self) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!("{}.M{}P{}Q{}.{}",
+                now.as_secs(),
+                now.subsec_micros(),
+                process::id(),
+                DELIVERIES.fetch_add(1, Ordering::SeqCst) + 1,
+                self.hostname)
+    }
+}
+
+impl MailStore for Maildir {
+    fn store(&self, mailbox: &str, payload: &Payload, recipient: &Address) -> io::Result<()> {
+        let maildir = self.path(mailbox);
+        for subdirectory in &["tmp", "new", "cur"] {
+            try!(DirBuilder::new().recursive(true).mode(0o700).create(maildir.join(subdirectory)));
+        }
+        let name = self._unique_name();
+        let tmp_path = maildir.join("tmp").join(&name);
+        let result = _write_new(&tmp_path, &local_copy(payload, recipient))
+                         .and_then(|()| fs::rename(&tmp_path, maildir.join("new").join(&name)));
+        if let Err(err) = result {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        File::open(maildir.join("new")).and_then(|directory| directory.sync_all())
+    }
+}
+
+/// Writes `data` to a file that must not exist yet and syncs it.
+fn _write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).create_new(true).mode(0o600).open(path));
+    try!(file.write_all(data));
+    file.sync_all()
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+    use address::Address;
+    use local::{LocalDelivery, LocalPart, MailStore};
+    use local::maildir::Maildir;
+    use payload::Payload;
+    use payload_handler::{RecipientStatus, Transport};
+
+    fn entries(directory: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(directory)
+                            .unwrap()
+                            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn delivers_into_new() {
+        let root = env::temp_dir().join("nibbler-maildir-test");
+        let _ = fs::remove_dir_all(&root);
+        let maildir = Arc::new(Maildir::new(&root).with_hostname("mx.example.org:25"));
+        let mut payload = Payload::new();
+        payload.add_recipient(Address::new("marie", "example.org"));
+        payload.add_recipient(Address::new("max", "example.org"));
+        payload.data = b"Subject: hi\r\n\r\nHi\r\n".to_vec();
+        let delivery = LocalDelivery::new(Arc::new(LocalPart), maildir.clone());
+        assert_eq!(vec![(Address::new("marie", "example.org"), RecipientStatus::Delivered),
+                        (Address::new("max", "example.org"), RecipientStatus::Delivered)],
+                   delivery.deliver(&payload).unwrap());
+        maildir.store("marie", &payload, &Address::new("marie", "example.org")).unwrap();
+
+        let marie = maildir.path("marie");
+        assert_eq!(vec!["cur", "new", "tmp"], entries(&marie));
+        assert!(entries(&marie.join("tmp")).is_empty());
+        let names = entries(&marie.join("new"));
+        assert_eq!(2, names.len());
+        assert!(names[0] != names[1]);
+        assert!(names[0].ends_with(".mx.example.org\\07225"));
+        assert_eq!(b"Return-Path: <>\nDelivered-To: marie@example.org\nSubject: hi\n\nHi\n"
+                       .to_vec(),
+                   fs::read(marie.join("new").join(&names[0])).unwrap());
+        assert_eq!(1, entries(&maildir.path("max").join("new")).len());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
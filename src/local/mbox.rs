@@ -0,0 +1,223 @@
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use libc;
+use time::{self, Timespec};
+use address::Address;
+use local::{MailStore, local_copy};
+use payload::Payload;
+
+/// Dot locks older than this were left behind by a crashed process.
+const STALE_LOCK: u64 = 5 * 60;
+
+/// Appends messages to the single file `<directory>/<mailbox>` in mboxrd
+/// format. Writers are kept apart with both an fcntl lock and a
+/// `<mailbox>.lock` dot lock, as readers may honour either.
+pub struct Mbox {
+    directory: PathBuf,
+    lock_timeout: Duration,
+}
+
+impl Mbox {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Mbox {
+        Mbox {
+            directory: directory.into(),
+            lock_timeout: Duration::from_secs(60),
+        }
+    }
+
+    /// The mbox file of `mailbox`.
+    pub fn path(&self, mailbox: &str) -> PathBuf {
+        self.directory.join(mailbox)
+    }
+}
+
+impl MailStore for Mbox {
+    fn store(&self, mailbox: &str, payload: &Payload, recipient: &Address) -> io::Result<()> {
+        try!(DirBuilder::new().recursive(true).mode(0o755).create(&self.directory));
+        let path = self.path(mailbox);
+        let entry = mbox_entry(payload, recipient, SystemTime::now());
+        let _dot_lock = try!(DotLock::acquire(&path, self.lock_timeout));
+        let file = try!(OpenOptions::new().append(true).create(true).mode(0o600).open(&path));
+        try!(_lock(&file));
+        _append(&file, |mut file| file.write_all(&entry))
+    }
+}
+
+/// The message for `recipient` as one mboxrd entry: a `From ` line with
+/// the sender and `now`, the message with `>` added in front of lines that
+/// look like `From ` lines, and a blank line.
+pub fn mbox_entry(payload: &Payload, recipient: &Address, now: SystemTime) -> Vec<u8> {
+    let sender = payload.sender.as_ref().map_or("MAILER-DAEMON".to_string(), |s| s.to_string());
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let tm = time::at_utc(Timespec::new(now.as_secs() as i64, 0));
+    let mut entry = format!("From {} {}\n",
+                            sender,
+                            time::strftime("%a %b %e %H:%M:%S %Y", &tm).unwrap())
+                        .into_bytes();
+    let message = local_copy(payload, recipient);
+    let message = if message.ends_with(b"\n") {
+        &message[..message.len() - 1]
+    } else {
+        &message[..]
+    };
+    for line in message.split(|&b| b == b'\n') {
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        if line[quotes..].starts_with(b"From ") {
+            entry.push(b'>');
+        }
+        entry.extend(line.iter());
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+/// Runs `write` on the end of `file` and cuts the file back to where it
+/// ended if that fails, so a half written message does not corrupt the
+/// next one.
+fn _append<F>(file: &File, write: F) -> io::Result<()>
+    where F: FnOnce(&File) -> io::Result<()>
+{
+    let size = try!(file.metadata()).len();
+    match write(file).and_then(|()| file.sync_all()) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            if let Err(truncate_err) = file.set_len(size).and_then(|()| file.sync_all()) {
+                error!("Could not truncate mbox after failed write: {}", truncate_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Takes an fcntl write lock on all of `file`, waiting for other holders.
+/// It is released when `file` is closed.
+fn _lock(file: &File) -> io::Result<()> {
+    let mut lock: libc::flock = unsafe { mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLKW, &lock) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `<mbox>.lock`, created exclusively and removed when dropped.
+struct DotLock {
+    path: PathBuf,
+}
+
+impl DotLock {
+    fn acquire(mbox: &Path, timeout: Duration) -> io::Result<DotLock> {
+        let mut path = mbox.as_os_str().to_owned();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        let deadline = Instant::now() + timeout;
+        loop {
+            match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+                Ok(_) => return Ok(DotLock { path: path }),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(err) => return Err(err),
+            }
+            let stale = fs::metadata(&path)
+                            .and_then(|metadata| metadata.modified())
+                            .ok()
+                            .and_then(|modified| modified.elapsed().ok())
+                            .map_or(false, |age| age > Duration::from_secs(STALE_LOCK));
+            if stale {
+                warn!("Removing stale lock {}", path.display());
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                          format!("{} is locked", mbox.display())));
+            }
+            sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for DotLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::io::Write;
+    use std::time::{Duration, UNIX_EPOCH};
+    use address::Address;
+    use local::MailStore;
+    use local::mbox::{Mbox, DotLock, _append, mbox_entry};
+    use payload::Payload;
+
+    fn payload() -> Payload {
+        let mut payload = Payload::new();
+        payload.sender = Some(Address::new("matt", "example.net"));
+        payload.add_recipient(Address::new("marie", "example.org"));
+        payload.data = b"Subject: hi\r\n\r\nFrom here\r\n>From there\r\nFromage\r\n".to_vec();
+        payload
+    }
+
+    #[test]
+    fn escapes_from_lines() {
+        let entry = mbox_entry(&payload(),
+                               &Address::new("marie", "example.org"),
+                               UNIX_EPOCH + Duration::from_secs(1546344000));
+        assert_eq!("From matt@example.net Tue Jan  1 12:00:00 2019\nReturn-Path: \
+                    <matt@example.net>\nDelivered-To: marie@example.org\nSubject: hi\n\n>From \
+                    here\n>>From there\nFromage\n\n",
+                   String::from_utf8(entry).unwrap());
+    }
+
+    #[test]
+    fn appends_under_lock() {
+        let directory = env::temp_dir().join("nibbler-mbox-test");
+        let _ = fs::remove_dir_all(&directory);
+        let mbox = Mbox::new(&directory);
+        let marie = Address::new("marie", "example.org");
+        mbox.store("marie", &payload(), &marie).unwrap();
+        mbox.store("marie", &payload(), &marie).unwrap();
+        let text = String::from_utf8(fs::read(mbox.path("marie")).unwrap()).unwrap();
+        assert_eq!(2, text.matches("From matt@example.net ").count());
+        assert!(text.ends_with("Fromage\n\n"));
+        assert!(!directory.join("marie.lock").exists());
+
+        // held by someone else
+        let lock = DotLock::acquire(&mbox.path("marie"), Duration::from_secs(0)).unwrap();
+        let mut impatient = Mbox::new(&directory);
+        impatient.lock_timeout = Duration::from_millis(200);
+        assert_eq!(io::ErrorKind::WouldBlock,
+                   impatient.store("marie", &payload(), &marie).unwrap_err().kind());
+        drop(lock);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn truncates_failed_writes() {
+        let directory = env::temp_dir().join("nibbler-mbox-truncate-test");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("marie");
+        File::create(&path).unwrap().write_all(b"From a\n\n").unwrap();
+        let file = OpenOptions::new().append(true).open(&path).unwrap();
+        let result = _append(&file, |mut file| {
+            try!(file.write_all(b"From b\nhalf a mess"));
+            Err(io::Error::new(io::ErrorKind::Other, "disk full"))
+        });
+        assert!(result.is_err());
+        assert_eq!(b"From a\n\n".to_vec(), fs::read(&path).unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,102 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use rusqlite::{self, Connection, OptionalExtension};
+use address::Address;
+use local::{User, UserDirectory, alias_target, user_name};
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS users (
+        name TEXT PRIMARY KEY,
+        mailbox TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS aliases (
+        name TEXT NOT NULL,
+        target TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS aliases_name ON aliases (name);
+";
+
+/// Users and aliases in an SQLite database, by user name (see
+/// `user_name`). Names in `aliases` are looked up first, an alias has a
+/// row per target:
+///
+/// ```sql
+/// INSERT INTO users VALUES ('marie', 'marie');
+/// INSERT INTO aliases VALUES ('postmaster', 'marie'), ('postmaster', 'max@example.net');
+/// ```
+pub struct SqliteUsers {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteUsers {
+    /// Opens the database at `path`, creating the tables if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SqliteUsers> {
+        let connection = try!(Connection::open(path).map_err(_sql_error));
+        // the tables may be edited while we are running
+        try!(connection.busy_timeout(Duration::from_secs(10)).map_err(_sql_error));
+        try!(connection.execute_batch(SCHEMA).map_err(_sql_error));
+        Ok(SqliteUsers { connection: Mutex::new(connection) })
+    }
+}
+
+impl UserDirectory for SqliteUsers {
+    fn lookup(&self, address: &Address) -> io::Result<User> {
+        let name = user_name(address);
+        let connection = self.connection.lock().unwrap();
+        let targets = {
+            let mut statement = try!(connection.prepare("SELECT target FROM aliases WHERE \
+                                                         name = ? ORDER BY rowid")
+                                               .map_err(_sql_error));
+            let targets = try!(statement.query_map(params![name], |row| row.get::<_, String>(0))
+                                        .map_err(_sql_error));
+            try!(targets.collect::<Result<Vec<_>, _>>().map_err(_sql_error))
+        };
+        if !targets.is_empty() {
+            return Ok(User::Alias(targets.iter()
+                                         .filter_map(|target| alias_target(target, address))
+                                         .collect()));
+        }
+        let mailbox = try!(connection.query_row("SELECT mailbox FROM users WHERE name = ?",
+                                                params![name],
+                                                |row| row.get::<_, String>(0))
+                                     .optional()
+                                     .map_err(_sql_error));
+        Ok(mailbox.map_or(User::Unknown, User::Mailbox))
+    }
+}
+
+fn _sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use rusqlite::Connection;
+    use address::Address;
+    use local::{User, UserDirectory};
+    use local::sqlite::SqliteUsers;
+
+    #[test]
+    fn looks_up_users_and_aliases() {
+        let path = env::temp_dir().join("nibbler-sqlite-users-test.sqlite");
+        let _ = fs::remove_file(&path);
+        let users = SqliteUsers::open(&path).unwrap();
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("INSERT INTO users VALUES ('marie', 'marie'); INSERT INTO aliases \
+                            VALUES ('postmaster', 'marie'), ('postmaster', 'max@example.net');")
+            .unwrap();
+
+        assert_eq!(User::Mailbox("marie".to_string()),
+                   users.lookup(&Address::new("Marie+lists", "example.org")).unwrap());
+        assert_eq!(User::Alias(vec![Address::new("marie", "example.org"),
+                                    Address::new("max", "example.net")]),
+                   users.lookup(&Address::new("postmaster", "example.org")).unwrap());
+        assert_eq!(User::Unknown,
+                   users.lookup(&Address::new("max", "example.org")).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
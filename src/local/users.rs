@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use address::Address;
+use local::{User, UserDirectory, alias_target, user_name};
+use watched_file::WatchedFile;
+
+enum Entry {
+    Mailbox(String),
+    Alias(Vec<String>),
+}
+
+/// Users and aliases listed in code or in the configuration, by user name
+/// (see `user_name`).
+pub struct StaticUsers {
+    entries: HashMap<String, Entry>,
+}
+
+impl StaticUsers {
+    pub fn new() -> StaticUsers {
+        StaticUsers { entries: HashMap::new() }
+    }
+
+    pub fn with_mailbox(mut self, name: &str, mailbox: &str) -> StaticUsers {
+        self.entries.insert(name.to_lowercase(), Entry::Mailbox(mailbox.to_string()));
+        self
+    }
+
+    /// Mail for `name` goes to `targets`, full addresses or names of other
+    /// users in the same domain.
+    pub fn with_alias<S: AsRef<str>>(mut self, name: &str, targets: &[S]) -> StaticUsers {
+        let targets = targets.iter().map(|target| target.as_ref().to_string()).collect();
+        self.entries.insert(name.to_lowercase(), Entry::Alias(targets));
+        self
+    }
+}
+
+impl UserDirectory for StaticUsers {
+    fn lookup(&self, address: &Address) -> io::Result<User> {
+        match self.entries.get(&user_name(address)) {
+            Some(&Entry::Mailbox(ref mailbox)) => Ok(User::Mailbox(mailbox.clone())),
+            Some(&Entry::Alias(ref targets)) => {
+                Ok(User::Alias(targets.iter()
+                                      .filter_map(|target| alias_target(target, address))
+                                      .collect()))
+            }
+            None => Ok(User::Unknown),
+        }
+    }
+}
+
+/// The users of a file in `/etc/passwd` format, each with the mailbox of
+/// their login name. Only the first field of each line is used, so other
+/// files of `name:...` lines work as well. The file is read again whenever
+/// its modification time changes.
+pub struct PasswdFile {
+    users: WatchedFile<HashMap<String, String>>,
+}
+
+impl PasswdFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PasswdFile> {
+        Ok(PasswdFile { users: try!(WatchedFile::open(path, "users file", _read_users)) })
+    }
+}
+
+impl UserDirectory for PasswdFile {
+    fn lookup(&self, address: &Address) -> io::Result<User> {
+        match self.users.get().get(&user_name(address)) {
+            Some(name) => Ok(User::Mailbox(name.clone())),
+            None => Ok(User::Unknown),
+        }
+    }
+}
+
+fn _read_users(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+
+    let mut users = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let name = line.split(':').next().unwrap_or("");
+        if !name.is_empty() {
+            users.insert(name.to_lowercase(), name.to_string());
+        }
+    }
+    Ok(users)
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process;
+    use address::Address;
+    use local::{User, UserDirectory};
+    use local::users::{PasswdFile, StaticUsers};
+
+    #[test]
+    fn looks_up_static_users() {
+        let users = StaticUsers::new()
+                        .with_mailbox("Marie", "marie")
+                        .with_alias("postmaster", &["marie", "max@example.net"]);
+        assert_eq!(User::Mailbox("marie".to_string()),
+                   users.lookup(&Address::new("marie+lists", "example.org")).unwrap());
+        assert_eq!(User::Alias(vec![Address::new("marie", "example.com"),
+                                    Address::new("max", "example.net")]),
+                   users.lookup(&Address::new("PostMaster", "example.com")).unwrap());
+        assert_eq!(User::Unknown,
+                   users.lookup(&Address::new("max", "example.org")).unwrap());
+    }
+
+    #[test]
+    fn reads_passwd_files() {
+        let path = env::temp_dir().join(format!("nibbler-passwd-test-{}", process::id()));
+        File::create(&path)
+            .unwrap()
+            .write_all(b"# users\nroot:x:0:0:root:/root:/bin/sh\nMarie:x:1000:1000::/home/marie:\
+                         /bin/sh\n\n")
+            .unwrap();
+        let users = PasswdFile::open(&path).unwrap();
+        assert_eq!(User::Mailbox("Marie".to_string()),
+                   users.lookup(&Address::new("marie", "example.org")).unwrap());
+        assert_eq!(User::Unknown,
+                   users.lookup(&Address::new("x", "example.org")).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
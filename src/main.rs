@@ -1,50 +1,497 @@
 extern crate nibbler;
+#[macro_use]
 extern crate log;
+extern crate getopts;
+extern crate libc;
+extern crate time;
 
-use std::net::TcpListener;
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::ptr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::thread::spawn;
-use std::error::Error;
-use nibbler::smtp::{DefaultConnectionHandler, ConnectionHandler};
-use std::sync::mpsc::channel;
+use getopts::{Matches, Options};
+use nibbler::server::Server;
+use nibbler::shutdown::ShutdownHandle;
+use nibbler::config::{ServerConfig, ListenerConfig, ListenerRole, QueueConfig, QueueBackend,
+                      LocalConfig, MailboxFormat, UserSource};
+use nibbler::auth::Authenticator;
+use nibbler::auth_throttle::AuthThrottle;
+use nibbler::password_file::PasswordFile;
+use nibbler::received::return_path_header;
+use nibbler::payload::Payload;
+use nibbler::payload_handler::{PayloadHandler, Transport};
+use nibbler::mx::MxTransport;
+use nibbler::local::{LocalDelivery, LocalPart, LocalRouter, MailStore, UserDirectory};
+use nibbler::local::maildir::Maildir;
+use nibbler::local::mbox::Mbox;
+use nibbler::local::users::{PasswdFile, StaticUsers};
+#[cfg(feature = "sqlite")]
+use nibbler::local::sqlite::SqliteUsers;
+use nibbler::resolver::DnsResolver;
+use nibbler::sink::{MessageSink, SinkError};
+use nibbler::spool::{QueueStore, QueueSink, QueuedMessage, RecipientState, Spool, Scheduler};
+#[cfg(feature = "sqlite")]
+use nibbler::spool::sqlite::SqliteSpool;
+
+const COMMANDS: &'static str = "Commands:
+    serve           Run the server
+    check-config    Check the configuration file for serve and exit
+    catch DIR       Run the server and write every message to DIR
+    queue list      List queued messages and the status of their recipients
+    queue show ID   Print a queued message with its delivery status
+    queue flush [ID]
+                    Deliver all queued messages, or message ID, now
+    queue hold ID   Keep message ID from being delivered
+    queue release ID
+                    Deliver message ID again
+    queue delete ID Remove message ID from the queue";
 
 pub fn main() {
-    log::set_logger(|max_log_level| {
-        max_log_level.set(log::LogLevelFilter::Info);
-        Box::new(SimpleLogger)
-    })
-        .unwrap();
+    let args = env::args().collect::<Vec<_>>();
+    let mut options = Options::new();
+    options.optopt("c", "config", "read settings from FILE", "FILE");
+    options.optmulti("l",
+                     "listen",
+                     "listen for relay connections on ADDR instead of the configured \
+                      listeners",
+                     "ADDR");
+    options.optopt("", "log-level", "off, error, warn, info (default), debug or trace", "LEVEL");
+    options.optflag("h", "help", "print this help");
 
-    let (message_tx, message_rx) = channel();
+    let usage = options.usage(&format!("Usage: {} <command> [options]\n\n{}", args[0], COMMANDS));
+    let matches = match options.parse(&args[1..]) {
+        Ok(matches) => matches,
+        Err(error) => _fail(&format!("{}\n\n{}", error, usage)),
+    };
+    if matches.opt_present("help") {
+        println!("{}", usage);
+        return;
+    }
 
-    spawn(move || {
-        loop {
-            if let Ok(_) = message_rx.recv() {
-                println!("Got a message.");
+    let log_level = matches.opt_str("log-level").unwrap_or("info".to_string());
+    match log_level.parse() {
+        Ok(log_level) => _init_logger(log_level),
+        Err(_) => _fail(&format!("Invalid log level {}", log_level)),
+    }
+
+    // before any thread is started, so that they all inherit the mask
+    let signals = _block_shutdown_signals();
+
+    match matches.free.get(0).map(|command| &command[..]) {
+        Some("serve") if matches.free.len() == 1 => {
+            let config = _load_config(&matches);
+            if let Err(error) = config.validate_serve() {
+                _fail(&format!("{}", error));
             }
+            // only mail for local domains is accepted without AUTH
+            let relay_domains = config.local.as_ref().map_or(vec![], |local| local.domains.clone());
+            // validate_serve() made sure there is a queue
+            let queue = config.queue.clone().unwrap();
+            let store = match _open_queue(&queue) {
+                Ok(store) => store,
+                Err(error) => _fail(&format!("{}: {}", queue.directory.display(), error)),
+            };
+            let remote: Arc<Transport> = match config.relay.clone() {
+                Some(relay) => Arc::new(PayloadHandler::new(relay)),
+                None => {
+                    Arc::new(MxTransport::new(Arc::new(DnsResolver::system()),
+                                              &config.hostname))
+                }
+            };
+            let users = config.local.as_ref().map(|local| match _open_users(local) {
+                Ok(users) => users,
+                Err(error) => _fail(&format!("local.users_file: {}", error)),
+            });
+            let transport: Arc<Transport> = match (config.local.clone(), users.clone()) {
+                (Some(local), Some(users)) => {
+                    let store: Arc<MailStore> = match local.format {
+                        MailboxFormat::Maildir => {
+                            Arc::new(Maildir::new(local.directory)
+                                         .with_hostname(&config.hostname))
+                        }
+                        MailboxFormat::Mbox => Arc::new(Mbox::new(local.directory)),
+                    };
+                    let delivery = LocalDelivery::new(users.clone(), store);
+                    Arc::new(LocalRouter::new(local.domains, users, Arc::new(delivery), remote))
+                }
+                _ => remote,
+            };
+            let scheduler = Scheduler::new(store.clone(), transport, queue)
+                                .with_hostname(&config.hostname);
+            _serve(config,
+                   Arc::new(QueueSink::new(store)),
+                   Some(scheduler),
+                   users,
+                   Some(relay_domains),
+                   signals);
         }
-    });
+        Some("check-config") if matches.free.len() == 1 => {
+            if let Err(error) = _load_config(&matches).validate_serve() {
+                _fail(&format!("{}", error));
+            }
+            println!("Configuration OK");
+        }
+        Some("catch") if matches.free.len() == 2 => {
+            let directory = PathBuf::from(&matches.free[1]);
+            if let Err(error) = fs::create_dir_all(&directory) {
+                _fail(&format!("{}: {}", directory.display(), error));
+            }
+            _serve(_load_config(&matches),
+                   Arc::new(CatchSink { directory: directory }),
+                   None,
+                   None,
+                   None,
+                   signals);
+        }
+        Some("queue") if matches.free.len() >= 2 => {
+            let args = matches.free[2..].iter().map(|arg| &arg[..]).collect::<Vec<_>>();
+            _queue_command(&matches.free[1], &args, &_load_config(&matches), &usage);
+        }
+        _ => _fail(&usage),
+    }
+}
+
+fn _fail(message: &str) -> ! {
+    let _ = writeln!(&mut std::io::stderr(), "{}", message);
+    exit(1);
+}
+
+/// The configuration file given with `--config`, with `--listen` replacing
+/// the configured listeners.
+fn _load_config(matches: &Matches) -> ServerConfig {
+    let mut config = match matches.opt_str("config") {
+        Some(path) => {
+            match ServerConfig::load(&path) {
+                Ok(config) => config,
+                Err(error) => _fail(&format!("{}: {}", path, error)),
+            }
+        }
+        None => ServerConfig::default(),
+    };
+
+    let listen = matches.opt_strs("listen");
+    if !listen.is_empty() {
+        config.listeners = Vec::new();
+        for address in listen {
+            match address.parse::<SocketAddr>() {
+                Ok(address) => {
+                    config.listeners.push(ListenerConfig::new(address, ListenerRole::Relay))
+                }
+                Err(_) => _fail(&format!("--listen: invalid address {}", address)),
+            }
+        }
+    }
+
+    if let Err(error) = config.validate() {
+        _fail(&format!("{}", error));
+    }
+    config
+}
+
+fn _open_queue(queue: &QueueConfig) -> io::Result<Arc<QueueStore>> {
+    match queue.backend {
+        QueueBackend::Filesystem => Ok(Arc::new(try!(Spool::open(&queue.directory)))),
+        QueueBackend::Sqlite => _open_sqlite_queue(&queue.directory),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn _open_sqlite_queue(directory: &Path) -> io::Result<Arc<QueueStore>> {
+    try!(fs::create_dir_all(directory));
+    Ok(Arc::new(try!(SqliteSpool::open(directory.join("queue.sqlite")))))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn _open_sqlite_queue(_: &Path) -> io::Result<Arc<QueueStore>> {
+    // rejected when the configuration is validated
+    unreachable!()
+}
+
+fn _open_users(local: &LocalConfig) -> io::Result<Arc<UserDirectory>> {
+    let users_file = local.users_file.clone().unwrap_or_default();
+    match local.users {
+        UserSource::LocalPart => Ok(Arc::new(LocalPart)),
+        UserSource::Static => {
+            let mut users = StaticUsers::new();
+            for &(ref name, ref mailbox) in &local.mailboxes {
+                users = users.with_mailbox(name, mailbox);
+            }
+            for &(ref name, ref targets) in &local.aliases {
+                users = users.with_alias(name, targets);
+            }
+            Ok(Arc::new(users))
+        }
+        UserSource::Passwd => Ok(Arc::new(try!(PasswdFile::open(users_file)))),
+        UserSource::Sqlite => _open_sqlite_users(&users_file),
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn _open_sqlite_users(path: &Path) -> io::Result<Arc<UserDirectory>> {
+    Ok(Arc::new(try!(SqliteUsers::open(path))))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn _open_sqlite_users(_: &Path) -> io::Result<Arc<UserDirectory>> {
+    // rejected when the configuration is validated
+    unreachable!()
+}
+
+/// Runs `smtp queue <command> <args>` on the configured queue, which may be
+/// in use by a running server at the same time.
+fn _queue_command(command: &str, args: &[&str], config: &ServerConfig, usage: &str) {
+    let queue = match config.queue {
+        Some(ref queue) => queue,
+        None => _fail("No [queue] configured"),
+    };
+    let store = match _open_queue(queue) {
+        Ok(store) => store,
+        Err(error) => _fail(&format!("{}: {}", queue.directory.display(), error)),
+    };
+    let id = args.get(0).cloned().unwrap_or("");
+    let result = match (command, args.len()) {
+        ("list", 0) => _queue_list(&*store),
+        ("show", 1) => _queue_show(&*store, id),
+        ("flush", 0) => _queue_flush(&*store, None),
+        ("flush", 1) => _queue_flush(&*store, Some(id)),
+        ("hold", 1) => store.set_held(id, true).map(|_| println!("{}: held", id)),
+        ("release", 1) => store.set_held(id, false).map(|_| println!("{}: released", id)),
+        ("delete", 1) => store.remove(id).map(|_| println!("{}: deleted", id)),
+        _ => _fail(usage),
+    };
+    match result {
+        Ok(()) => (),
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
+            _fail(&format!("{}: not in the queue", id))
+        }
+        Err(error) => _fail(&format!("{}", error)),
+    }
+}
 
-    match TcpListener::bind(("127.0.0.1", 25255)) {
-        Ok(listener) => {
-            for acceptor in listener.incoming() {
-                let handler = DefaultConnectionHandler::new(message_tx.clone());
-                match acceptor {
-                    Ok(mut conn) => {
-                        spawn(move || handler.handle_connection(&mut conn));
-                    }
-                    _ => (),
+fn _queue_list(store: &QueueStore) -> io::Result<()> {
+    let messages = try!(store.messages());
+    for message in messages.iter() {
+        let schedule = if message.held {
+            "held".to_string()
+        } else {
+            format!("next attempt {}", _format_time(message.next_attempt))
+        };
+        println!("{}  {}  <{}>  {} attempts, {}",
+                 message.id,
+                 _format_time(message.created_at),
+                 message.sender.as_ref().map_or(String::new(), |s| s.to_string()),
+                 message.attempts,
+                 schedule);
+        if let Some(ref error) = message.last_error {
+            println!("    ({})", error);
+        }
+        _print_recipients(message);
+    }
+    println!("-- {} messages", messages.len());
+    Ok(())
+}
+
+fn _queue_show(store: &QueueStore, id: &str) -> io::Result<()> {
+    let message = try!(store.get(id));
+    let data = try!(store.data(id));
+    println!("Id: {}", message.id);
+    println!("Sender: <{}>",
+             message.sender.as_ref().map_or(String::new(), |s| s.to_string()));
+    println!("Created: {}", _format_time(message.created_at));
+    println!("Size: {} bytes", data.len());
+    println!("Attempts: {}", message.attempts);
+    println!("Next attempt: {}{}",
+             _format_time(message.next_attempt),
+             if message.held { " (held)" } else { "" });
+    if let Some(ref error) = message.last_error {
+        println!("Last error: {}", error);
+    }
+    _print_recipients(&message);
+    println!("");
+    try!(io::stdout().write_all(&data));
+    Ok(())
+}
+
+/// Makes all messages that are not held, or just message `id`, due now.
+fn _queue_flush(store: &QueueStore, id: Option<&str>) -> io::Result<()> {
+    let now = SystemTime::now();
+    match id {
+        Some(id) => {
+            try!(store.reschedule(id, now));
+            println!("{}: due now{}",
+                     id,
+                     if try!(store.get(id)).held { ", but held" } else { "" });
+        }
+        None => {
+            let mut flushed = 0;
+            for message in try!(store.messages()).iter().filter(|m| !m.held) {
+                match store.reschedule(&message.id, now) {
+                    Ok(()) => flushed += 1,
+                    // delivered in the meantime
+                    Err(ref error) if error.kind() == io::ErrorKind::NotFound => (),
+                    Err(error) => return Err(error),
                 }
             }
+            println!("{} messages due now", flushed);
         }
-        Err(error) => panic!("Could not bind server to socket: {}", error.description()),
+    }
+    Ok(())
+}
+
+fn _print_recipients(message: &QueuedMessage) {
+    for recipient in message.recipients.iter() {
+        let state = match recipient.state {
+            RecipientState::Pending => "pending",
+            RecipientState::Delivered => "delivered",
+            RecipientState::Failed => "failed",
+        };
+        println!("    {:<9}  {}{}",
+                 state,
+                 recipient.address,
+                 recipient.last_reply.as_ref().map_or(String::new(), |r| format!("  {}", r)));
     }
 }
 
-struct SimpleLogger;
+fn _format_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let tm = time::at_utc(time::Timespec::new(seconds as i64, 0));
+    time::strftime("%Y-%m-%d %H:%M:%S UTC", &tm).unwrap()
+}
+
+/// Returns once the server has shut down and dropped its sink, and the
+/// scheduler, if any, has finished the delivery it was making.
+fn _serve(config: ServerConfig,
+          sink: Arc<MessageSink>,
+          scheduler: Option<Scheduler>,
+          users: Option<Arc<UserDirectory>>,
+          relay_domains: Option<Vec<String>>,
+          signals: libc::sigset_t) {
+    let authenticator = match config.auth.password_file {
+        Some(ref path) => {
+            match PasswordFile::open(path) {
+                Ok(passwords) => {
+                    let throttle = AuthThrottle::new(config.auth.throttle.clone());
+                    Some(Arc::new(Authenticator::new(Arc::new(passwords))
+                                      .with_throttle(Arc::new(throttle))))
+                }
+                Err(error) => _fail(&format!("auth.password_file: {}", error)),
+            }
+        }
+        None => None,
+    };
+
+    let mut server = match Server::new(config, sink) {
+        Ok(server) => server,
+        Err(error) => _fail(&format!("tls: {}", error)),
+    };
+    if let Some(authenticator) = authenticator {
+        server = server.with_authenticator(authenticator);
+    }
+    if let Some(users) = users {
+        server = server.with_user_directory(users);
+    }
+    if let Some(domains) = relay_domains {
+        server = server.with_relay_domains(domains);
+    }
+    _shut_down_on_signal(signals, server.shutdown_handle());
+    let scheduler = scheduler.map(|scheduler| {
+        let shutdown = server.shutdown_handle();
+        spawn(move || scheduler.run(&shutdown))
+    });
+    if let Err(error) = server.run() {
+        _fail(&format!("Could not start server: {}", error));
+    }
+    if let Some(scheduler) = scheduler {
+        let _ = scheduler.join();
+    }
+}
+
+/// Blocks SIGINT and SIGTERM, to be picked up with `sigwait` instead.
+fn _block_shutdown_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        signals
+    }
+}
+
+/// The first signal starts a graceful shutdown, a second one exits at once.
+fn _shut_down_on_signal(signals: libc::sigset_t, shutdown: ShutdownHandle) {
+    spawn(move || {
+        let mut signal = 0;
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        info!("Got signal {}, shutting down", signal);
+        shutdown.shutdown();
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        _fail(&format!("Got signal {} again, exiting", signal));
+    });
+}
+
+/// Writes each message to `<queue id>.eml` in `directory`, with a
+/// Return-Path header in front. Messages are synced to disk before they
+/// are acknowledged.
+struct CatchSink {
+    directory: PathBuf,
+}
+
+impl CatchSink {
+    fn _write(&self, payload: &Payload, path: &Path) -> io::Result<()> {
+        let tmp_path = self.directory.join(format!(".{}.tmp", payload.envelope.queue_id));
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_all(return_path_header(payload.sender.as_ref()).as_bytes()));
+        try!(file.write_all(&payload.data));
+        try!(file.sync_all());
+        try!(fs::rename(&tmp_path, path));
+        // makes the rename itself durable
+        File::open(&self.directory).and_then(|directory| directory.sync_all())
+    }
+}
+
+impl MessageSink for CatchSink {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        let path = self.directory.join(format!("{}.eml", payload.envelope.queue_id));
+        match self._write(&payload, &path) {
+            Ok(()) => {
+                info!("{}: written to {}", payload.envelope.queue_id, path.display());
+                Ok(())
+            }
+            Err(error) => {
+                error!("{}: could not write {}: {}",
+                       payload.envelope.queue_id,
+                       path.display(),
+                       error);
+                Err(SinkError::Temporary("Could not store message".to_string()))
+            }
+        }
+    }
+}
+
+fn _init_logger(level: log::LogLevelFilter) {
+    log::set_logger(|max_log_level| {
+        max_log_level.set(level);
+        Box::new(SimpleLogger { level: level })
+    })
+        .unwrap();
+}
+
+struct SimpleLogger {
+    level: log::LogLevelFilter,
+}
 
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &log::LogMetadata) -> bool {
-        metadata.level() <= log::LogLevel::Info
+        metadata.level() <= self.level
     }
 
     fn log(&self, record: &log::LogRecord) {
@@ -0,0 +1,326 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+use address::Address;
+use client::{ClientError, Reply, SmtpClient};
+use payload::Payload;
+use payload_handler::{send, RecipientStatus, RelayError, Transport};
+use resolver::{ResolveError, Resolver};
+
+/// Delivers each recipient straight to the mail exchangers of its domain
+/// (RFC 5321 section 5), for when there is no smarthost.
+pub struct MxTransport {
+    resolver: Arc<Resolver>,
+    hello_name: String,
+    port: u16,
+    connect_timeout: Duration,
+    timeout: Duration,
+}
+
+impl MxTransport {
+    pub fn new(resolver: Arc<Resolver>, hello_name: &str) -> MxTransport {
+        MxTransport {
+            resolver: resolver,
+            hello_name: hello_name.to_lowercase(),
+            port: 25,
+            connect_timeout: Duration::from_secs(30),
+            // see PayloadHandler::new
+            timeout: Duration::from_secs(10 * 60),
+        }
+    }
+
+    /// Connects to another port than 25, e.g. a stand-in server in tests.
+    pub fn with_port(mut self, port: u16) -> MxTransport {
+        self.port = port;
+        self
+    }
+
+    /// The hosts to try for `domain`, best first, or what becomes of its
+    /// recipients if there are none.
+    pub fn hosts(&self, domain: &str) -> Result<Vec<String>, RecipientStatus> {
+        if domain.starts_with('[') {
+            return Ok(vec![domain.to_string()]);
+        }
+        let mut records = match self.resolver.mx(domain) {
+            Ok(records) => records,
+            Err(ResolveError::NoSuchDomain) => {
+                return Err(_failed(550, &format!("5.1.2 Domain {} does not exist", domain)))
+            }
+            Err(ResolveError::Temporary(err)) => {
+                return Err(RecipientStatus::Deferred(_reply(451,
+                                                            &format!("4.4.3 MX lookup for {} \
+                                                                      failed: {}",
+                                                                     domain,
+                                                                     err))))
+            }
+        };
+        if records.is_empty() {
+            // the domain is its own exchanger
+            return Ok(vec![domain.to_string()]);
+        }
+        if records.iter().all(|record| record.is_null()) {
+            return Err(_failed(556, &format!("5.1.10 Domain {} does not accept mail", domain)));
+        }
+        records.retain(|record| !record.is_null());
+        // equal preferences share the load
+        thread_rng().shuffle(&mut records);
+        records.sort_by_key(|record| record.preference);
+        let own = records.iter()
+                         .find(|record| {
+                             record.exchange.trim_end_matches('.').to_lowercase() ==
+                             self.hello_name
+                         })
+                         .map(|record| record.preference);
+        if let Some(own) = own {
+            records.retain(|record| record.preference < own);
+            if records.is_empty() {
+                return Err(_failed(554, &format!("5.4.6 Mail for {} loops back to me", domain)));
+            }
+        }
+        Ok(records.into_iter().map(|record| record.exchange).collect())
+    }
+
+    /// Delivers `payload`, whose recipients share one domain.
+    fn _deliver_domain(&self, domain: &str, payload: &Payload) -> Vec<RecipientStatus> {
+        let count = payload.recipients.len();
+        let hosts = match self.hosts(domain) {
+            Ok(hosts) => hosts,
+            Err(status) => return vec![status; count],
+        };
+        let mut last_error = String::new();
+        for host in hosts.iter() {
+            let addresses = match self._addresses(host) {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    last_error = format!("{}: {}", host, err);
+                    continue;
+                }
+            };
+            if addresses.is_empty() {
+                last_error = format!("{}: no addresses", host);
+            }
+            for &address in addresses.iter() {
+                let result = match self._try(host, address, payload, true) {
+                    Err(RelayError::Client(ClientError::Tls(err))) => {
+                        info!("{}: TLS failed ({}), trying without", host, err);
+                        self._try(host, address, payload, false)
+                    }
+                    result => result,
+                };
+                match result {
+                    Ok(statuses) => return statuses.into_iter().map(|(_, s)| s).collect(),
+                    Err(ref err) if err.is_transient() => {
+                        info!("{} ({}): {}", host, address, err);
+                        last_error = format!("{} ({}): {}", host, address, err);
+                    }
+                    Err(RelayError::SenderRejected(reply)) |
+                    Err(RelayError::Client(ClientError::Rejected(reply))) => {
+                        return vec![RecipientStatus::Failed(reply); count]
+                    }
+                    Err(err) => {
+                        return vec![_failed(550, &format!("5.0.0 {}: {}", host, err)); count]
+                    }
+                }
+            }
+        }
+        vec![RecipientStatus::Deferred(_reply(451, &format!("4.4.1 {}", last_error))); count]
+    }
+
+    fn _addresses(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        if host.starts_with('[') && host.ends_with(']') {
+            // a domain literal (RFC 5321 section 4.1.3)
+            let literal = &host[1..host.len() - 1];
+            let literal = if literal.len() >= 5 && literal[..5].eq_ignore_ascii_case("IPv6:") {
+                &literal[5..]
+            } else {
+                literal
+            };
+            return literal.parse().map(|address| vec![address]).map_err(|_| {
+                ResolveError::NoSuchDomain
+            });
+        }
+        self.resolver.addresses(host)
+    }
+
+    /// One session with `host` at `address`, with STARTTLS when the host
+    /// offers it and `tls` is set.
+    fn _try(&self,
+            host: &str,
+            address: IpAddr,
+            payload: &Payload,
+            tls: bool)
+            -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        let mut client = try!(SmtpClient::connect_to(&SocketAddr::new(address, self.port),
+                                                     host.trim_end_matches('.'),
+                                                     self.connect_timeout,
+                                                     self.timeout));
+        try!(client.hello(&self.hello_name));
+        if tls && client.has_extension("STARTTLS") {
+            client = try!(client.starttls());
+            try!(client.hello(&self.hello_name));
+        }
+        send(client, payload)
+    }
+}
+
+impl Transport for MxTransport {
+    fn deliver(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        let mut domains: Vec<(String, Vec<usize>)> = Vec::new();
+        for (i, recipient) in payload.recipients.iter().enumerate() {
+            let domain = recipient.domain.to_lowercase();
+            match domains.iter().position(|&(ref d, _)| *d == domain) {
+                Some(position) => domains[position].1.push(i),
+                None => domains.push((domain, vec![i])),
+            }
+        }
+
+        let mut statuses = vec![None; payload.recipients.len()];
+        for &(ref domain, ref indices) in domains.iter() {
+            let part = payload.subset(indices);
+            for (&i, status) in indices.iter().zip(self._deliver_domain(domain, &part)) {
+                statuses[i] = Some(status);
+            }
+        }
+        Ok(payload.recipients
+                  .iter()
+                  .cloned()
+                  .zip(statuses.into_iter().map(|status| status.unwrap()))
+                  .collect())
+    }
+}
+
+fn _reply(code: u16, line: &str) -> Reply {
+    Reply {
+        code: code,
+        lines: vec![line.to_string()],
+    }
+}
+
+fn _failed(code: u16, line: &str) -> RecipientStatus {
+    RecipientStatus::Failed(_reply(code, line))
+}
+
+pub mod tests {
+    use std::sync::Arc;
+    use address::Address;
+    use mx::MxTransport;
+    use payload::Payload;
+    use payload_handler::{RecipientStatus, Transport};
+    use payload_handler::tests::stand_in;
+    use resolver::StaticResolver;
+
+    fn transport(resolver: StaticResolver, port: u16) -> MxTransport {
+        MxTransport::new(Arc::new(resolver), "mx.example.org").with_port(port)
+    }
+
+    fn code(status: &RecipientStatus) -> u16 {
+        match *status {
+            RecipientStatus::Delivered |
+            RecipientStatus::Relayed |
+            RecipientStatus::RelayedWithDsn => 250,
+            RecipientStatus::Deferred(ref reply) |
+            RecipientStatus::Failed(ref reply) => reply.code,
+        }
+    }
+
+    #[test]
+    fn delivers_to_the_best_reachable_exchanger() {
+        let (port, server) = stand_in(|line| {
+            match line {
+                "RCPT TO:<gone@Example.NET>" => "550 5.1.1 No such user",
+                "QUIT" => "221 Bye",
+                _ => "250 OK",
+            }
+        });
+        // nothing listens on 127.0.0.2
+        let resolver = StaticResolver::new()
+                           .with_mx("example.net", 20, "mx2.example.net")
+                           .with_mx("example.net", 10, "mx1.example.net")
+                           .with_address("mx1.example.net", "127.0.0.2".parse().unwrap())
+                           .with_address("mx2.example.net", "127.0.0.1".parse().unwrap())
+                           .with_mx("example.com", 0, ".")
+                           .with_failure("example.edu");
+        let mut payload = Payload::new();
+        payload.sender = Some(Address::new("matt", "example.org"));
+        payload.add_recipient(Address::new("marie", "example.net"));
+        payload.add_recipient(Address::new("nobody", "example.com"));
+        payload.add_recipient(Address::new("nobody", "example.invalid"));
+        payload.add_recipient(Address::new("later", "example.edu"));
+        payload.add_recipient(Address::new("gone", "Example.NET"));
+        payload.data = b"Subject: hi\r\n\r\nHello\r\n".to_vec();
+
+        let statuses = transport(resolver, port).deliver(&payload).unwrap();
+        assert_eq!(payload.recipients,
+                   statuses.iter().map(|&(ref a, _)| a.clone()).collect::<Vec<_>>());
+        assert_eq!(vec![250, 556, 550, 451, 550],
+                   statuses.iter().map(|&(_, ref s)| code(s)).collect::<Vec<_>>());
+        let transcript = server.join().unwrap();
+        assert_eq!(vec!["EHLO mx.example.org",
+                        "MAIL FROM:<matt@example.org>",
+                        "RCPT TO:<marie@example.net>",
+                        "RCPT TO:<gone@Example.NET>",
+                        "DATA",
+                        "Subject: hi",
+                        "",
+                        "Hello",
+                        ".",
+                        "QUIT"],
+                   transcript);
+    }
+
+    #[test]
+    fn falls_back_to_the_domain_itself() {
+        let (port, server) = stand_in(|line| if line == "QUIT" { "221 Bye" } else { "250 OK" });
+        let resolver = StaticResolver::new()
+                           .with_address("example.net", "127.0.0.1".parse().unwrap());
+        let mut payload = Payload::new();
+        payload.add_recipient(Address::new("marie", "example.net"));
+        payload.data = b"\r\n".to_vec();
+        let statuses = transport(resolver, port).deliver(&payload).unwrap();
+        assert_eq!(RecipientStatus::Relayed, statuses[0].1);
+        assert!(server.join().unwrap().contains(&"RCPT TO:<marie@example.net>".to_string()));
+    }
+
+    #[test]
+    fn delivers_to_domain_literals() {
+        let (port, server) = stand_in(|line| if line == "QUIT" { "221 Bye" } else { "250 OK" });
+        let mut payload = Payload::new();
+        payload.add_recipient(Address::new("postmaster", "[127.0.0.1]"));
+        payload.data = b"\r\n".to_vec();
+        let statuses = transport(StaticResolver::new(), port).deliver(&payload).unwrap();
+        assert_eq!(RecipientStatus::Relayed, statuses[0].1);
+        assert!(server.join().unwrap().contains(&"MAIL FROM:<>".to_string()));
+    }
+
+    #[test]
+    fn defers_when_no_exchanger_answers() {
+        let resolver = StaticResolver::new()
+                           .with_mx("example.net", 10, "mx1.example.net")
+                           .with_address("mx1.example.net", "127.0.0.2".parse().unwrap())
+                           .with_mx("example.com", 10, "mx.example.com");
+        let mut payload = Payload::new();
+        payload.add_recipient(Address::new("marie", "example.net"));
+        payload.add_recipient(Address::new("marie", "example.com"));
+        let statuses = transport(resolver, 1).deliver(&payload).unwrap();
+        assert_eq!(451, code(&statuses[0].1));
+        assert_eq!(451, code(&statuses[1].1));
+    }
+
+    #[test]
+    fn orders_exchangers_and_avoids_loops() {
+        let resolver = StaticResolver::new()
+                           .with_mx("example.net", 30, "mx.example.org.")
+                           .with_mx("example.net", 20, "backup.example.net")
+                           .with_mx("example.net", 10, "mx.example.net")
+                           .with_mx("example.com", 10, "MX.example.org")
+                           .with_mx("example.edu", 10, "mx.example.edu")
+                           .with_mx("example.edu", 20, ".");
+        let transport = transport(resolver, 25);
+        assert_eq!(Ok(vec!["mx.example.net".to_string(), "backup.example.net".to_string()]),
+                   transport.hosts("example.net"));
+        assert_eq!(554, code(&transport.hosts("example.com").unwrap_err()));
+        assert_eq!(Ok(vec!["mx.example.edu".to_string()]),
+                   transport.hosts("example.edu"));
+    }
+}
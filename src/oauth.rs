@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use rustc_serialize::json::Json;
+use auth::{SaslMechanism, SaslStep};
+use scram::decode_saslname;
+
+/// Why a bearer token was refused. Sent back to the client as the JSON error
+/// challenge of RFC 7628 section 3.2.2.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TokenError {
+    /// e.g. `invalid_token` or `insufficient_scope`
+    pub status: String,
+    pub scope: Option<String>,
+    pub openid_configuration: Option<String>,
+}
+
+impl TokenError {
+    pub fn new(status: &str) -> TokenError {
+        TokenError {
+            status: status.to_string(),
+            scope: None,
+            openid_configuration: None,
+        }
+    }
+
+    fn _to_json(&self, status: &str, schemes: Option<&str>) -> Vec<u8> {
+        let mut object = BTreeMap::new();
+        object.insert("status".to_string(), Json::String(status.to_string()));
+        if let Some(schemes) = schemes {
+            object.insert("schemes".to_string(), Json::String(schemes.to_string()));
+        }
+        if let Some(ref scope) = self.scope {
+            object.insert("scope".to_string(), Json::String(scope.clone()));
+        }
+        if let Some(ref openid_configuration) = self.openid_configuration {
+            object.insert("openid-configuration".to_string(),
+                          Json::String(openid_configuration.clone()));
+        }
+        Json::Object(object).to_string().into_bytes()
+    }
+}
+
+pub trait TokenValidator: Send + Sync {
+    /// Returns the identity the bearer token was issued to.
+    fn validate(&self, token: &str) -> Result<String, TokenError>;
+}
+
+// Checks the token and that it belongs to the user the client claims to be.
+fn _authenticate(validator: &TokenValidator,
+                 user: Option<&str>,
+                 token: &str)
+                 -> Result<String, TokenError> {
+    let identity = try!(validator.validate(token));
+    match user {
+        Some(user) if user != identity => Err(TokenError::new("invalid_token")),
+        _ => Ok(identity),
+    }
+}
+
+// "auth=Bearer <token>", the scheme is case-insensitive (RFC 6750)
+fn _bearer_token(kvpair: &str) -> Option<&str> {
+    if !kvpair.starts_with("auth=") {
+        return None;
+    }
+    let credentials = &kvpair[5..];
+    match credentials.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("bearer ") && credentials.len() > 7 => {
+            Some(&credentials[7..])
+        }
+        _ => None,
+    }
+}
+
+/// OAUTHBEARER (RFC 7628).
+pub struct OAuthBearer {
+    validator: Arc<TokenValidator>,
+}
+
+impl OAuthBearer {
+    pub fn new(validator: Arc<TokenValidator>) -> OAuthBearer {
+        OAuthBearer { validator: validator }
+    }
+
+    fn _initial_response(&self, response: &[u8]) -> SaslStep {
+        let response = match String::from_utf8(response.to_vec()) {
+            Ok(response) => response,
+            Err(_) => return SaslStep::Failure,
+        };
+
+        // gs2-header, channel binding is not supported
+        let parts = response.splitn(3, ',').collect::<Vec<_>>();
+        if parts.len() != 3 || (parts[0] != "n" && parts[0] != "y") {
+            return SaslStep::Failure;
+        }
+        let user = match parts[1] {
+            "" => None,
+            authzid if authzid.starts_with("a=") => {
+                match decode_saslname(&authzid[2..]) {
+                    Some(user) => Some(user),
+                    None => return SaslStep::Failure,
+                }
+            }
+            _ => return SaslStep::Failure,
+        };
+
+        if !parts[2].starts_with("\x01") || !parts[2].ends_with("\x01\x01") {
+            return SaslStep::Failure;
+        }
+        let token = match parts[2].split('\x01').filter_map(_bearer_token).next() {
+            Some(token) => token,
+            None => return SaslStep::Failure,
+        };
+
+        match _authenticate(&*self.validator, user.as_ref().map(|u| &u[..]), token) {
+            Ok(identity) => SaslStep::Success(identity, None),
+            Err(error) => SaslStep::ErrorChallenge(error._to_json(&error.status, None)),
+        }
+    }
+}
+
+impl SaslMechanism for OAuthBearer {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        match response {
+            None => SaslStep::Challenge(Vec::new()),
+            Some(response) => self._initial_response(response),
+        }
+    }
+}
+
+/// XOAUTH2 as used by Google and Microsoft. Predates OAUTHBEARER and reports
+/// errors with HTTP status codes.
+pub struct XOAuth2 {
+    validator: Arc<TokenValidator>,
+}
+
+impl XOAuth2 {
+    pub fn new(validator: Arc<TokenValidator>) -> XOAuth2 {
+        XOAuth2 { validator: validator }
+    }
+
+    fn _initial_response(&self, response: &[u8]) -> SaslStep {
+        let response = match String::from_utf8(response.to_vec()) {
+            Ok(response) => response,
+            Err(_) => return SaslStep::Failure,
+        };
+
+        // "user=" user ^A "auth=Bearer " token ^A ^A
+        let fields = response.split('\x01').collect::<Vec<_>>();
+        if fields.len() != 4 || !fields[0].starts_with("user=") || !fields[2].is_empty() ||
+           !fields[3].is_empty() {
+            return SaslStep::Failure;
+        }
+        let token = match _bearer_token(fields[1]) {
+            Some(token) => token,
+            None => return SaslStep::Failure,
+        };
+
+        match _authenticate(&*self.validator, Some(&fields[0][5..]), token) {
+            Ok(identity) => SaslStep::Success(identity, None),
+            Err(error) => {
+                let status = if error.status == "insufficient_scope" { "403" } else { "401" };
+                SaslStep::ErrorChallenge(error._to_json(status, Some("bearer")))
+            }
+        }
+    }
+}
+
+impl SaslMechanism for XOAuth2 {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        match response {
+            None => SaslStep::Challenge(Vec::new()),
+            Some(response) => self._initial_response(response),
+        }
+    }
+}
+
+pub mod tests {
+    use std::sync::Arc;
+    use auth::{Authenticator, SaslMechanism, SaslStep, StaticCredentialStore};
+    use oauth::{OAuthBearer, XOAuth2, TokenError, TokenValidator};
+    use session::{SessionContext, TlsInfo};
+
+    struct StaticValidator;
+
+    impl TokenValidator for StaticValidator {
+        fn validate(&self, token: &str) -> Result<String, TokenError> {
+            if token == "vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==" {
+                Ok("user@example.com".to_string())
+            } else {
+                let mut error = TokenError::new("invalid_token");
+                error.scope = Some("example_scope".to_string());
+                Err(error)
+            }
+        }
+    }
+
+    #[test]
+    fn offers_tokens_only_over_tls() {
+        let authenticator = Authenticator::new(Arc::new(StaticCredentialStore::new()))
+                                .with_token_validator(Arc::new(StaticValidator));
+        let mut session = SessionContext::new();
+        for mechanism in &["OAUTHBEARER", "XOAUTH2"] {
+            assert!(!authenticator.mechanisms(&session).contains(mechanism));
+            assert!(authenticator.start(mechanism, "localhost", &session).is_none());
+        }
+        session.tls = Some(TlsInfo::new());
+        for mechanism in &["OAUTHBEARER", "XOAUTH2"] {
+            assert!(authenticator.mechanisms(&session).contains(mechanism));
+            assert!(authenticator.start(mechanism, "localhost", &session).is_some());
+        }
+    }
+
+    #[test]
+    fn oauthbearer_accepts_rfc_example() {
+        // RFC 7628 section 4.1
+        let mut mechanism = OAuthBearer::new(Arc::new(StaticValidator));
+        let response = "n,a=user@example.com,\x01host=server.example.com\x01port=143\x01auth=Bearer \
+                        vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01";
+        match mechanism.step(Some(response.as_bytes())) {
+            SaslStep::Success(identity, None) => assert_eq!("user@example.com", identity),
+            _ => panic!("expected success"),
+        }
+    }
+
+    #[test]
+    fn oauthbearer_sends_error_challenge() {
+        let mut mechanism = OAuthBearer::new(Arc::new(StaticValidator));
+        let response = "n,,\x01auth=Bearer expired\x01\x01";
+        match mechanism.step(Some(response.as_bytes())) {
+            SaslStep::ErrorChallenge(error) => {
+                assert_eq!(&b"{\"scope\":\"example_scope\",\"status\":\"invalid_token\"}"[..],
+                           &error[..])
+            }
+            _ => panic!("expected error challenge"),
+        }
+    }
+
+    #[test]
+    fn xoauth2_rejects_token_of_other_user() {
+        let mut mechanism = XOAuth2::new(Arc::new(StaticValidator));
+        let response = "user=someone@example.com\x01auth=Bearer \
+                        vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01";
+        match mechanism.step(Some(response.as_bytes())) {
+            SaslStep::ErrorChallenge(error) => {
+                assert_eq!(&b"{\"schemes\":\"bearer\",\"status\":\"401\"}"[..], &error[..])
+            }
+            _ => panic!("expected error challenge"),
+        }
+    }
+}
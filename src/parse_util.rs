@@ -1,5 +1,5 @@
 
-use std::io::Read;
+use std::io::{ErrorKind, Read};
 use data::ParseError;
 
 // RFC 5321 Section 2.3.8. Lines
@@ -134,7 +134,9 @@ pub fn read_line_bytes(stream: &mut Read) -> Result<Vec<u8>, ParseError> {
                     s.push(buf[0]);
                 }
             }
-            Err(_) => return Err(ParseError::InvalidLineEnding),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock ||
+                            err.kind() == ErrorKind::TimedOut => return Err(ParseError::Timeout),
+            Err(_) => return Err(ParseError::UnexpectedEndOfInput),
         }
     }
 }
@@ -1,7 +1,7 @@
 use std::io::Read;
 
 use parse_util::*;
-use data::{Command, ParseError};
+use data::{Command, ParseError, Parameters};
 use address::Address;
 
 pub fn read_command(stream: &mut Read) -> Result<Command, ParseError> {
@@ -34,30 +34,8 @@ pub fn parse_command(command: &[u8]) -> Result<Command, ParseError> {
             }
 
             if line.match_next_str_ignore_case("FROM:") {
-                line.pop_while(|b: u8| b == (' ' as u8));
-                let addr = if line.match_next_str_ignore_case("<") {
-                    line.pop_while(|b| b == ' ' as u8);
-                    let addr = line.pop_while(|b: u8| b != '>' as u8);
-                    if line.is_at_end() {
-                        return Err(ParseError::SyntaxError("Invalid MAIL command: \
-                                                                    Missing >"));
-                    }
-                    line.match_next_str_ignore_case(">");
-                    addr
-                } else {
-                    let addr = line.pop_while(|b: u8| b != CR && b != LF && b != (' ' as u8));
-                    addr
-                };
-                if line.match_next_str_ignore_case("\r\n") && line.is_at_end() {
-                    let validating_address = _parse_address(addr);
-                    if !validating_address.is_ok() {
-                        return Err(ParseError::SyntaxError("Address is not a valid email address"));
-                    }
-                    return Ok(Command::MAIL_FROM(validating_address.unwrap()));
-                } else {
-                    return Err(ParseError::SyntaxError("Invalid trailing characters on MAIL \
-                                                        command"));
-                }
+                let (address, parameters) = try!(_read_address(&mut line));
+                return Ok(Command::MAIL_FROM(address, parameters));
             } else {
                 return Err(ParseError::SyntaxError("Invalid MAIL command"));
             }
@@ -78,8 +56,12 @@ pub fn parse_command(command: &[u8]) -> Result<Command, ParseError> {
         }
         Some('r') => {
             if line.match_next_bytes_ignore_case(b"CPT TO:") {
-                let email_address = try!(_read_address(&mut line));
-                return Ok(Command::RCPT_TO(email_address));
+                let (email_address, parameters) = try!(_read_address(&mut line));
+                match email_address {
+                    Some(email_address) => return Ok(Command::RCPT_TO(email_address, parameters)),
+                    None => return Err(ParseError::SyntaxError("Invalid RCPT command: Empty \
+                                                                address")),
+                }
             } else {
                 return Err(ParseError::MalformedCommand("Expected RCPT TO"));
             }
@@ -98,11 +80,40 @@ pub fn parse_command(command: &[u8]) -> Result<Command, ParseError> {
                 return Err(ParseError::MalformedCommand("Expected QUIT"));
             }
         }
+        Some('a') => {
+            if line.match_next_bytes_ignore_case(b"UTH ") {
+                let args = try!(line.read_line());
+                let mut args = args.split(' ');
+                let mechanism = match args.next() {
+                    Some(mechanism) if mechanism.len() > 0 => mechanism.to_string(),
+                    _ => {
+                        return Err(ParseError::SyntaxError("Invalid AUTH command: Missing \
+                                                            mechanism"))
+                    }
+                };
+                let initial_response = args.next().map(|r| r.to_string());
+                if args.next().is_some() {
+                    return Err(ParseError::SyntaxError("Invalid trailing characters on AUTH \
+                                                        command"));
+                }
+                return Ok(Command::AUTH(mechanism, initial_response));
+            } else {
+                return Err(ParseError::MalformedCommand("Expected AUTH"));
+            }
+        }
+        Some('s') => {
+            if line.match_next_bytes_ignore_case(b"TARTTLS\r\n") {
+                return Ok(Command::STARTTLS);
+            } else {
+                return Err(ParseError::MalformedCommand("Expected STARTTLS"));
+            }
+        }
         _ => return Err(ParseError::MalformedCommand("Unknown command")),
     }
 }
 
-fn _read_address(line: &mut SliceScanner) -> Result<Address, ParseError> {
+/// The address is `None` for `<>`.
+fn _read_address(line: &mut SliceScanner) -> Result<(Option<Address>, Parameters), ParseError> {
     line.pop_while(|b: u8| b == (' ' as u8));
     let addr = if line.match_next_str_ignore_case("<") {
         line.pop_while(|b| b == ' ' as u8);
@@ -116,17 +127,44 @@ fn _read_address(line: &mut SliceScanner) -> Result<Address, ParseError> {
         let addr = line.pop_while(|b: u8| b != CR && b != LF && b != (' ' as u8));
         addr
     };
+    let parameters = try!(_read_parameters(line));
     if line.match_next_str_ignore_case("\r\n") && line.is_at_end() {
+        if addr.is_empty() {
+            return Ok((None, parameters));
+        }
         let validating_address = _parse_address(addr);
         if !validating_address.is_ok() {
             return Err(ParseError::SyntaxError("Address is not a valid email address"));
         }
-        return Ok(validating_address.unwrap());
+        return Ok((Some(validating_address.unwrap()), parameters));
     } else {
         return Err(ParseError::SyntaxError("Invalid trailing characters on MAIL command"));
     }
 }
 
+// esmtp-param *(SP esmtp-param), esmtp-param = esmtp-keyword ["=" esmtp-value]
+fn _read_parameters(line: &mut SliceScanner) -> Result<Parameters, ParseError> {
+    let mut parameters = Vec::new();
+    while line.pop_while(is_space_byte).len() > 0 {
+        let parameter = line.pop_while(|b: u8| b != CR && b != LF && b != (' ' as u8));
+        if parameter.len() == 0 {
+            continue;
+        }
+        let parameter = match String::from_utf8(parameter) {
+            Ok(parameter) => parameter,
+            Err(_) => return Err(ParseError::SyntaxError("Parameter is not valid ASCII string")),
+        };
+        let mut parts = parameter.splitn(2, '=');
+        let keyword = parts.next().unwrap_or("").to_uppercase();
+        let value = parts.next().map(|v| v.to_string());
+        if keyword.len() == 0 || value.as_ref().map_or(false, |v| v.len() == 0) {
+            return Err(ParseError::SyntaxError("Invalid ESMTP parameter"));
+        }
+        parameters.push((keyword, value));
+    }
+    Ok(Parameters(parameters))
+}
+
 fn _parse_address(addr: Vec<u8>) -> Result<Address, ParseError> {
     let address_string_result = String::from_utf8(addr);
     match address_string_result {
@@ -144,7 +182,7 @@ fn _parse_address(addr: Vec<u8>) -> Result<Address, ParseError> {
 
 pub mod tests {
     use parser::parse_command;
-    use data::{ParseError, Command};
+    use data::{ParseError, Command, Parameters};
     use address::Address;
 
     #[test]
@@ -170,10 +208,41 @@ pub mod tests {
                            Err(ParseError::SyntaxError("Invalid MAIL command: Missing >")));
 
         test_parse_command("MAIL FROM:<mneumann@ntecs.de>\r\n",
-                           Ok(Command::MAIL_FROM(Address::new("mneumann", "ntecs.de"))));
+                           Ok(Command::MAIL_FROM(Some(Address::new("mneumann", "ntecs.de")),
+                                                 Parameters::new())));
         test_parse_command("MAIL FROM:mneumann@ntecs.de\r\n",
-                           Ok(Command::MAIL_FROM(Address::new("mneumann", "ntecs.de"))));
-
+                           Ok(Command::MAIL_FROM(Some(Address::new("mneumann", "ntecs.de")),
+                                                 Parameters::new())));
+        test_parse_command("MAIL FROM:<mneumann@ntecs.de> body=8BITMIME SIZE=1000\r\n",
+                           Ok(Command::MAIL_FROM(Some(Address::new("mneumann", "ntecs.de")),
+                                                 Parameters(vec![("BODY".to_string(),
+                                                                  Some("8BITMIME".to_string())),
+                                                                 ("SIZE".to_string(),
+                                                                  Some("1000".to_string()))]))));
+        test_parse_command("RCPT TO:<mneumann@ntecs.de> NOTIFY=NEVER\r\n",
+                           Ok(Command::RCPT_TO(Address::new("mneumann", "ntecs.de"),
+                                               Parameters(vec![("NOTIFY".to_string(),
+                                                                Some("NEVER".to_string()))]))));
+        test_parse_command("MAIL FROM:<> RET=HDRS\r\n",
+                           Ok(Command::MAIL_FROM(None,
+                                                 Parameters(vec![("RET".to_string(),
+                                                                  Some("HDRS".to_string()))]))));
+        test_parse_command("RCPT TO:<>\r\n",
+                           Err(ParseError::SyntaxError("Invalid RCPT command: Empty address")));
+        test_parse_command("MAIL FROM:<mneumann@ntecs.de> SIZE=\r\n",
+                           Err(ParseError::SyntaxError("Invalid ESMTP parameter")));
+
+
+        test_parse_command("AUTH CRAM-MD5\r\n",
+                           Ok(Command::AUTH("CRAM-MD5".to_string(), None)));
+        test_parse_command("auth SCRAM-SHA-256 biws\r\n",
+                           Ok(Command::AUTH("SCRAM-SHA-256".to_string(),
+                                            Some("biws".to_string()))));
+        test_parse_command("AUTH\r\n", Err(ParseError::MalformedCommand("Expected AUTH")));
+
+        test_parse_command("STARTTLS\r\n", Ok(Command::STARTTLS));
+        test_parse_command("StartTLS now\r\n",
+                           Err(ParseError::MalformedCommand("Expected STARTTLS")));
 
         test_parse_command("DATA\r\n", Ok(Command::DATA));
         test_parse_command("data\r\n", Ok(Command::DATA));
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use argon2;
+use pwhash::{bcrypt, sha512_crypt};
+use auth::{CredentialStore, StoredCredentials};
+use watched_file::WatchedFile;
+
+/// Credential store backed by an htpasswd-like file of `user:hash` lines.
+///
+/// Supported hashes are argon2 (`$argon2i$`, `$argon2d$`, `$argon2id$`),
+/// bcrypt (`$2a$`, `$2b$`, `$2y$`) and SHA-512-crypt (`$6$`). Empty lines and
+/// lines starting with `#` are ignored. The file is read again whenever its
+/// modification time changes.
+///
+/// Hashed passwords can only be checked with PLAIN and LOGIN, so CRAM-MD5
+/// and SCRAM are not offered with this store.
+pub struct PasswordFile {
+    entries: WatchedFile<HashMap<String, String>>,
+}
+
+impl PasswordFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<PasswordFile> {
+        Ok(PasswordFile { entries: try!(WatchedFile::open(path, "password file", _read_entries)) })
+    }
+}
+
+impl CredentialStore for PasswordFile {
+    fn lookup(&self, _username: &str) -> Option<StoredCredentials> {
+        None
+    }
+
+    fn supports_challenge_response(&self) -> bool {
+        false
+    }
+
+    fn verify_password(&self, username: &str, password: &str) -> bool {
+        match self.entries.get().get(username) {
+            Some(hash) => verify_hash(hash, password),
+            None => false,
+        }
+    }
+}
+
+fn _read_entries(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut contents = String::new();
+    try!(try!(File::open(path)).read_to_string(&mut contents));
+
+    let mut entries = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split(':').collect::<Vec<_>>();
+        if fields.len() < 2 || fields[0].is_empty() || fields[1].is_empty() {
+            warn!("{}:{}: expected user:hash, ignoring line",
+                  path.display(),
+                  number + 1);
+            continue;
+        }
+        entries.insert(fields[0].to_string(), fields[1].to_string());
+    }
+    Ok(entries)
+}
+
+pub fn verify_hash(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    } else if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+        bcrypt::verify(password, hash)
+    } else if hash.starts_with("$6$") {
+        sha512_crypt::verify(password, hash)
+    } else {
+        warn!("Unsupported password hash scheme");
+        false
+    }
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::Arc;
+    use argon2;
+    use pwhash::{bcrypt, sha512_crypt};
+    use auth::{Authenticator, CredentialStore};
+    use password_file::PasswordFile;
+    use session::SessionContext;
+
+    fn write_file(path: &PathBuf, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn verifies_supported_hashes() {
+        let path = env::temp_dir().join(format!("nibbler-password-file-test-{}", process::id()));
+        let argon2_hash = argon2::hash_encoded(b"secret1", b"somesaltsomesalt", &Default::default())
+                              .unwrap();
+        write_file(&path,
+                   &format!("# users\nalice:{}\nbob:{}\n\ncarol:{}\nbroken\n",
+                            argon2_hash,
+                            bcrypt::hash("secret2").unwrap(),
+                            sha512_crypt::hash("secret3").unwrap()));
+
+        let store = PasswordFile::open(&path).unwrap();
+        assert!(store.verify_password("alice", "secret1"));
+        assert!(store.verify_password("bob", "secret2"));
+        assert!(store.verify_password("carol", "secret3"));
+        assert!(!store.verify_password("carol", "secret1"));
+        assert!(!store.verify_password("broken", ""));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn offers_only_password_mechanisms() {
+        let path = env::temp_dir().join(format!("nibbler-password-file-mechanisms-test-{}",
+                                                process::id()));
+        write_file(&path, &format!("alice:{}\n", sha512_crypt::hash("secret").unwrap()));
+        let authenticator = Authenticator::new(Arc::new(PasswordFile::open(&path).unwrap()));
+        let session = SessionContext::new();
+        assert!(authenticator.mechanisms(&session).is_empty());
+        assert!(authenticator.start("CRAM-MD5", "localhost", &session).is_none());
+        assert!(authenticator.start("SCRAM-SHA-256", "localhost", &session).is_none());
+        fs::remove_file(&path).unwrap();
+    }
+}
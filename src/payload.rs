@@ -1,9 +1,12 @@
 use address::Address;
+use envelope::Envelope;
 
+#[derive(Clone)]
 pub struct Payload {
     pub sender: Option<Address>,
     pub recipients: Vec<Address>,
-    pub data: Vec<u8>
+    pub data: Vec<u8>,
+    pub envelope: Envelope,
 }
 
 impl Payload {
@@ -12,10 +15,33 @@ impl Payload {
             sender: None,
             recipients: Vec::new(),
             data: Vec::new(),
+            envelope: Envelope::new(),
         }
     }
 
     pub fn add_recipient(&mut self, recipient: Address) {
         self.recipients.push(recipient);
     }
+
+    /// A copy for the recipients at `indices` only, with their parameters.
+    pub fn subset(&self, indices: &[usize]) -> Payload {
+        let mut subset = self.clone();
+        subset.recipients = indices.iter().map(|&i| self.recipients[i].clone()).collect();
+        subset.envelope.recipient_parameters = indices.iter()
+                                                      .filter_map(|&i| {
+                                                          self.envelope
+                                                              .recipient_parameters
+                                                              .get(i)
+                                                              .cloned()
+                                                      })
+                                                      .collect();
+        subset
+    }
+
+    /// Adds a header line (including CRLF) in front of the message data.
+    pub fn prepend_header(&mut self, header: &str) {
+        let mut data = header.as_bytes().to_vec();
+        data.extend(self.data.iter());
+        self.data = data;
+    }
 }
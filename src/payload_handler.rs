@@ -1,71 +1,389 @@
-use payload::Payload;
-use email::MimeMessage;
-use lettre::email::{EmailBuilder, SendableEmail};
-use lettre::transport::smtp::{SecurityLevel, SmtpTransport,
-SmtpTransportBuilder};
-use lettre::transport::smtp::authentication::Mechanism;
-use lettre::transport::smtp::SUBMISSION_PORT;
-use lettre::transport::EmailTransport;
-use rand::Rng;
-use rand;
-
-
-pub struct PayloadHandler;
-
-struct PayloadEmail(Payload, MessageId);
-
-type MessageId = String;
-
-impl SendableEmail for PayloadEmail {
-    fn from_address(&self) -> String {
-        match self.0.sender {
-            Some(ref sender) => format!("{}@{}", sender.address, sender.domain),
-            _ => panic!("No address set on payload!")
-        }
-    }
-    fn to_addresses(&self) -> Vec<String> {
-        self.0.recipients.iter().map(|rec| format!("{}@{}", rec.address, rec.domain)).collect::<Vec<String>>()
-    }
-    fn message(&self) -> String{
-        String::from_utf8(self.0.data.clone()).unwrap()
-    }
-    fn message_id(&self) -> String{
-        self.1.clone()
-    }
-}
-
-impl PayloadHandler {
-	pub fn handle(payload: Payload) {
-        let data_string = String::from_utf8(payload.data.clone()).unwrap();
-        let message = MimeMessage::parse(&data_string).unwrap();
-        let message_id: String = match message.headers.get("Message-ID".to_string()) {
-            Some(msg_id) => msg_id.get_value::<String>().unwrap().clone(),
-            _ => {
-                let mut rng = rand::thread_rng();
-                let mut available = Vec::new();
-                available.extend('A' as u8..'Z' as u8);
-                available.extend('a' as u8..'z' as u8);
-                available.extend('0' as u8..'9' as u8);
-                String::from_utf8((0..48).map(|x| available[rng.gen::<usize>() % available.len()])
-                    .collect::<Vec<u8>>()).ok().unwrap()
-            }
-        };
-        let payload_email = PayloadEmail(payload, message_id);
-        let mut mailer = SmtpTransportBuilder::new(("server.tld",
-                                       SUBMISSION_PORT)).unwrap()
-                // Set the name sent during EHLO/HELO, default is `localhost`
-                .hello_name("210-246-36-65.dsl.dyn.ihug.co.nz")
-                    // Add credentials for authentication
-                    .credentials("just.mate.antunovic@gmail.com", "shittycrap12")
-                        // Specify a TLS security level. You can also specify an SslContext with
-                        // .ssl_context(SslContext::Ssl23)
-                        .security_level(SecurityLevel::AlwaysEncrypt)
-                            // Enable SMTPUTF8 if the server supports it
-                            .smtp_utf8(true)
-                                // Configure expected authentication mechanism
-                                //.authentication_mechanism(Mechanism::CramMd5)
-                                    // Enable connection reuse
-                                    .connection_reuse(false).build();
-        mailer.send(payload_email);
-	}
-}
+use std::fmt;
+use std::time::Duration;
+use address::Address;
+use client::{SmtpClient, ClientError, Reply};
+use config::{RelayConfig, TlsMode};
+use data::Parameters;
+use envelope::BodyType;
+use payload::Payload;
+
+/// What became of one recipient.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecipientStatus {
+    /// Stored in a mailbox on this host.
+    Delivered,
+    /// Accepted by a server that does not offer DSN (RFC 3461), so the
+    /// sender hears nothing more about it.
+    Relayed,
+    /// Accepted by a server that was given the DSN parameters and notifies
+    /// the sender from now on.
+    RelayedWithDsn,
+    /// Refused for now, e.g. with `451`. Worth another try later.
+    Deferred(Reply),
+    /// Refused for good, e.g. with `550`.
+    Failed(Reply),
+}
+
+/// Why no recipient could be handed to the relay.
+#[derive(Debug)]
+pub enum RelayError {
+    /// Connecting, the greeting, EHLO or TLS failed.
+    Client(ClientError),
+    /// TLS is required but the relay does not offer STARTTLS.
+    StartTlsUnavailable,
+    /// Credentials are configured but the relay does not offer AUTH PLAIN.
+    AuthUnavailable,
+    AuthFailed(ClientError),
+    SenderRejected(Reply),
+}
+
+impl RelayError {
+    /// Whether trying again later might help.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            RelayError::Client(ref err) |
+            RelayError::AuthFailed(ref err) => err.is_transient(),
+            RelayError::SenderRejected(ref reply) => reply.is_transient(),
+            RelayError::StartTlsUnavailable | RelayError::AuthUnavailable => true,
+        }
+    }
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RelayError::Client(ref err) => write!(f, "{}", err),
+            RelayError::StartTlsUnavailable => write!(f, "relay does not offer STARTTLS"),
+            RelayError::AuthUnavailable => write!(f, "relay does not offer AUTH PLAIN"),
+            RelayError::AuthFailed(ref err) => write!(f, "authentication failed: {}", err),
+            RelayError::SenderRejected(ref reply) => write!(f, "sender rejected: {}", reply),
+        }
+    }
+}
+
+impl RecipientStatus {
+    /// Whether this host is done with the recipient.
+    pub fn is_accepted(&self) -> bool {
+        match *self {
+            RecipientStatus::Delivered |
+            RecipientStatus::Relayed |
+            RecipientStatus::RelayedWithDsn => true,
+            RecipientStatus::Deferred(_) | RecipientStatus::Failed(_) => false,
+        }
+    }
+}
+
+impl From<ClientError> for RelayError {
+    fn from(err: ClientError) -> RelayError {
+        RelayError::Client(err)
+    }
+}
+
+/// Delivers messages somewhere else, e.g. to a smarthost.
+pub trait Transport: Send + Sync {
+    /// Tries each recipient of `payload` once and returns their statuses in
+    /// the order of `payload.recipients`, or why none could be tried.
+    fn deliver(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError>;
+}
+
+/// Hands messages to a smarthost.
+pub struct PayloadHandler {
+    config: RelayConfig,
+    timeout: Duration,
+}
+
+impl PayloadHandler {
+    pub fn new(config: RelayConfig) -> PayloadHandler {
+        PayloadHandler {
+            config: config,
+            // RFC 5321 section 4.5.3.2 asks for at least 5 minutes for MAIL,
+            // RCPT and DATA, and 10 for the final dot
+            timeout: Duration::from_secs(10 * 60),
+        }
+    }
+
+    /// Relays `payload` and tells what became of each recipient. Only fails
+    /// as a whole if not a single RCPT could be sent.
+    pub fn handle(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        let config = &self.config;
+        let mut client = try!(SmtpClient::connect(&config.host,
+                                                  config.port,
+                                                  config.tls == TlsMode::Implicit,
+                                                  self.timeout));
+        try!(client.hello(&config.hello_name));
+        if config.tls == TlsMode::StartTls {
+            if !client.has_extension("STARTTLS") {
+                client.quit();
+                return Err(RelayError::StartTlsUnavailable);
+            }
+            client = try!(client.starttls());
+            try!(client.hello(&config.hello_name));
+        }
+        if let Some((username, password)) = config.credentials() {
+            if !client.extension_parameters("AUTH").iter().any(|m| m == "PLAIN") {
+                client.quit();
+                return Err(RelayError::AuthUnavailable);
+            }
+            if let Err(err) = client.auth_plain(&username, &password) {
+                client.quit();
+                return Err(RelayError::AuthFailed(err));
+            }
+        }
+        send(client, payload)
+    }
+}
+
+/// Runs one mail transaction for `payload` on a session that has been
+/// greeted, and says goodbye. The DSN parameters are passed on if the
+/// server offers DSN (RFC 3461 section 6.2). Only fails as a whole if not a
+/// single RCPT could be sent.
+pub fn send(client: SmtpClient,
+            payload: &Payload)
+            -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+    let mut client = client;
+    let dsn = client.has_extension("DSN");
+    let mut mail_from = format!("MAIL FROM:<{}>",
+                                payload.sender.as_ref().map_or(String::new(), |s| s.to_string()));
+    if payload.envelope.body_type == BodyType::EightBitMime && client.has_extension("8BITMIME") {
+        mail_from.push_str(" BODY=8BITMIME");
+    }
+    if dsn {
+        _push_parameters(&mut mail_from, &payload.envelope.mail_parameters, &["RET", "ENVID"]);
+    }
+    let reply = try!(client.command(&mail_from));
+    if !reply.is_positive() {
+        client.quit();
+        return Err(RelayError::SenderRejected(reply));
+    }
+
+    let mut statuses = Vec::new();
+    for (i, recipient) in payload.recipients.iter().enumerate() {
+        let mut rcpt_to = format!("RCPT TO:<{}>", recipient);
+        if let (true, Some(parameters)) = (dsn, payload.envelope.recipient_parameters.get(i)) {
+            _push_parameters(&mut rcpt_to, parameters, &["NOTIFY", "ORCPT"]);
+        }
+        let reply = try!(client.command(&rcpt_to));
+        statuses.push((recipient.clone(), _status(reply, dsn)));
+    }
+    if statuses.iter().any(|&(_, ref status)| status.is_accepted()) {
+        let status = _status(try!(client.data(&payload.data)), dsn);
+        for &mut (_, ref mut recipient_status) in statuses.iter_mut() {
+            if recipient_status.is_accepted() {
+                *recipient_status = status.clone();
+            }
+        }
+    }
+    client.quit();
+    Ok(statuses)
+}
+
+impl Transport for PayloadHandler {
+    fn deliver(&self, payload: &Payload) -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+        self.handle(payload)
+    }
+}
+
+/// Appends those of `keywords` that are in `parameters` to `command`.
+fn _push_parameters(command: &mut String, parameters: &Parameters, keywords: &[&str]) {
+    for keyword in keywords {
+        match parameters.get(keyword) {
+            Some(Some(value)) => command.push_str(&format!(" {}={}", keyword, value)),
+            Some(None) => command.push_str(&format!(" {}", keyword)),
+            None => (),
+        }
+    }
+}
+
+/// `dsn` tells whether the server was given the DSN parameters.
+fn _status(reply: Reply, dsn: bool) -> RecipientStatus {
+    if reply.is_positive() && dsn {
+        RecipientStatus::RelayedWithDsn
+    } else if reply.is_positive() {
+        RecipientStatus::Relayed
+    } else if reply.is_transient() {
+        RecipientStatus::Deferred(reply)
+    } else {
+        RecipientStatus::Failed(reply)
+    }
+}
+
+pub mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{spawn, JoinHandle};
+    use address::Address;
+    use client::Reply;
+    use config::{RelayConfig, TlsMode};
+    use data::Parameters;
+    use payload::Payload;
+    use payload_handler::{PayloadHandler, RecipientStatus, RelayError};
+
+    /// A one-connection SMTP server on loopback that answers each command
+    /// line with `reply(line)` and returns everything it was sent.
+    pub fn stand_in<F>(reply: F) -> (u16, JoinHandle<Vec<String>>)
+        where F: Fn(&str) -> &'static str + Send + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(conn.try_clone().unwrap());
+            let mut transcript = Vec::new();
+            conn.write_all(b"220 stand-in ESMTP\r\n").unwrap();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    return transcript;
+                }
+                let line = line.trim_end_matches("\r\n").to_string();
+                let answer = if in_data {
+                    if line == "." {
+                        in_data = false;
+                        reply(".")
+                    } else {
+                        ""
+                    }
+                } else if line == "DATA" {
+                    in_data = true;
+                    "354 Go ahead"
+                } else {
+                    reply(&line)
+                };
+                transcript.push(line);
+                if !answer.is_empty() {
+                    conn.write_all(answer.as_bytes()).unwrap();
+                    conn.write_all(b"\r\n").unwrap();
+                }
+            }
+        });
+        (port, server)
+    }
+
+    fn relay_config(port: u16) -> RelayConfig {
+        let mut config = RelayConfig::new("127.0.0.1", "mx.example.org");
+        config.port = port;
+        config.tls = TlsMode::Disabled;
+        config
+    }
+
+    fn payload() -> Payload {
+        let mut payload = Payload::new();
+        payload.sender = Some(Address::new("matt", "example.org"));
+        payload.add_recipient(Address::new("marie", "example.net"));
+        payload.add_recipient(Address::new("gone", "example.net"));
+        payload.add_recipient(Address::new("busy", "example.net"));
+        payload.data = b"Subject: hi\r\n\r\n.leading dot\r\n".to_vec();
+        payload
+    }
+
+    #[test]
+    fn relays_and_reports_each_recipient() {
+        let (port, server) = stand_in(|line| {
+            match line {
+                l if l.starts_with("EHLO") => "250-stand-in\r\n250 AUTH LOGIN PLAIN",
+                l if l.starts_with("AUTH PLAIN") => "235 2.7.0 Accepted",
+                "RCPT TO:<gone@example.net>" => "550 5.1.1 No such user",
+                "RCPT TO:<busy@example.net>" => "452 4.2.2 Mailbox full",
+                "QUIT" => "221 Bye",
+                _ => "250 OK",
+            }
+        });
+        let mut config = relay_config(port);
+        config.username = Some("mx".to_string());
+        config.password = Some("secret".to_string());
+        let statuses = PayloadHandler::new(config).handle(&payload()).unwrap();
+        assert_eq!(vec![(Address::new("marie", "example.net"), RecipientStatus::Relayed),
+                        (Address::new("gone", "example.net"),
+                         RecipientStatus::Failed(Reply {
+                             code: 550,
+                             lines: vec!["5.1.1 No such user".to_string()],
+                         })),
+                        (Address::new("busy", "example.net"),
+                         RecipientStatus::Deferred(Reply {
+                             code: 452,
+                             lines: vec!["4.2.2 Mailbox full".to_string()],
+                         }))],
+                   statuses);
+
+        let transcript = server.join().unwrap();
+        assert_eq!(vec!["EHLO mx.example.org",
+                        "AUTH PLAIN AG14AHNlY3JldA==",
+                        "MAIL FROM:<matt@example.org>",
+                        "RCPT TO:<marie@example.net>",
+                        "RCPT TO:<gone@example.net>",
+                        "RCPT TO:<busy@example.net>",
+                        "DATA",
+                        "Subject: hi",
+                        "",
+                        "..leading dot",
+                        ".",
+                        "QUIT"],
+                   transcript);
+    }
+
+    #[test]
+    fn passes_on_dsn_parameters() {
+        let (port, server) = stand_in(|line| {
+            match line {
+                l if l.starts_with("EHLO") => "250-stand-in\r\n250 DSN",
+                "QUIT" => "221 Bye",
+                _ => "250 OK",
+            }
+        });
+        let mut payload = payload();
+        payload.recipients.truncate(2);
+        payload.envelope.mail_parameters = Parameters(vec![("RET".to_string(),
+                                                            Some("HDRS".to_string())),
+                                                           ("ENVID".to_string(),
+                                                            Some("QQ314159".to_string()))]);
+        payload.envelope.recipient_parameters =
+            vec![Parameters(vec![("NOTIFY".to_string(), Some("SUCCESS,FAILURE".to_string())),
+                                 ("ORCPT".to_string(),
+                                  Some("rfc822;marie@example.net".to_string()))]),
+                 Parameters::new()];
+        let statuses = PayloadHandler::new(relay_config(port)).handle(&payload).unwrap();
+        assert_eq!(vec![RecipientStatus::RelayedWithDsn, RecipientStatus::RelayedWithDsn],
+                   statuses.into_iter().map(|(_, status)| status).collect::<Vec<_>>());
+
+        let transcript = server.join().unwrap();
+        assert_eq!(vec!["MAIL FROM:<matt@example.org> RET=HDRS ENVID=QQ314159",
+                        "RCPT TO:<marie@example.net> NOTIFY=SUCCESS,FAILURE \
+                         ORCPT=rfc822;marie@example.net",
+                        "RCPT TO:<gone@example.net>"],
+                   &transcript[1..4]);
+    }
+
+    #[test]
+    fn reports_why_nothing_was_relayed() {
+        let (port, _) = stand_in(|_| "250 stand-in");
+        let mut config = relay_config(port);
+        config.tls = TlsMode::StartTls;
+        match PayloadHandler::new(config).handle(&payload()) {
+            Err(RelayError::StartTlsUnavailable) => (),
+            other => panic!("expected missing STARTTLS, got {:?}", other),
+        }
+
+        let (port, _) = stand_in(|line| {
+            if line.starts_with("MAIL") {
+                "553 5.7.1 Sender not allowed"
+            } else {
+                "250 OK"
+            }
+        });
+        match PayloadHandler::new(relay_config(port)).handle(&payload()) {
+            Err(ref err @ RelayError::SenderRejected(_)) => assert!(!err.is_transient()),
+            other => panic!("expected rejected sender, got {:?}", other),
+        }
+
+        let (port, server) = stand_in(|_| "250 OK");
+        drop(server);
+        let mut config = relay_config(port);
+        config.username = Some("mx".to_string());
+        config.password = Some("secret".to_string());
+        match PayloadHandler::new(config).handle(&payload()) {
+            Err(ref err @ RelayError::AuthUnavailable) => assert!(err.is_transient()),
+            other => panic!("expected missing AUTH, got {:?}", other),
+        }
+    }
+}
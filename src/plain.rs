@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use auth::{CredentialStore, SaslMechanism, SaslStep};
+
+/// PLAIN (RFC 4616). Only offered when the session is encrypted.
+pub struct Plain {
+    credentials: Arc<CredentialStore>,
+    username: Option<String>,
+}
+
+impl Plain {
+    pub fn new(credentials: Arc<CredentialStore>) -> Plain {
+        Plain {
+            credentials: credentials,
+            username: None,
+        }
+    }
+}
+
+impl SaslMechanism for Plain {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let response = match response {
+            Some(response) => response,
+            None => return SaslStep::Challenge(Vec::new()),
+        };
+
+        // authzid NUL authcid NUL passwd
+        let fields = response.split(|&b| b == 0).collect::<Vec<_>>();
+        if fields.len() != 3 {
+            return SaslStep::Failure;
+        }
+        let (authzid, username, password) = match (String::from_utf8(fields[0].to_vec()),
+                                                   String::from_utf8(fields[1].to_vec()),
+                                                   String::from_utf8(fields[2].to_vec())) {
+            (Ok(authzid), Ok(username), Ok(password)) => (authzid, username, password),
+            _ => return SaslStep::Failure,
+        };
+        self.username = Some(username.clone());
+        if !authzid.is_empty() && authzid != username {
+            return SaslStep::Failure;
+        }
+
+        if self.credentials.verify_password(&username, &password) {
+            SaslStep::Success(username, None)
+        } else {
+            SaslStep::Failure
+        }
+    }
+
+    fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
+
+/// The non-standard LOGIN mechanism still used by many clients.
+pub struct Login {
+    credentials: Arc<CredentialStore>,
+    username: Option<String>,
+}
+
+impl Login {
+    pub fn new(credentials: Arc<CredentialStore>) -> Login {
+        Login {
+            credentials: credentials,
+            username: None,
+        }
+    }
+}
+
+impl SaslMechanism for Login {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let response = match response.map(|r| String::from_utf8(r.to_vec())) {
+            None => return SaslStep::Challenge(b"Username:".to_vec()),
+            Some(Ok(response)) => response,
+            Some(Err(_)) => return SaslStep::Failure,
+        };
+
+        match self.username.clone() {
+            None => {
+                self.username = Some(response);
+                SaslStep::Challenge(b"Password:".to_vec())
+            }
+            Some(username) => {
+                if self.credentials.verify_password(&username, &response) {
+                    SaslStep::Success(username, None)
+                } else {
+                    SaslStep::Failure
+                }
+            }
+        }
+    }
+
+    fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
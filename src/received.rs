@@ -0,0 +1,140 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::{self, Timespec};
+use address::Address;
+use envelope::Envelope;
+use session::SessionContext;
+
+/// Protocol name for the `with` clause (RFC 3848).
+pub fn protocol(session: &SessionContext) -> &'static str {
+    match (session.extended, session.is_tls(), session.authenticated_user.is_some()) {
+        (false, _, _) => "SMTP",
+        (true, false, false) => "ESMTP",
+        (true, true, false) => "ESMTPS",
+        (true, false, true) => "ESMTPA",
+        (true, true, true) => "ESMTPSA",
+    }
+}
+
+/// The `Received:` trace header of RFC 5321 section 4.4, including CRLF.
+pub fn received_header(envelope: &Envelope, recipients: &[Address], hostname: &str) -> String {
+    let session = &envelope.session;
+    let mut header = "Received:".to_string();
+
+    if let Some(ref helo_name) = session.helo_name {
+        header.push_str(&format!(" from {}", helo_name));
+        if let Some(peer_addr) = session.peer_addr {
+            let peer_name = session.peer_name.as_ref().map(|n| &n[..]).unwrap_or("unknown");
+            header.push_str(&format!(" ({} {})", peer_name, address_literal(&peer_addr.ip())));
+        }
+        header.push_str("\r\n\t");
+    }
+
+    header.push_str(&format!("by {} (rust-smtp) with {} id {}",
+                             hostname,
+                             protocol(session),
+                             envelope.queue_id));
+    if recipients.len() == 1 {
+        header.push_str(&format!("\r\n\tfor <{}>", recipients[0]));
+    }
+    header.push_str(&format!(";\r\n\t{}\r\n", rfc5322_date(envelope.received_at)));
+    header
+}
+
+/// The `Return-Path:` header added at final delivery (RFC 5321 section 4.4).
+pub fn return_path_header(sender: Option<&Address>) -> String {
+    match sender {
+        Some(sender) => format!("Return-Path: <{}>\r\n", sender),
+        None => "Return-Path: <>\r\n".to_string(),
+    }
+}
+
+/// Number of relays a message has passed, counted as `Received:` and
+/// `Delivered-To:` header fields in the header section of `data`.
+pub fn hop_count(data: &[u8]) -> usize {
+    data.split(|&b| b == b'\n')
+        .map(|line| if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line })
+        .take_while(|line| !line.is_empty())
+        .filter(|line| _is_field(line, "Received") || _is_field(line, "Delivered-To"))
+        .count()
+}
+
+fn _is_field(line: &[u8], name: &str) -> bool {
+    line.len() > name.len() && line[name.len()] == b':' &&
+    line[..name.len()].eq_ignore_ascii_case(name.as_bytes())
+}
+
+/// `[192.0.2.1]` or `[IPv6:2001:db8::1]` (RFC 5321 section 4.1.3)
+pub fn address_literal(ip: &IpAddr) -> String {
+    match *ip {
+        IpAddr::V4(ref ip) => format!("[{}]", ip),
+        IpAddr::V6(ref ip) => format!("[IPv6:{}]", ip),
+    }
+}
+
+/// `at` as in a `Date:` header field, e.g. `Tue, 01 Jan 2019 12:00:00 +0000`.
+pub fn rfc5322_date(at: SystemTime) -> String {
+    let at = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let tm = time::at_utc(Timespec::new(at.as_secs() as i64, 0));
+    // rfc822z() would give "-0000", which means "local time unknown"
+    time::strftime("%a, %d %b %Y %H:%M:%S +0000", &tm).unwrap()
+}
+
+pub mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use address::Address;
+    use envelope::Envelope;
+    use received::{received_header, return_path_header, hop_count};
+    use session::TlsInfo;
+
+    fn envelope() -> Envelope {
+        let mut envelope = Envelope::new();
+        envelope.queue_id = "0065A1B2C3D4E5F6G7H8".to_string();
+        envelope.received_at = UNIX_EPOCH + Duration::from_secs(1791892800);
+        envelope.session.helo_name = Some("client.example".to_string());
+        envelope.session.extended = true;
+        envelope.session.peer_addr = Some("192.0.2.1:4321".parse().unwrap());
+        envelope
+    }
+
+    #[test]
+    fn formats_received_header() {
+        let mut envelope = envelope();
+        envelope.session.tls = Some(TlsInfo::new());
+        envelope.session.authenticated_user = Some("matt".to_string());
+        let recipients = vec![Address::new("marie", "example.org")];
+        assert_eq!("Received: from client.example (unknown [192.0.2.1])\r\n\tby mx.example.org \
+                    (rust-smtp) with ESMTPSA id 0065A1B2C3D4E5F6G7H8\r\n\tfor \
+                    <marie@example.org>;\r\n\tTue, 13 Oct 2026 12:00:00 +0000\r\n",
+                   received_header(&envelope, &recipients, "mx.example.org"));
+    }
+
+    #[test]
+    fn omits_for_clause_with_several_recipients() {
+        let mut envelope = envelope();
+        envelope.session.peer_name = Some("client.example".to_string());
+        envelope.session.peer_addr = Some("[2001:db8::1]:4321".parse().unwrap());
+        let recipients = vec![Address::new("marie", "example.org"),
+                              Address::new("max", "example.org")];
+        assert_eq!("Received: from client.example (client.example [IPv6:2001:db8::1])\r\n\tby \
+                    mx.example.org (rust-smtp) with ESMTP id 0065A1B2C3D4E5F6G7H8;\r\n\tTue, 13 \
+                    Oct 2026 12:00:00 +0000\r\n",
+                   received_header(&envelope, &recipients, "mx.example.org"));
+    }
+
+    #[test]
+    fn formats_return_path() {
+        assert_eq!("Return-Path: <matt@example.org>\r\n",
+                   return_path_header(Some(&Address::new("matt", "example.org"))));
+        assert_eq!("Return-Path: <>\r\n", return_path_header(None));
+    }
+
+    #[test]
+    fn counts_hops_in_header_section_only() {
+        let data = b"Received: from a\r\n\tby b; date\r\nreceived: from c\r\nDelivered-To: \
+                     marie@example.org\r\nX-Received-By: d\r\nSubject: hi\r\n\r\nReceived: \
+                     quoted in the body\r\n";
+        assert_eq!(3, hop_count(data));
+        assert_eq!(0, hop_count(b"Hi Marie\r\n"));
+    }
+}
@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+use dns_parser::{Builder, Packet, QueryClass, QueryType, RData, ResponseCode};
+use rand::random;
+
+/// A mail exchanger of a domain (RFC 5321 section 5.1). Lower preferences
+/// are tried first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MxRecord {
+    pub preference: u16,
+    /// Host name, `.` for a null MX (RFC 7505).
+    pub exchange: String,
+}
+
+impl MxRecord {
+    pub fn new(preference: u16, exchange: &str) -> MxRecord {
+        MxRecord {
+            preference: preference,
+            exchange: exchange.to_string(),
+        }
+    }
+
+    /// Whether this says the domain accepts no mail at all (RFC 7505).
+    pub fn is_null(&self) -> bool {
+        self.exchange.is_empty() || self.exchange == "."
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ResolveError {
+    /// The name does not exist (NXDOMAIN).
+    NoSuchDomain,
+    /// No answer for now, e.g. the name servers timed out or failed.
+    Temporary(String),
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ResolveError::NoSuchDomain => write!(f, "no such domain"),
+            ResolveError::Temporary(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Looks up what outbound delivery needs from the DNS.
+pub trait Resolver: Send + Sync {
+    /// The MX records of `domain`, empty if it has none.
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>, ResolveError>;
+
+    /// The IPv4 and IPv6 addresses of `host`, empty if it has none.
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError>;
+}
+
+/// Answers from a fixed table, for tests and for pinning routes. Names it
+/// knows nothing about do not exist.
+pub struct StaticResolver {
+    mx: HashMap<String, Vec<MxRecord>>,
+    addresses: HashMap<String, Vec<IpAddr>>,
+    failing: Vec<String>,
+}
+
+impl StaticResolver {
+    pub fn new() -> StaticResolver {
+        StaticResolver {
+            mx: HashMap::new(),
+            addresses: HashMap::new(),
+            failing: Vec::new(),
+        }
+    }
+
+    pub fn with_mx(mut self, domain: &str, preference: u16, exchange: &str) -> StaticResolver {
+        self.mx.entry(_normalize(domain)).or_insert(Vec::new()).push(MxRecord::new(preference,
+                                                                                  exchange));
+        self
+    }
+
+    pub fn with_address(mut self, host: &str, address: IpAddr) -> StaticResolver {
+        self.addresses.entry(_normalize(host)).or_insert(Vec::new()).push(address);
+        self
+    }
+
+    /// Makes every lookup of `name` fail temporarily.
+    pub fn with_failure(mut self, name: &str) -> StaticResolver {
+        self.failing.push(_normalize(name));
+        self
+    }
+
+    fn _lookup<T: Clone>(&self,
+                         table: &HashMap<String, Vec<T>>,
+                         name: &str)
+                         -> Result<Vec<T>, ResolveError> {
+        let name = _normalize(name);
+        if self.failing.contains(&name) {
+            return Err(ResolveError::Temporary(format!("{}: lookup failed", name)));
+        }
+        match table.get(&name) {
+            Some(records) => Ok(records.clone()),
+            None if self.mx.contains_key(&name) || self.addresses.contains_key(&name) => {
+                Ok(Vec::new())
+            }
+            None => Err(ResolveError::NoSuchDomain),
+        }
+    }
+}
+
+impl Resolver for StaticResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>, ResolveError> {
+        self._lookup(&self.mx, domain)
+    }
+
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        self._lookup(&self.addresses, host)
+    }
+}
+
+/// Asks recursive name servers, one after the other, over UDP and over TCP
+/// for answers that do not fit into a datagram.
+pub struct DnsResolver {
+    servers: Vec<SocketAddr>,
+    timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(servers: Vec<SocketAddr>) -> DnsResolver {
+        DnsResolver {
+            servers: servers,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// The name servers of `/etc/resolv.conf`, or the local one if there
+    /// are none.
+    pub fn system() -> DnsResolver {
+        let mut text = String::new();
+        let _ = File::open("/etc/resolv.conf").and_then(|mut file| file.read_to_string(&mut text));
+        let mut servers = text.lines()
+                              .filter_map(|line| {
+                                  let mut words = line.split_whitespace();
+                                  match (words.next(), words.next()) {
+                                      (Some("nameserver"), Some(address)) => {
+                                          address.parse::<IpAddr>().ok()
+                                      }
+                                      _ => None,
+                                  }
+                              })
+                              .map(|address| SocketAddr::new(address, 53))
+                              .collect::<Vec<_>>();
+        if servers.is_empty() {
+            servers.push("127.0.0.1:53".parse().unwrap());
+        }
+        DnsResolver::new(servers)
+    }
+
+    /// Asks each server in turn until one answers, and collects the records
+    /// `extract` picks from the answer section.
+    fn _query<T, F>(&self, name: &str, qtype: QueryType, extract: F) -> Result<Vec<T>, ResolveError>
+        where F: Fn(&RData) -> Option<T>
+    {
+        let mut builder = Builder::new_query(random(), true);
+        builder.add_question(&_normalize(name), false, qtype, QueryClass::IN);
+        let query = try!(builder.build().map_err(|_| {
+            ResolveError::Temporary("query too long".to_string())
+        }));
+        let mut last_error = ResolveError::Temporary("no name servers".to_string());
+        for server in self.servers.iter() {
+            let response = match self._exchange(server, &query) {
+                Ok(response) => response,
+                Err(err) => {
+                    last_error = ResolveError::Temporary(format!("{}: {}", server, err));
+                    continue;
+                }
+            };
+            let packet = match Packet::parse(&response) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    last_error = ResolveError::Temporary(format!("{}: {}", server, err));
+                    continue;
+                }
+            };
+            match packet.header.response_code {
+                ResponseCode::NoError => {
+                    return Ok(packet.answers.iter().filter_map(|a| extract(&a.data)).collect())
+                }
+                ResponseCode::NameError => return Err(ResolveError::NoSuchDomain),
+                code => last_error = ResolveError::Temporary(format!("{}: {:?}", server, code)),
+            }
+        }
+        Err(last_error)
+    }
+
+    fn _exchange(&self, server: &SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = try!(UdpSocket::bind(local));
+        try!(socket.set_read_timeout(Some(self.timeout)));
+        try!(socket.connect(server));
+        try!(socket.send(query));
+        let mut buffer = [0; 512];
+        loop {
+            let length = try!(socket.recv(&mut buffer));
+            // answers to earlier queries that timed out
+            if length < 12 || buffer[..2] != query[..2] {
+                continue;
+            }
+            let truncated = buffer[2] & 0x02 != 0;
+            if !truncated {
+                return Ok(buffer[..length].to_vec());
+            }
+            return self._exchange_tcp(server, query);
+        }
+    }
+
+    /// RFC 1035 section 4.2.2: messages prefixed with their length.
+    fn _exchange_tcp(&self, server: &SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = try!(TcpStream::connect_timeout(server, self.timeout));
+        try!(stream.set_read_timeout(Some(self.timeout)));
+        try!(stream.write_all(&[(query.len() >> 8) as u8, query.len() as u8]));
+        try!(stream.write_all(query));
+        let mut length = [0; 2];
+        try!(stream.read_exact(&mut length));
+        let mut response = vec![0; (length[0] as usize) << 8 | length[1] as usize];
+        try!(stream.read_exact(&mut response));
+        Ok(response)
+    }
+}
+
+impl Resolver for DnsResolver {
+    fn mx(&self, domain: &str) -> Result<Vec<MxRecord>, ResolveError> {
+        self._query(domain, QueryType::MX, |data| {
+            match *data {
+                RData::MX(ref mx) => Some(MxRecord::new(mx.preference, &mx.exchange.to_string())),
+                _ => None,
+            }
+        })
+    }
+
+    fn addresses(&self, host: &str) -> Result<Vec<IpAddr>, ResolveError> {
+        let mut addresses = try!(self._query(host, QueryType::A, |data| {
+            match *data {
+                RData::A(ref a) => Some(IpAddr::V4(a.0)),
+                _ => None,
+            }
+        }));
+        match self._query(host, QueryType::AAAA, |data| {
+            match *data {
+                RData::AAAA(ref aaaa) => Some(IpAddr::V6(aaaa.0)),
+                _ => None,
+            }
+        }) {
+            Ok(v6) => addresses.extend(v6),
+            // IPv4 alone will do
+            Err(_) if !addresses.is_empty() => (),
+            Err(err) => return Err(err),
+        }
+        Ok(addresses)
+    }
+}
+
+fn _normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+pub mod tests {
+    use std::net::{IpAddr, UdpSocket};
+    use std::thread::spawn;
+    use resolver::{DnsResolver, MxRecord, Resolver, ResolveError, StaticResolver};
+
+    #[test]
+    fn static_resolver_knows_only_its_table() {
+        let resolver = StaticResolver::new()
+                           .with_mx("example.net", 10, "mx.example.net")
+                           .with_address("mx.example.net", "192.0.2.1".parse().unwrap())
+                           .with_failure("example.com");
+        assert_eq!(Ok(vec![MxRecord::new(10, "mx.example.net")]),
+                   resolver.mx("Example.NET."));
+        assert_eq!(Ok(vec![]), resolver.mx("mx.example.net"));
+        assert_eq!(Ok(vec!["192.0.2.1".parse::<IpAddr>().unwrap()]),
+                   resolver.addresses("mx.example.net"));
+        assert_eq!(Err(ResolveError::NoSuchDomain), resolver.mx("example.org"));
+        assert!(resolver.mx("example.com").is_err());
+    }
+
+    /// Answers an MX query for example.net with `10 mx.example.net` and
+    /// everything else with NXDOMAIN.
+    fn answer(query: &[u8]) -> Vec<u8> {
+        let mut response = query.to_vec();
+        let is_mx = query[query.len() - 4..query.len() - 2] == [0, 15] &&
+                    query[12..].starts_with(b"\x07example\x03net\x00");
+        response[2] = 0x81;
+        response[3] = if is_mx { 0x80 } else { 0x83 };
+        if is_mx {
+            response[7] = 1;
+            // name pointing at the question, MX, IN, TTL 300
+            response.extend(&[0xc0, 12, 0, 15, 0, 1, 0, 0, 1, 44]);
+            let exchange = b"\x00\x0a\x02mx\x07example\x03net\x00";
+            response.extend(&[0, exchange.len() as u8]);
+            response.extend(exchange.iter());
+        }
+        response
+    }
+
+    #[test]
+    fn asks_name_servers() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        spawn(move || {
+            let mut buffer = [0; 512];
+            loop {
+                let (length, client) = server.recv_from(&mut buffer).unwrap();
+                server.send_to(&answer(&buffer[..length]), client).unwrap();
+            }
+        });
+        let resolver = DnsResolver::new(vec![address]);
+        assert_eq!(Ok(vec![MxRecord::new(10, "mx.example.net")]),
+                   resolver.mx("example.net"));
+        assert_eq!(Err(ResolveError::NoSuchDomain), resolver.mx("example.org"));
+        assert_eq!(Err(ResolveError::NoSuchDomain), resolver.addresses("example.org"));
+    }
+}
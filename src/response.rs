@@ -13,7 +13,26 @@ impl Response {
         }
     }
 
+    /// A multiline reply; each arg is sent on its own continuation line.
+    pub fn with_args(code: u16, message: &str, args: Vec<String>) -> Response {
+        Response {
+            code: code,
+            message: message.to_string(),
+            args: Some(args),
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        format!("{} {}\r\n", self.code, self.message).into_bytes()
+        match self.args {
+            Some(ref args) if args.len() > 0 => {
+                let mut reply = format!("{}-{}\r\n", self.code, self.message);
+                for (i, arg) in args.iter().enumerate() {
+                    let separator = if i + 1 == args.len() { ' ' } else { '-' };
+                    reply.push_str(&format!("{}{}{}\r\n", self.code, separator, arg));
+                }
+                reply.into_bytes()
+            }
+            _ => format!("{} {}\r\n", self.code, self.message).into_bytes(),
+        }
     }
 }
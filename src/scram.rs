@@ -0,0 +1,359 @@
+use std::sync::Arc;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Digest};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use auth::{ChannelBinding, CredentialStore, SaslMechanism, SaslStep, random_nonce,
+           constant_time_eq};
+
+/// Salted SCRAM-SHA-256 keys (RFC 5802 section 3), stored instead of the password.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ScramKeys {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: Vec<u8>,
+    pub server_key: Vec<u8>,
+}
+
+impl ScramKeys {
+    pub fn derive(password: &str, salt: &[u8], iterations: u32) -> ScramKeys {
+        let salted_password = _hi(password.as_bytes(), salt, iterations);
+        let client_key = _hmac(&salted_password, b"Client Key");
+        ScramKeys {
+            salt: salt.to_vec(),
+            iterations: iterations,
+            stored_key: Sha256::digest(&client_key).to_vec(),
+            server_key: _hmac(&salted_password, b"Server Key"),
+        }
+    }
+}
+
+fn _hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).unwrap();
+    mac.input(data);
+    mac.result().code().to_vec()
+}
+
+// Hi() of RFC 5802 section 2.2, i.e. PBKDF2 with HMAC-SHA-256 and one output block.
+fn _hi(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = salt.to_vec();
+    block.extend(&[0, 0, 0, 1]);
+    let mut u = _hmac(password, &block);
+    let mut result = u.clone();
+    for _ in 1..iterations {
+        u = _hmac(password, &u);
+        for (r, b) in result.iter_mut().zip(u.iter()) {
+            *r ^= *b;
+        }
+    }
+    result
+}
+
+enum ScramState {
+    Initial,
+    ServerFirstSent {
+        username: String,
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+        // None for unknown users; the exchange then fails at the proof.
+        keys: Option<ScramKeys>,
+    },
+    Done,
+}
+
+/// SCRAM-SHA-256 and SCRAM-SHA-256-PLUS (RFC 7677, RFC 5802).
+pub struct ScramSha256 {
+    credentials: Arc<CredentialStore>,
+    channel_binding: Option<ChannelBinding>,
+    plus: bool,
+    // Key for the salts made up for unknown users.
+    salt_secret: Vec<u8>,
+    server_nonce: String,
+    state: ScramState,
+    username: Option<String>,
+}
+
+impl ScramSha256 {
+    /// `salt_secret` must stay the same for the life of the process, so that
+    /// an unknown user gets the same salt on every attempt.
+    pub fn new(credentials: Arc<CredentialStore>,
+               channel_binding: Option<ChannelBinding>,
+               plus: bool,
+               salt_secret: &[u8])
+               -> ScramSha256 {
+        ScramSha256::with_nonce(credentials,
+                                channel_binding,
+                                plus,
+                                salt_secret,
+                                &random_nonce(24))
+    }
+
+    fn with_nonce(credentials: Arc<CredentialStore>,
+                  channel_binding: Option<ChannelBinding>,
+                  plus: bool,
+                  salt_secret: &[u8],
+                  server_nonce: &str)
+                  -> ScramSha256 {
+        ScramSha256 {
+            credentials: credentials,
+            channel_binding: channel_binding,
+            plus: plus,
+            salt_secret: salt_secret.to_vec(),
+            server_nonce: server_nonce.to_string(),
+            state: ScramState::Initial,
+            username: None,
+        }
+    }
+
+    // Checks the gs2-cbind-flag against the selected mechanism and the
+    // channel binding of the session (RFC 5802 section 6).
+    fn _check_cbind_flag(&self, flag: &str) -> bool {
+        match (flag, self.plus, self.channel_binding.as_ref()) {
+            ("n", false, _) => true,
+            // client supports channel binding but thinks we do not; if we
+            // advertised -PLUS this is a downgrade
+            ("y", false, None) => true,
+            (flag, true, Some(cb)) if flag.starts_with("p=") => &flag[2..] == cb.name,
+            _ => false,
+        }
+    }
+
+    fn _client_first(&mut self, message: &[u8]) -> SaslStep {
+        let message = match String::from_utf8(message.to_vec()) {
+            Ok(message) => message,
+            Err(_) => return SaslStep::Failure,
+        };
+
+        let parts = message.splitn(3, ',').collect::<Vec<_>>();
+        if parts.len() != 3 || !self._check_cbind_flag(parts[0]) {
+            return SaslStep::Failure;
+        }
+        let gs2_header = format!("{},{},", parts[0], parts[1]);
+        let client_first_bare = parts[2].to_string();
+
+        let attributes = client_first_bare.split(',').collect::<Vec<_>>();
+        if attributes.len() < 2 || !attributes[0].starts_with("n=") ||
+           !attributes[1].starts_with("r=") || attributes[1].len() == 2 {
+            return SaslStep::Failure;
+        }
+        let username = match decode_saslname(&attributes[0][2..]) {
+            Some(username) => username,
+            None => return SaslStep::Failure,
+        };
+        let client_nonce = attributes[1][2..].to_string();
+        self.username = Some(username.clone());
+
+        // Authorization identities other than the user itself are not supported.
+        if !parts[1].is_empty() && parts[1] != format!("a={}", username) {
+            return SaslStep::Failure;
+        }
+
+        let keys = self.credentials.lookup(&username).and_then(|c| c.scram_sha256);
+        let (salt, iterations) = match keys {
+            Some(ref keys) => (keys.salt.clone(), keys.iterations),
+            // a fresh salt on each attempt would tell that the user does not exist
+            None => {
+                let salt = _hmac(&self.salt_secret, username.as_bytes())[..16].to_vec();
+                (salt, 4096)
+            }
+        };
+
+        let nonce = format!("{}{}", client_nonce, self.server_nonce);
+        let server_first = format!("r={},s={},i={}", nonce, salt.to_base64(STANDARD), iterations);
+
+        self.state = ScramState::ServerFirstSent {
+            username: username,
+            gs2_header: gs2_header,
+            client_first_bare: client_first_bare,
+            server_first: server_first.clone(),
+            nonce: nonce,
+            keys: keys,
+        };
+        SaslStep::Challenge(server_first.into_bytes())
+    }
+
+    fn _client_final(&self, message: &[u8]) -> SaslStep {
+        let (username, gs2_header, client_first_bare, server_first, nonce, keys) = match self.state {
+            ScramState::ServerFirstSent { ref username,
+                                          ref gs2_header,
+                                          ref client_first_bare,
+                                          ref server_first,
+                                          ref nonce,
+                                          ref keys } => {
+                (username, gs2_header, client_first_bare, server_first, nonce, keys)
+            }
+            _ => return SaslStep::Failure,
+        };
+
+        let message = match String::from_utf8(message.to_vec()) {
+            Ok(message) => message,
+            Err(_) => return SaslStep::Failure,
+        };
+        let (without_proof, proof) = match message.rfind(",p=") {
+            Some(index) => (&message[..index], &message[index + 3..]),
+            None => return SaslStep::Failure,
+        };
+
+        let mut expected_cbind = gs2_header.clone().into_bytes();
+        if self.plus {
+            if let Some(ref cb) = self.channel_binding {
+                expected_cbind.extend(&cb.data);
+            }
+        }
+
+        let attributes = without_proof.split(',').collect::<Vec<_>>();
+        if attributes.len() < 2 ||
+           attributes[0] != format!("c={}", expected_cbind.to_base64(STANDARD)) ||
+           attributes[1] != format!("r={}", nonce) {
+            return SaslStep::Failure;
+        }
+
+        let keys = match *keys {
+            Some(ref keys) => keys,
+            None => return SaslStep::Failure,
+        };
+        let proof = match proof.from_base64() {
+            Ok(proof) => proof,
+            Err(_) => return SaslStep::Failure,
+        };
+
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+        let client_signature = _hmac(&keys.stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return SaslStep::Failure;
+        }
+        let client_key = proof.iter()
+                              .zip(client_signature.iter())
+                              .map(|(&p, &s)| p ^ s)
+                              .collect::<Vec<u8>>();
+        if !constant_time_eq(&Sha256::digest(&client_key), &keys.stored_key) {
+            return SaslStep::Failure;
+        }
+
+        let server_signature = _hmac(&keys.server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", server_signature.to_base64(STANDARD));
+        SaslStep::Success(username.clone(), Some(server_final.into_bytes()))
+    }
+}
+
+impl SaslMechanism for ScramSha256 {
+    fn step(&mut self, response: Option<&[u8]>) -> SaslStep {
+        let step = match (&self.state, response) {
+            // client-first mechanism, prompt for the initial response
+            (&ScramState::Initial, None) => return SaslStep::Challenge(Vec::new()),
+            (&ScramState::Initial, Some(response)) => return self._client_first(response),
+            (&ScramState::ServerFirstSent { .. }, Some(response)) => self._client_final(response),
+            _ => SaslStep::Failure,
+        };
+        self.state = ScramState::Done;
+        step
+    }
+
+    fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+}
+
+// saslname of RFC 5802 section 5.1: ',' and '=' are escaped as =2C and =3D.
+pub fn decode_saslname(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(index) = rest.find('=') {
+        decoded.push_str(&rest[..index]);
+        match rest.get(index..index + 3) {
+            Some("=2C") => decoded.push(','),
+            Some("=3D") => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[index + 3..];
+    }
+    decoded.push_str(rest);
+    if decoded.is_empty() { None } else { Some(decoded) }
+}
+
+pub mod tests {
+    use std::sync::Arc;
+    use rustc_serialize::base64::FromBase64;
+    use auth::{ChannelBinding, StaticCredentialStore, SaslMechanism, SaslStep};
+    use scram::{ScramKeys, ScramSha256, decode_saslname};
+
+    // RFC 7677 section 3
+    const SERVER_NONCE: &'static str = "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+    const CLIENT_FIRST: &'static str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SERVER_FIRST: &'static str = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                        s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+    const CLIENT_FINAL: &'static str = "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                        p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=";
+    const SERVER_FINAL: &'static str = "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=";
+    const SECRET: &'static [u8] = b"secret";
+
+    fn store() -> Arc<StaticCredentialStore> {
+        let salt = "W22ZaJ0SNY7soEsUEjb6gQ==".from_base64().unwrap();
+        let mut store = StaticCredentialStore::new();
+        store.add_scram_keys("user", ScramKeys::derive("pencil", &salt, 4096));
+        Arc::new(store)
+    }
+
+    #[test]
+    fn accepts_rfc_example() {
+        let mut scram = ScramSha256::with_nonce(store(), None, false, SECRET, SERVER_NONCE);
+        match scram.step(Some(CLIENT_FIRST.as_bytes())) {
+            SaslStep::Challenge(challenge) => assert_eq!(SERVER_FIRST.as_bytes(), &challenge[..]),
+            _ => panic!("expected server-first-message"),
+        }
+        match scram.step(Some(CLIENT_FINAL.as_bytes())) {
+            SaslStep::Success(identity, Some(server_final)) => {
+                assert_eq!("user", identity);
+                assert_eq!(SERVER_FINAL.as_bytes(), &server_final[..]);
+            }
+            _ => panic!("expected success"),
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_proof() {
+        let mut scram = ScramSha256::with_nonce(store(), None, false, SECRET, SERVER_NONCE);
+        scram.step(Some(CLIENT_FIRST.as_bytes()));
+        let client_final = CLIENT_FINAL.replace("p=dHzb", "p=AAAA");
+        match scram.step(Some(client_final.as_bytes())) {
+            SaslStep::Failure => (),
+            _ => panic!("expected failure"),
+        }
+    }
+
+    #[test]
+    fn rejects_channel_binding_downgrade() {
+        let cb = ChannelBinding::new("tls-unique", vec![1, 2, 3]);
+        let mut scram = ScramSha256::with_nonce(store(), Some(cb), false, SECRET, SERVER_NONCE);
+        match scram.step(Some(b"y,,n=user,r=rOprNGfwEbeRWgbNEkqO")) {
+            SaslStep::Failure => (),
+            _ => panic!("expected failure"),
+        }
+    }
+
+    #[test]
+    fn repeats_salt_for_unknown_users() {
+        let server_first = |username: &str, secret: &[u8]| {
+            let mut scram = ScramSha256::new(store(), None, false, secret);
+            let client_first = format!("n,,n={},r=rOprNGfwEbeRWgbNEkqO", username);
+            match scram.step(Some(client_first.as_bytes())) {
+                SaslStep::Challenge(challenge) => {
+                    let challenge = String::from_utf8(challenge).unwrap();
+                    challenge.split(',').skip(1).collect::<Vec<_>>().join(",")
+                }
+                _ => panic!("expected server-first-message"),
+            }
+        };
+        assert_eq!(server_first("nobody", SECRET), server_first("nobody", SECRET));
+        assert!(server_first("nobody", SECRET) != server_first("somebody", SECRET));
+        assert!(server_first("nobody", SECRET) != server_first("nobody", b"other"));
+    }
+
+    #[test]
+    fn decodes_saslname() {
+        assert_eq!(Some("a,b=c".to_string()), decode_saslname("a=2Cb=3Dc"));
+        assert_eq!(None, decode_saslname("a=2"));
+        assert_eq!(None, decode_saslname(""));
+    }
+}
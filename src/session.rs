@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+use auth::ChannelBinding;
+
+/// Parameters of the TLS layer a session runs over, as reported by whoever
+/// set up the TLS stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// e.g. `TLSv1.3`
+    pub protocol: Option<String>,
+    /// e.g. `TLS_AES_256_GCM_SHA384`
+    pub cipher: Option<String>,
+    /// Enables SCRAM-SHA-256-PLUS when known.
+    pub channel_binding: Option<ChannelBinding>,
+}
+
+impl TlsInfo {
+    pub fn new() -> TlsInfo {
+        TlsInfo {
+            protocol: None,
+            cipher: None,
+            channel_binding: None,
+        }
+    }
+}
+
+/// What is known about the client and the connection. Built by whoever
+/// accepts the connection, completed by the connection handler as the
+/// session goes on, and copied into each `Payload`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionContext {
+    pub peer_addr: Option<SocketAddr>,
+    /// Reverse DNS name of the peer, if the acceptor looked it up.
+    pub peer_name: Option<String>,
+    pub local_addr: Option<SocketAddr>,
+    /// Name the client gave in HELO or EHLO.
+    pub helo_name: Option<String>,
+    /// Whether the client greeted with EHLO.
+    pub extended: bool,
+    pub tls: Option<TlsInfo>,
+    pub authenticated_user: Option<String>,
+}
+
+impl SessionContext {
+    pub fn new() -> SessionContext {
+        SessionContext {
+            peer_addr: None,
+            peer_name: None,
+            local_addr: None,
+            helo_name: None,
+            extended: false,
+            tls: None,
+            authenticated_user: None,
+        }
+    }
+
+    pub fn from_addrs(peer_addr: SocketAddr, local_addr: SocketAddr) -> SessionContext {
+        let mut session = SessionContext::new();
+        session.peer_addr = Some(peer_addr);
+        session.local_addr = Some(local_addr);
+        session
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn channel_binding(&self) -> Option<&ChannelBinding> {
+        self.tls.as_ref().and_then(|tls| tls.channel_binding.as_ref())
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::Sender;
+use payload::Payload;
+
+/// Why a sink did not take a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkError {
+    /// The client should try again later (`451`).
+    Temporary(String),
+    /// The sink is full, the client should try again later (`451 4.3.1`).
+    InsufficientStorage,
+    /// The message will never be taken (`554`).
+    Rejected(String),
+}
+
+/// Where accepted messages go.
+///
+/// The client is told the message was accepted only after `commit` returned
+/// `Ok`, so a sink should only do that once it can no longer lose the
+/// message, e.g. after it was written and synced to disk.
+pub trait MessageSink: Send + Sync {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError>;
+
+    /// Whether a new mail transaction should be started. Asked at MAIL, so
+    /// that a full sink turns clients away before they send their data.
+    fn has_capacity(&self) -> bool {
+        true
+    }
+}
+
+/// Hands messages to a channel. Only as durable as the receiving end makes
+/// it: messages are acknowledged as soon as they are in the channel.
+pub struct ChannelSink {
+    sender: Mutex<Sender<Payload>>,
+}
+
+impl ChannelSink {
+    pub fn new(sender: Sender<Payload>) -> ChannelSink {
+        ChannelSink { sender: Mutex::new(sender) }
+    }
+}
+
+impl MessageSink for ChannelSink {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        match self.sender.lock().unwrap().send(payload) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(SinkError::Temporary("Message consumer is gone".to_string())),
+        }
+    }
+}
+
+/// Calls a function for each message, on the session's thread.
+pub struct CallbackSink<F> {
+    callback: F,
+}
+
+impl<F> CallbackSink<F>
+    where F: Fn(Payload) -> Result<(), SinkError> + Send + Sync
+{
+    pub fn new(callback: F) -> CallbackSink<F> {
+        CallbackSink { callback: callback }
+    }
+}
+
+impl<F> MessageSink for CallbackSink<F>
+    where F: Fn(Payload) -> Result<(), SinkError> + Send + Sync
+{
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        (self.callback)(payload)
+    }
+}
+
+/// Commits each message to all of its sinks, in order, and fails if one of
+/// them fails. The sinks before the failing one keep their copy, so a client
+/// retrying the message may hand them a duplicate.
+pub struct FanOutSink {
+    sinks: Vec<Arc<MessageSink>>,
+}
+
+impl FanOutSink {
+    pub fn new(sinks: Vec<Arc<MessageSink>>) -> FanOutSink {
+        FanOutSink { sinks: sinks }
+    }
+}
+
+impl MessageSink for FanOutSink {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        for sink in self.sinks.iter() {
+            try!(sink.commit(payload.clone()));
+        }
+        Ok(())
+    }
+
+    fn has_capacity(&self) -> bool {
+        self.sinks.iter().all(|sink| sink.has_capacity())
+    }
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    capacity: usize,
+}
+
+struct QueueState {
+    payloads: VecDeque<Payload>,
+    closed: bool,
+}
+
+/// Sending end of a bounded queue, see `bounded`.
+pub struct BoundedSink {
+    queue: Arc<Queue>,
+}
+
+/// Receiving end of a bounded queue. Iterating yields messages until the
+/// sink is dropped and the queue is empty.
+pub struct BoundedReceiver {
+    queue: Arc<Queue>,
+}
+
+/// A queue holding at most `capacity` messages. While it is full, MAIL is
+/// answered with `451 4.3.1` and messages still in transit are deferred, so
+/// a slow consumer pushes back on clients instead of filling up memory.
+pub fn bounded(capacity: usize) -> (BoundedSink, BoundedReceiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            payloads: VecDeque::new(),
+            closed: false,
+        }),
+        not_empty: Condvar::new(),
+        capacity: capacity,
+    });
+    (BoundedSink { queue: queue.clone() }, BoundedReceiver { queue: queue })
+}
+
+impl MessageSink for BoundedSink {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.payloads.len() >= self.queue.capacity {
+            return Err(SinkError::InsufficientStorage);
+        }
+        state.payloads.push_back(payload);
+        self.queue.not_empty.notify_one();
+        Ok(())
+    }
+
+    fn has_capacity(&self) -> bool {
+        self.queue.state.lock().unwrap().payloads.len() < self.queue.capacity
+    }
+}
+
+impl Drop for BoundedSink {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().closed = true;
+        self.queue.not_empty.notify_all();
+    }
+}
+
+impl BoundedReceiver {
+    /// Waits for the next message. `None` once the sink is gone and all
+    /// messages were received.
+    pub fn recv(&self) -> Option<Payload> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(payload) = state.payloads.pop_front() {
+                return Some(payload);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.queue.not_empty.wait(state).unwrap();
+        }
+    }
+}
+
+impl Iterator for BoundedReceiver {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        self.recv()
+    }
+}
+
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread::spawn;
+    use payload::Payload;
+    use address::Address;
+    use sink::{MessageSink, CallbackSink, FanOutSink, SinkError, bounded};
+
+    fn payload(recipient: &str) -> Payload {
+        let mut payload = Payload::new();
+        payload.add_recipient(Address::new(recipient, "example.org"));
+        payload
+    }
+
+    #[test]
+    fn bounded_queue_pushes_back_when_full() {
+        let (sink, receiver) = bounded(2);
+        assert!(sink.commit(payload("a")).is_ok());
+        assert!(sink.has_capacity());
+        assert!(sink.commit(payload("b")).is_ok());
+        assert!(!sink.has_capacity());
+        assert_eq!(Err(SinkError::InsufficientStorage), sink.commit(payload("c")));
+
+        assert_eq!("a", receiver.recv().unwrap().recipients[0].address);
+        assert!(sink.has_capacity());
+        let consumer = spawn(move || {
+            receiver.map(|p| p.recipients[0].address.clone()).collect::<Vec<_>>()
+        });
+        assert!(sink.commit(payload("d")).is_ok());
+        drop(sink);
+        assert_eq!(vec!["b", "d"], consumer.join().unwrap());
+    }
+
+    #[test]
+    fn fans_out_until_a_sink_fails() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = {
+            let seen = seen.clone();
+            Arc::new(CallbackSink::new(move |payload: Payload| {
+                seen.lock().unwrap().push(payload.recipients[0].address.clone());
+                Ok(())
+            }))
+        };
+        let (full, _receiver) = bounded(0);
+        let sink = FanOutSink::new(vec![recorder.clone(), recorder.clone()]);
+        assert!(sink.commit(payload("a")).is_ok());
+        assert!(sink.has_capacity());
+
+        let sink = FanOutSink::new(vec![recorder, Arc::new(full)]);
+        assert!(!sink.has_capacity());
+        assert_eq!(Err(SinkError::InsufficientStorage), sink.commit(payload("b")));
+        assert_eq!(vec!["a", "a", "b"], *seen.lock().unwrap());
+    }
+}
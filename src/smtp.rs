@@ -1,43 +1,173 @@
+use std::cmp::min;
 use std::io;
-use std::io::{Read, Write};
-use data::Command;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use data::{Command, ParseError};
 use parser::read_command;
 use parse_util::read_line_bytes;
 use smtp_state::{SmtpStateMachine, DefaultStateMachine, SmtpState};
 use payload::Payload;
-use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use smtp_error::SmtpError;
 use response::Response;
+use auth::{Authenticator, AuthError};
+use session::SessionContext;
+use config::{ServerConfig, ListenerConfig, Policy, Timeouts, TlsMode};
+use address::Address;
+use received::{received_header, hop_count};
+use shutdown::ShutdownHandle;
+use sink::{MessageSink, SinkError};
+use local::{User, UserDirectory, is_local_domain};
+use std::time::SystemTime;
+
 
 pub struct DefaultConnectionHandler {
-    message_sender: Sender<Payload>,
+    sink: Arc<MessageSink>,
+    authenticator: Option<Arc<Authenticator>>,
+    hostname: String,
+    banner: String,
+    received_header: bool,
+    max_hops: usize,
+    timeouts: Timeouts,
+    shutdown: Option<ShutdownHandle>,
+    starttls: bool,
+    require_auth: bool,
+    policies: Vec<Policy>,
+    local_users: Option<(Vec<String>, Arc<UserDirectory>)>,
+    relay_domains: Option<Vec<String>>,
 }
 
 impl DefaultConnectionHandler {
-    pub fn new(message_sender: Sender<Payload>) -> DefaultConnectionHandler {
-        DefaultConnectionHandler { message_sender: message_sender }
+    /// Accepted messages are committed to `sink`.
+    pub fn new(sink: Arc<MessageSink>) -> DefaultConnectionHandler {
+        DefaultConnectionHandler::with_config(sink, &ServerConfig::default())
     }
 
-    fn _say_hello_and_start_session<C: Read + Write, S: SmtpStateMachine>
-        (&self,
-         conn: &mut C)
-         -> Result<S, SmtpError> {
-        let server_hostname = "mail.ntecs.de";
-        let server_agent = "rust-smtp";
+    /// Takes hostname, banner, limits, timeouts and delivery settings from
+    /// `config`.
+    /// AUTH still has to be enabled with `with_authenticator`.
+    pub fn with_config(sink: Arc<MessageSink>,
+                       config: &ServerConfig)
+                       -> DefaultConnectionHandler {
+        DefaultConnectionHandler {
+            sink: sink,
+            authenticator: None,
+            hostname: config.hostname.clone(),
+            banner: config.banner.clone(),
+            received_header: config.delivery.received_header,
+            max_hops: config.limits.max_hops,
+            timeouts: config.timeouts.clone(),
+            shutdown: None,
+            starttls: false,
+            require_auth: false,
+            policies: Vec::new(),
+            local_users: None,
+            relay_domains: None,
+        }
+    }
+
+    /// Applies the TLS mode, auth requirement and policies of the listener
+    /// the connections come from. Without it the handler behaves like an
+    /// open relay listener.
+    pub fn with_listener(mut self, listener: &ListenerConfig) -> DefaultConnectionHandler {
+        self.starttls = listener.tls == TlsMode::StartTls;
+        self.require_auth = listener.require_auth;
+        self.policies = listener.policies.clone();
+        self
+    }
+
+    /// Enables the AUTH command (RFC 4954) with the given authenticator.
+    pub fn with_authenticator(mut self,
+                              authenticator: Arc<Authenticator>)
+                              -> DefaultConnectionHandler {
+        self.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Whether to prepend a `Received:` trace header to each message
+    /// (RFC 5321 section 4.4). On by default.
+    pub fn with_received_header(mut self, received_header: bool) -> DefaultConnectionHandler {
+        self.received_header = received_header;
+        self
+    }
+
+    /// Messages that already passed more relays than this are rejected as a
+    /// mail loop. Defaults to 100 (RFC 5321 section 6.3).
+    pub fn with_max_hops(mut self, max_hops: usize) -> DefaultConnectionHandler {
+        self.max_hops = max_hops;
+        self
+    }
+
+    /// How long to wait for the client. Defaults to the values recommended
+    /// in RFC 5321 section 4.5.3.2.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> DefaultConnectionHandler {
+        self.timeouts = timeouts;
+        self
+    }
 
-        let response_220 = Response::new(220,
-                                         &format!("220 {} ESMTP {}",
-                                                  server_hostname,
-                                                  server_agent));
+    /// Once `shutdown` is triggered, idle sessions are closed with `421`.
+    /// Sessions in a mail transaction get to finish it first.
+    pub fn with_shutdown(mut self, shutdown: ShutdownHandle) -> DefaultConnectionHandler {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Refuses recipients in `domains` that `users` does not know.
+    pub fn with_local_users(mut self,
+                            domains: Vec<String>,
+                            users: Arc<UserDirectory>)
+                            -> DefaultConnectionHandler {
+        self.local_users = Some((domains, users));
+        self
+    }
+
+    /// Refuses recipients outside `domains` until the client authenticated,
+    /// so that only our own users can relay mail through us.
+    pub fn with_relay_domains(mut self, domains: Vec<String>) -> DefaultConnectionHandler {
+        self.relay_domains = Some(domains);
+        self
+    }
+
+    fn _shutting_down(&self) -> bool {
+        self.shutdown.as_ref().map_or(false, |shutdown| shutdown.is_shutting_down())
+    }
 
-        if let Err(err) = conn.write_all(&response_220.to_bytes()) {
-            error!("Error while writing 220 hostname and agent response");
-            return Err(SmtpError::IOError(err));
+    fn _shut_down<C: Write>(&self, conn: &mut C) -> SessionEnd {
+        info!("Closing session for shutdown");
+        _flush_bytes(&Response::new(421, &format!("4.3.2 {} Service shutting down",
+                                                  self.hostname))
+                          .to_bytes(),
+                     conn);
+        SessionEnd::Closed
+    }
+
+    fn _say_hello_and_start_session<C: Connection, S: SmtpStateMachine>
+        (&self,
+         conn: &mut C,
+         mut session: SessionContext,
+         greet: bool,
+         session_deadline: Instant)
+         -> Result<S, SmtpError> {
+        if greet {
+            let response_220 = Response::new(220,
+                                             &format!("{} ESMTP {}", self.hostname, self.banner));
+            if let Err(err) = conn.write_all(&response_220.to_bytes()) {
+                error!("Error while writing 220 hostname and agent response");
+                return Err(SmtpError::IOError(err));
+            }
         }
 
-        let client_hostname = match read_command(conn) {
-            Ok(Command::EHLO(h)) => h,
-            Ok(Command::HELO(h)) => h,
+        let timeout = if greet {
+            self.timeouts.greeting
+        } else {
+            self.timeouts.command
+        };
+        let deadline = _deadline(timeout, session_deadline);
+        let mut timed_conn = Deadline::new(conn, deadline).interruptible(self.shutdown.as_ref());
+        let (client_hostname, extended) = match read_command(&mut timed_conn) {
+            Ok(Command::EHLO(h)) => (h, true),
+            Ok(Command::HELO(h)) => (h, false),
             Ok(unexpected_command) => {
                 error!("Unexpected command {:?}", unexpected_command);
                 return Err(SmtpError::UnexpectedCommand(unexpected_command,
@@ -50,7 +180,24 @@ impl DefaultConnectionHandler {
         };
 
         info!("Client hostname: {}", client_hostname);
-        let hello_response = Response::new(250, &format!("Hello {}", client_hostname));
+        let hello_message = format!("Hello {}", client_hostname);
+        // RET, ENVID, NOTIFY and ORCPT are kept with the envelope (RFC 3461)
+        let mut extensions = vec!["8BITMIME".to_string(), "DSN".to_string()];
+        if self.starttls && !session.is_tls() {
+            extensions.push("STARTTLS".to_string());
+        }
+        if let Some(ref authenticator) = self.authenticator {
+            let mechanisms = authenticator.mechanisms(&session);
+            // e.g. only PLAIN and LOGIN, before STARTTLS
+            if !mechanisms.is_empty() {
+                extensions.push(format!("AUTH {}", mechanisms.join(" ")));
+            }
+        }
+        let hello_response = if extended {
+            Response::with_args(250, &hello_message, extensions)
+        } else {
+            Response::new(250, &hello_message)
+        };
         let hello_result = conn.write_all(&hello_response.to_bytes());
         if let Ok(_) = hello_result {
             info!("Said Hello to {}", client_hostname);
@@ -59,89 +206,455 @@ impl DefaultConnectionHandler {
             return Err(SmtpError::IOError(hello_result.err().unwrap()));
         }
 
-        Ok(S::new())
+        session.helo_name = Some(client_hostname);
+        session.extended = extended;
+        Ok(S::new(session))
     }
-}
 
-pub trait ConnectionHandler {
-    fn handle_connection<C: Read + Write>(&self, mut conn: &mut C);
-}
+    fn _authenticate<C: Read + Write, S: SmtpStateMachine>(&self,
+                                                           conn: &mut C,
+                                                           state_machine: &mut S,
+                                                           auth_failures: &mut u32,
+                                                           mechanism: &str,
+                                                           initial_response: &Option<String>)
+                                                           -> Result<Response, SmtpError> {
+        let authenticator = match self.authenticator {
+            Some(ref authenticator) => authenticator,
+            None => return Ok(Response::new(502, "5.5.1 AUTH not supported")),
+        };
+        if state_machine.session().authenticated_user.is_some() {
+            return Ok(Response::new(503, "5.5.1 Already authenticated"));
+        }
+        if state_machine.state() != SmtpState::Start {
+            return Ok(Response::new(503, "5.5.1 AUTH not permitted during a mail transaction"));
+        }
 
-impl ConnectionHandler for DefaultConnectionHandler {
-    fn handle_connection<C: Read + Write>(&self, conn: &mut C) {
-        debug!("Got connection");
+        match authenticator.authenticate(conn,
+                                         mechanism,
+                                         initial_response.as_ref().map(|r| &r[..]),
+                                         &self.hostname,
+                                         state_machine.session()) {
+            Ok(identity) => {
+                info!("Authenticated as {}", identity);
+                state_machine.session_mut().authenticated_user = Some(identity);
+                Ok(Response::new(235, "2.7.0 Authentication successful"))
+            }
+            Err(AuthError::IOError(err)) => Err(SmtpError::IOError(err)),
+            Err(AuthError::ParseError(err)) => Err(SmtpError::ParseError(err)),
+            Err(err) => {
+                warn!("Authentication with {} failed: {:?}", mechanism, err);
+                match err {
+                    AuthError::InvalidCredentials | AuthError::LockedOut => *auth_failures += 1,
+                    _ => (),
+                }
+                let max_failures = authenticator.throttle()
+                                                .map(|t| t.config().max_failures_per_session);
+                if max_failures.map_or(false, |max| *auth_failures >= max) {
+                    return Ok(Response::new(421,
+                                            "4.7.0 Too many authentication failures, closing \
+                                             connection"));
+                }
+                Ok(err.to_response())
+            }
+        }
+    }
 
-        let setup_result = self._say_hello_and_start_session::<C, DefaultStateMachine>(conn);
-        if !setup_result.is_ok() {
-            return;
+    /// Checks the listener's auth requirement and policies before a
+    /// MAIL FROM is passed on to the state machine.
+    fn _check_mail(&self, session: &SessionContext, sender: Option<&Address>) -> Option<Response> {
+        if self.policies.contains(&Policy::RequireTls) && !session.is_tls() {
+            return Some(Response::new(530, "5.7.0 Must issue a STARTTLS command first"));
+        }
+        let user = match session.authenticated_user {
+            Some(ref user) => user,
+            None if self.require_auth => {
+                return Some(Response::new(530, "5.7.0 Authentication required"))
+            }
+            None => return None,
+        };
+        if self.policies.contains(&Policy::SenderMatchesLogin) && !_sender_matches(sender, user) {
+            return Some(Response::new(553,
+                                      &format!("5.7.1 Sender address not owned by user {}",
+                                               user)));
         }
-        let mut session_state = setup_result.unwrap();
+        None
+    }
+
+    /// Checks relaying and looks up recipients in local domains before
+    /// RCPT TO is passed on to the state machine.
+    fn _check_rcpt(&self,
+                   session: &SessionContext,
+                   state: SmtpState,
+                   recipient: &Address)
+                   -> Option<Response> {
+        match state {
+            SmtpState::ReadyForRecptTo | SmtpState::ReadyForData => (),
+            // out of sequence, for the state machine to answer
+            _ => return None,
+        }
+        if let Some(ref relay_domains) = self.relay_domains {
+            if session.authenticated_user.is_none() &&
+               !is_local_domain(relay_domains, &recipient.domain) {
+                info!("Refusing to relay to {}", recipient);
+                return Some(Response::new(554, "5.7.1 Relay access denied"));
+            }
+        }
+        let (domains, users) = match self.local_users {
+            Some((ref domains, ref users)) => (domains, users),
+            None => return None,
+        };
+        if !is_local_domain(domains, &recipient.domain) {
+            return None;
+        }
+        match users.lookup(recipient) {
+            Ok(User::Unknown) => {
+                info!("Refusing unknown user {}", recipient);
+                Some(Response::new(550, &format!("5.1.1 <{}>: No such user here", recipient)))
+            }
+            Ok(_) => None,
+            Err(err) => {
+                error!("Could not look up {}: {}", recipient, err);
+                Some(Response::new(451, "4.3.0 Temporary user lookup failure"))
+            }
+        }
+    }
+
+    fn _check_capacity(&self) -> Option<Response> {
+        if self.sink.has_capacity() {
+            None
+        } else {
+            warn!("Sink is full, deferring new transactions");
+            Some(Response::new(451, "4.3.1 Insufficient system storage"))
+        }
+    }
+
+    fn _run_session<C: Connection>(&self,
+                                   conn: &mut C,
+                                   session: SessionContext,
+                                   greet: bool)
+                                   -> SessionEnd {
+        if self._shutting_down() {
+            return self._shut_down(conn);
+        }
+        let session_deadline = Instant::now() + self.timeouts.session;
+        let setup_result =
+            self._say_hello_and_start_session::<C, DefaultStateMachine>(conn,
+                                                                        session,
+                                                                        greet,
+                                                                        session_deadline);
+        let mut session_state = match setup_result {
+            Ok(session_state) => session_state,
+            Err(SmtpError::ParseError(ParseError::Timeout)) => return _time_out(conn),
+            Err(_) if self._shutting_down() => {
+                return self._shut_down(conn)
+            }
+            Err(_) => return SessionEnd::Closed,
+        };
+        let mut auth_failures = 0;
 
         let mut bytes_to_write: Vec<u8> = Vec::new();
         loop {
             bytes_to_write.clear();
-            let cmd_result = read_command(conn);
+            let idle = match session_state.state() {
+                SmtpState::ReadyForRecptTo | SmtpState::ReadyForData => false,
+                _ => true,
+            };
+            let deadline = _deadline(self.timeouts.command, session_deadline);
+            let cmd_result = {
+                let shutdown = if idle {
+                    self.shutdown.as_ref()
+                } else {
+                    None
+                };
+                read_command(&mut Deadline::new(conn, deadline).interruptible(shutdown))
+            };
             if let Ok(cmd) = cmd_result {
-                if let Ok(response) = session_state.transition(&cmd) {
+                if let Command::AUTH(ref mechanism, ref initial_response) = cmd {
+                    let deadline = _deadline(self.timeouts.command, session_deadline);
+                    match self._authenticate(&mut Deadline::new(conn, deadline),
+                                             &mut session_state,
+                                             &mut auth_failures,
+                                             mechanism,
+                                             initial_response) {
+                        Ok(ref response) if response.code == 421 => {
+                            _flush_bytes(&response.to_bytes(), conn);
+                            return SessionEnd::Closed;
+                        }
+                        Ok(response) => bytes_to_write.extend(response.to_bytes()),
+                        Err(SmtpError::ParseError(ParseError::Timeout)) => {
+                            return _time_out(conn)
+                        }
+                        Err(err) => {
+                            error!("Error during authentication: {:?}. Quitting", err);
+                            return SessionEnd::Closed;
+                        }
+                    }
+                } else if let Command::STARTTLS = cmd {
+                    if !self.starttls || session_state.session().is_tls() {
+                        bytes_to_write.extend(Response::new(502, "5.5.1 STARTTLS not available")
+                                                  .to_bytes());
+                    } else if session_state.state() != SmtpState::Start {
+                        bytes_to_write.extend(Response::new(503,
+                                                            "5.5.1 STARTTLS not permitted \
+                                                             during a mail transaction")
+                                                  .to_bytes());
+                    } else {
+                        _flush_bytes(&Response::new(220, "2.0.0 Ready to start TLS").to_bytes(),
+                                     conn);
+                        return SessionEnd::StartTls(session_state.session().clone());
+                    }
+                } else if let Some(response) = match cmd {
+                    Command::MAIL_FROM(ref sender, _) => {
+                        self._check_mail(session_state.session(), sender.as_ref())
+                            .or_else(|| self._check_capacity())
+                    }
+                    Command::RCPT_TO(ref recipient, _) => {
+                        self._check_rcpt(session_state.session(), session_state.state(), recipient)
+                    }
+                    _ => None,
+                } {
+                    bytes_to_write.extend(response.to_bytes());
+                } else if let Ok(response) = session_state.transition(&cmd) {
                     bytes_to_write.extend(response.to_bytes());
                 } else {
                     // meh
                 }
                 _flush_bytes(&bytes_to_write, conn);
+            } else if let Err(ParseError::Timeout) = cmd_result {
+                return _time_out(conn);
+            } else if idle && self._shutting_down() {
+                return self._shut_down(conn);
+            } else if let Err(ParseError::UnexpectedEndOfInput) = cmd_result {
+                info!("Client went away without QUIT");
+                return SessionEnd::Closed;
             } else {
                 bytes_to_write.extend(format!("500 Error while parsing command: {:?}\r\n",
                                               cmd_result)
-                                          .bytes())
+                                          .bytes());
+                _flush_bytes(&bytes_to_write, conn);
             }
             let current_state = session_state.state();
 
             if current_state == SmtpState::Quit {
                 info!("Quitting now. Session will disconnect.");
-                let payload = session_state.extract_payload();
-                self.message_sender.send(payload);
-                return;
+                return SessionEnd::Closed;
+            }
+            match self._handle_state(&mut session_state, conn, session_deadline) {
+                Ok(()) => (),
+                Err(ParseError::Timeout) => return _time_out(conn),
+                Err(err) => {
+                    info!("Connection lost during DATA: {:?}", err);
+                    return SessionEnd::Closed;
+                }
+            }
+        }
+    }
+
+    fn _handle_state<C: Connection, S: SmtpStateMachine>(&self,
+                                                         state_machine: &mut S,
+                                                         conn: &mut C,
+                                                         session_deadline: Instant)
+                                                         -> Result<(), ParseError> {
+        match state_machine.state() {
+            SmtpState::DataInProgress => {
+                let data = try!(self._read_data(conn, session_deadline));
+                let response = self._accept_message(state_machine, data);
+                // the transaction is over either way (RFC 5321 section 4.1.1.4)
+                let _ = state_machine.transition(&Command::RESET);
+                _flush_bytes(&response.to_bytes(), conn);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads message data up to the final dot and undoes dot-stuffing
+    /// (RFC 5321 section 4.5.2).
+    fn _read_data<C: Connection>(&self,
+                                 conn: &mut C,
+                                 session_deadline: Instant)
+                                 -> Result<Vec<u8>, ParseError> {
+        let mut data = Vec::new();
+        let data_deadline = _deadline(self.timeouts.data_end, session_deadline);
+        loop {
+            let deadline = _deadline(self.timeouts.data_block, data_deadline);
+            let line = try!(read_line_bytes(&mut Deadline::new(conn, deadline)));
+            if line == b".\r\n" {
+                return Ok(data);
+            } else if line.starts_with(b".") {
+                data.extend(&line[1..]);
+            } else {
+                data.extend(line);
+            }
+        }
+    }
+
+    /// Hands the message to the sink. Only a successful commit is answered
+    /// with `250`, so the client keeps responsibility for the message until
+    /// it is safely stored.
+    fn _accept_message<S: SmtpStateMachine>(&self, state_machine: &mut S, data: Vec<u8>)
+                                            -> Response {
+        let hops = hop_count(&data);
+        if hops > self.max_hops {
+            warn!("Rejecting message after {} hops, probably a mail loop", hops);
+            return Response::new(554, "5.4.6 Too many hops");
+        }
+        let mut payload = state_machine.extract_payload();
+        payload.data = data;
+        payload.envelope.received_at = SystemTime::now();
+        if self.received_header {
+            let header = received_header(&payload.envelope, &payload.recipients, &self.hostname);
+            payload.prepend_header(&header);
+        }
+        let queue_id = payload.envelope.queue_id.clone();
+        info!("{}: received {} bytes from {:?}",
+              queue_id,
+              payload.data.len(),
+              payload.envelope.session.peer_addr);
+        match self.sink.commit(payload) {
+            Ok(()) => Response::new(250, &format!("2.0.0 Ok: queued as {}", queue_id)),
+            Err(SinkError::Temporary(reason)) => {
+                warn!("{}: deferred: {}", queue_id, reason);
+                Response::new(451, &format!("4.3.0 {}", reason))
+            }
+            Err(SinkError::InsufficientStorage) => {
+                warn!("{}: deferred, sink is full", queue_id);
+                Response::new(451, "4.3.1 Insufficient system storage")
+            }
+            Err(SinkError::Rejected(reason)) => {
+                warn!("{}: rejected: {}", queue_id, reason);
+                Response::new(554, &format!("5.3.0 {}", reason))
             }
-            _handle_state(&mut session_state, conn);
         }
     }
 }
 
-fn _handle_state<C: Read + Write, S: SmtpStateMachine>(state_machine: &mut S, conn: &mut C) {
-    let mut waiting_for_fullstop = false;
+/// A client connection whose reads can time out.
+pub trait Connection: Read + Write {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
 
-    fn is_str_equal(bytes: &[u8], string: &str) -> bool {
-        if bytes.len() != string.len() {
-            false;
+impl Connection for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+/// Lets reads from `conn` fail with `TimedOut` once `deadline` has passed,
+/// however slowly the client trickles in its bytes.
+struct Deadline<'a, C: 'a + Connection> {
+    conn: &'a mut C,
+    deadline: Instant,
+    shutdown: Option<&'a ShutdownHandle>,
+}
+
+impl<'a, C: Connection> Deadline<'a, C> {
+    fn new(conn: &'a mut C, deadline: Instant) -> Deadline<'a, C> {
+        Deadline {
+            conn: conn,
+            deadline: deadline,
+            shutdown: None,
         }
-        string.as_bytes() == bytes
     }
 
-    match state_machine.state() {
-        SmtpState::DataInProgress => {
-            let mut data = Vec::new();
-            loop {
-                let line_res = read_line_bytes(conn);
-                if !line_res.is_ok() {
-                    // TODO handle this better please!
-                    return;
-                }
-                let line = line_res.unwrap();
-                if !waiting_for_fullstop {
-                    data.extend(line);
-                    waiting_for_fullstop = true;
-                } else {
-                    if is_str_equal(&line, ".\r\n") {
-                        let payload = state_machine.get_payload_mut_ref();
-                        payload.data = data;
-                        return;
-                    } else {
-                        data.extend(line);
-                        waiting_for_fullstop = false;
-                    }
+    /// Also fails reads with `ConnectionAborted` once `shutdown` is
+    /// triggered, checking at least once a second.
+    fn interruptible(mut self, shutdown: Option<&'a ShutdownHandle>) -> Deadline<'a, C> {
+        self.shutdown = shutdown;
+        self
+    }
+}
+
+impl<'a, C: Connection> Read for Deadline<'a, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let now = Instant::now();
+            if now >= self.deadline {
+                return Err(io::Error::new(ErrorKind::TimedOut, "deadline passed"));
+            }
+            let mut timeout = self.deadline - now;
+            if let Some(shutdown) = self.shutdown {
+                if shutdown.is_shutting_down() {
+                    return Err(io::Error::new(ErrorKind::ConnectionAborted,
+                                              "server shutting down"));
                 }
+                timeout = min(timeout, Duration::from_secs(1));
+            }
+            try!(self.conn.set_read_timeout(Some(timeout)));
+            match self.conn.read(buf) {
+                Err(ref err) if self.shutdown.is_some() &&
+                                (err.kind() == ErrorKind::WouldBlock ||
+                                 err.kind() == ErrorKind::TimedOut) => continue,
+                result => return result,
             }
         }
-        _ => (),
+    }
+}
+
+impl<'a, C: Connection> Write for Deadline<'a, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.conn.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.conn.flush()
+    }
+}
+
+fn _deadline(timeout: Duration, session_deadline: Instant) -> Instant {
+    min(Instant::now() + timeout, session_deadline)
+}
+
+fn _time_out(conn: &mut Write) -> SessionEnd {
+    info!("Client timed out, closing session");
+    _flush_bytes(&Response::new(421, "4.4.2 Timeout").to_bytes(), conn);
+    SessionEnd::Closed
+}
+
+/// How a session handed back the connection.
+pub enum SessionEnd {
+    Closed,
+    /// The client was told to start TLS. The caller does the handshake and
+    /// continues with `resume_after_starttls`.
+    StartTls(SessionContext),
+}
+
+pub trait ConnectionHandler {
+    fn handle_connection<C: Connection>(&self, conn: &mut C, session: SessionContext)
+                                        -> SessionEnd;
+
+    /// Continues a session over the new TLS layer, without a greeting.
+    /// Everything learned before STARTTLS is forgotten (RFC 3207 section 4.2).
+    fn resume_after_starttls<C: Connection>(&self, conn: &mut C, session: SessionContext)
+                                            -> SessionEnd;
+}
+
+impl ConnectionHandler for DefaultConnectionHandler {
+    fn handle_connection<C: Connection>(&self, conn: &mut C, session: SessionContext)
+                                        -> SessionEnd {
+        debug!("Got connection from {:?}", session.peer_addr);
+        self._run_session(conn, session, true)
+    }
+
+    fn resume_after_starttls<C: Connection>(&self, conn: &mut C, mut session: SessionContext)
+                                            -> SessionEnd {
+        session.helo_name = None;
+        session.extended = false;
+        session.authenticated_user = None;
+        self._run_session(conn, session, false)
+    }
+}
+
+/// `matt@example.org` matches the login `matt@example.org` and, for logins
+/// without domain, `matt`.
+fn _sender_matches(sender: Option<&Address>, user: &str) -> bool {
+    let sender = match sender {
+        Some(sender) => sender,
+        None => return false,
+    };
+    match user.find('@') {
+        Some(_) => format!("{}", sender).eq_ignore_ascii_case(user),
+        None => sender.address == user,
     }
 }
 
@@ -161,15 +674,30 @@ fn _flush_bytes(bytes_to_write: &Vec<u8>, conn: &mut Write) {
 pub mod tests {
     use std::io;
     use std::io::{Read, Write};
-    use std::sync::mpsc::channel;
+    use std::sync::mpsc::{channel, Receiver};
     use std::cmp::max;
-    use smtp::{DefaultConnectionHandler, ConnectionHandler};
+    use std::sync::Arc;
+    use smtp::{DefaultConnectionHandler, ConnectionHandler, Connection, SessionEnd};
     use address::Address;
+    use std::time::{Duration, Instant};
+    use auth::{Authenticator, StaticCredentialStore};
+    use auth_throttle::{AuthThrottle, ThrottleConfig};
+    use session::{SessionContext, TlsInfo};
+    use envelope::BodyType;
+    use config::{ServerConfig, ListenerConfig, ListenerRole, Timeouts};
+    use shutdown::ShutdownHandle;
+    use sink::{MessageSink, ChannelSink, SinkError, bounded};
+    use payload::Payload;
+    use local::users::StaticUsers;
+    use std::thread::{sleep, spawn};
 
     struct MockStream {
         pub data_in: Vec<u8>,
         pub index: usize,
         pub data_out: Vec<u8>,
+        /// Whether reads past the end of `data_in` time out instead of
+        /// reporting end of input.
+        pub stalls: bool,
     }
 
     impl MockStream {
@@ -178,13 +706,21 @@ pub mod tests {
                 data_in: session_string.bytes().collect::<Vec<u8>>(),
                 index: 0,
                 data_out: Vec::new(),
+                stalls: false,
             }
         }
+
+        pub fn stalling_session(session_string: &str) -> MockStream {
+            MockStream { stalls: true, ..MockStream::new_session(session_string) }
+        }
     }
 
     impl Read for MockStream {
         fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
             if self.index >= self.data_in.len() {
+                if self.stalls {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data"));
+                }
                 return Ok(0);
             }
             let index_end = if self.index + buf.len() > self.data_in.len() {
@@ -216,24 +752,43 @@ pub mod tests {
         }
     }
 
+    impl Connection for MockStream {
+        fn set_read_timeout(&self, _: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn channel_sink() -> (Arc<MessageSink>, Receiver<Payload>) {
+        let (payload_tx, payload_rx) = channel();
+        (Arc::new(ChannelSink::new(payload_tx)), payload_rx)
+    }
+
+    struct FailingSink(SinkError);
+
+    impl MessageSink for FailingSink {
+        fn commit(&self, _: Payload) -> Result<(), SinkError> {
+            Err(self.0.clone())
+        }
+    }
+
     #[test]
     pub fn parses_basic_session() {
-        let (payload_tx, payload_rx) = channel();
-        let handler = DefaultConnectionHandler::new(payload_tx);
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
         let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
                                                   matt@localhost\r\nRCPT TO: \
                                                   marie@localhost\r\nDATA\r\nHi \
                                                   Marie\r\n.\r\nQUIT\r\n");
-        handler.handle_connection(&mut stream);
+        handler.handle_connection(&mut stream, SessionContext::new());
         assert!(payload_rx.try_recv().is_ok());
     }
 
 #[test]
     pub fn parses_basic_session_2() {
-        let (payload_tx, payload_rx) = channel();
-        let handler = DefaultConnectionHandler::new(payload_tx);
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink).with_received_header(false);
         let mut stream = MockStream::new_session("HELO antunovic.nz\r\nMAIL FROM: mate@antunovic.nz\r\nRCPT TO: just.mate.antunovic@gmail.com\r\nDATA\r\nHello, how are ya\r\n.\r\nQUIT\r\n");
-        handler.handle_connection(&mut stream);
+        handler.handle_connection(&mut stream, SessionContext::new());
         let mut string_bytes = Vec::new();
         stream.read_to_end(&mut string_bytes);
         let payload = payload_rx.try_recv().ok().unwrap();
@@ -243,4 +798,386 @@ pub mod tests {
         assert_eq!("Hello, how are ya\r\n".to_string().into_bytes(), payload.data);
     }
 
+    #[test]
+    pub fn advertises_auth_and_handles_failed_exchanges() {
+        let (sink, _payload_rx) = channel_sink();
+        let authenticator = Authenticator::new(Arc::new(StaticCredentialStore::new()));
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_authenticator(Arc::new(authenticator));
+        let mut stream = MockStream::new_session("EHLO localhost\r\nAUTH PLAIN\r\nAUTH \
+                                                  CRAM-MD5\r\n*\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains("250-Hello localhost\r\n250-8BITMIME\r\n250-DSN\r\n250 AUTH \
+                                  CRAM-MD5 SCRAM-SHA-256\r\n"));
+        assert!(session.contains("504 5.5.4 Unrecognized authentication type\r\n"));
+        assert!(session.contains("\r\n334 "));
+        assert!(session.contains("501 5.0.0 Authentication cancelled\r\n"));
+    }
+
+    #[test]
+    pub fn closes_connection_after_too_many_auth_failures() {
+        let (sink, _payload_rx) = channel_sink();
+        let throttle = AuthThrottle::new(ThrottleConfig {
+            delay_per_failure: Duration::from_secs(0),
+            max_failures_per_session: 2,
+            ..ThrottleConfig::default()
+        });
+        let authenticator = Authenticator::new(Arc::new(StaticCredentialStore::new()))
+                                .with_throttle(Arc::new(throttle));
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_authenticator(Arc::new(authenticator));
+        // "test" is not a valid CRAM-MD5 response
+        let mut stream = MockStream::new_session("EHLO localhost\r\nAUTH CRAM-MD5\r\ndGVzdA==\r\n\
+                                                  AUTH CRAM-MD5\r\ndGVzdA==\r\nNOOP\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains("535 5.7.8 Authentication credentials invalid\r\n"));
+        assert!(session.ends_with("421 4.7.0 Too many authentication failures, closing \
+                                   connection\r\n"));
+    }
+
+    #[test]
+    pub fn refuses_auth_retries_during_the_delay() {
+        let (sink, _payload_rx) = channel_sink();
+        let throttle = AuthThrottle::new(ThrottleConfig {
+            delay_per_failure: Duration::from_secs(10),
+            ..ThrottleConfig::default()
+        });
+        let authenticator = Authenticator::new(Arc::new(StaticCredentialStore::new()))
+                                .with_throttle(Arc::new(throttle));
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_authenticator(Arc::new(authenticator));
+        let session = SessionContext::from_addrs("192.0.2.1:4321".parse().unwrap(),
+                                                 "192.0.2.25:25".parse().unwrap());
+        let mut stream = MockStream::new_session("EHLO localhost\r\nAUTH CRAM-MD5\r\ndGVzdA==\r\n\
+                                                  AUTH CRAM-MD5\r\nQUIT\r\n");
+        let started = Instant::now();
+        handler.handle_connection(&mut stream, session);
+        // answered right away, without waiting for the delay
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains("535 5.7.8 Authentication credentials invalid\r\n454 4.7.0 Too \
+                                  many authentication failures, try again later\r\n221"));
+    }
+
+    #[test]
+    pub fn copies_session_into_payload() {
+        let (sink, payload_rx) = channel_sink();
+        let mut store = StaticCredentialStore::new();
+        store.add_password("matt", "secret");
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_authenticator(Arc::new(Authenticator::new(Arc::new(store))));
+        let mut session = SessionContext::from_addrs("192.0.2.1:4321".parse().unwrap(),
+                                                     "192.0.2.25:25".parse().unwrap());
+        session.tls = Some(TlsInfo::new());
+        // PLAIN credentials "\0matt\0secret"
+        let mut stream = MockStream::new_session("EHLO client.example\r\nAUTH PLAIN \
+                                                  AG1hdHQAc2VjcmV0\r\nMAIL FROM: \
+                                                  matt@localhost\r\nRCPT TO: \
+                                                  marie@localhost\r\nDATA\r\nHi \
+                                                  Marie\r\n.\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, session);
+
+        let session_out = String::from_utf8(stream.data_out).unwrap();
+        assert!(session_out.contains("250 AUTH CRAM-MD5 SCRAM-SHA-256 PLAIN LOGIN\r\n"));
+        assert!(session_out.contains("235 2.7.0 Authentication successful\r\n"));
+
+        let payload = payload_rx.try_recv().unwrap();
+        let session = payload.envelope.session;
+        assert_eq!(Some("192.0.2.1:4321".parse().unwrap()), session.peer_addr);
+        assert_eq!(Some("client.example".to_string()), session.helo_name);
+        assert!(session.extended);
+        assert!(session.is_tls());
+        assert_eq!(Some("matt".to_string()), session.authenticated_user);
+    }
+
+    #[test]
+    pub fn fills_in_envelope() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM:<matt@localhost> \
+                                                  BODY=8BITMIME\r\nRCPT \
+                                                  TO:<marie@localhost>\r\nRCPT \
+                                                  TO:<max@localhost> NOTIFY=NEVER\r\nDATA\r\nHi \
+                                                  all\r\n.\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+
+        let payload = payload_rx.try_recv().unwrap();
+        let envelope = payload.envelope;
+        assert!(envelope.queue_id.len() > 0);
+        assert_eq!(BodyType::EightBitMime, envelope.body_type);
+        assert_eq!(Some(Some("8BITMIME")), envelope.mail_parameters.get("body"));
+        assert_eq!(2, payload.recipients.len());
+        assert_eq!(2, envelope.recipient_parameters.len());
+        assert!(envelope.recipient_parameters[0].is_empty());
+        assert_eq!(Some(Some("NEVER")), envelope.recipient_parameters[1].get("NOTIFY"));
+    }
+
+    #[test]
+    pub fn accepts_null_reverse_path() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM:<>\r\nRCPT \
+                                                  TO:<matt@localhost>\r\nDATA\r\nBounce\r\n.\r\n\
+                                                  QUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        assert_eq!(None, payload_rx.try_recv().unwrap().sender);
+    }
+
+    #[test]
+    pub fn refuses_unknown_local_users() {
+        let (sink, payload_rx) = channel_sink();
+        let users = StaticUsers::new().with_mailbox("marie", "marie");
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_local_users(vec!["example.org".to_string()], Arc::new(users));
+        let mut stream = MockStream::new_session("EHLO localhost\r\nRCPT TO:<max@example.org>\r\n\
+                                                  MAIL FROM:<matt@localhost>\r\nRCPT \
+                                                  TO:<max@Example.ORG>\r\nRCPT \
+                                                  TO:<marie+lists@example.org>\r\nRCPT \
+                                                  TO:<max@example.net>\r\nDATA\r\nHi\r\n.\r\n\
+                                                  QUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        // not looked up out of sequence
+        assert_eq!(1, session.matches("550 5.1.1").count());
+        assert!(session.contains("550 5.1.1 <max@Example.ORG>: No such user here\r\n250 OK\r\n\
+                                  250 OK\r\n354"));
+        assert_eq!(vec![Address::new("marie+lists", "example.org"),
+                        Address::new("max", "example.net")],
+                   payload_rx.try_recv().unwrap().recipients);
+    }
+
+    #[test]
+    pub fn refuses_relaying_for_unauthenticated_clients() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_relay_domains(vec!["example.org".to_string()]);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM:<max@example.net>\r\n\
+                                                  RCPT TO:<marie@example.com>\r\nRCPT \
+                                                  TO:<marie@Example.ORG>\r\nDATA\r\nHi\r\n.\r\n\
+                                                  QUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains("554 5.7.1 Relay access denied\r\n250 OK\r\n354"));
+        assert_eq!(vec![Address::new("marie", "Example.ORG")],
+                   payload_rx.try_recv().unwrap().recipients);
+
+        let mut session = SessionContext::new();
+        session.authenticated_user = Some("max".to_string());
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM:<max@example.net>\r\n\
+                                                  RCPT TO:<marie@example.com>\r\nDATA\r\nHi\r\n.\
+                                                  \r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, session);
+        assert_eq!(vec![Address::new("marie", "example.com")],
+                   payload_rx.try_recv().unwrap().recipients);
+    }
+
+    #[test]
+    pub fn prepends_received_header() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
+        let session = SessionContext::from_addrs("192.0.2.1:4321".parse().unwrap(),
+                                                 "192.0.2.25:25".parse().unwrap());
+        let mut stream = MockStream::new_session("EHLO client.example\r\nMAIL FROM: \
+                                                  matt@localhost\r\nRCPT TO: \
+                                                  marie@localhost\r\nDATA\r\nHi \
+                                                  Marie\r\n.\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, session);
+
+        let payload = payload_rx.try_recv().unwrap();
+        let data = String::from_utf8(payload.data).unwrap();
+        let expected = format!("Received: from client.example (unknown [192.0.2.1])\r\n\tby \
+                                localhost (rust-smtp) with ESMTP id {}\r\n\tfor \
+                                <marie@localhost>;\r\n\t",
+                               payload.envelope.queue_id);
+        assert!(data.starts_with(&expected));
+        assert!(data.ends_with("\r\nHi Marie\r\n"));
+    }
+
+    #[test]
+    pub fn greets_with_configured_hostname() {
+        let (sink, _payload_rx) = channel_sink();
+        let mut config = ServerConfig::default();
+        config.hostname = "mx.example.org".to_string();
+        config.banner = "Mail Service".to_string();
+        let handler = DefaultConnectionHandler::with_config(sink, &config);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.starts_with("220 mx.example.org ESMTP Mail Service\r\n"));
+    }
+
+    #[test]
+    pub fn rejects_looping_message() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink).with_max_hops(2);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nRCPT TO: \
+                                                  marie@localhost\r\nDATA\r\nReceived: \
+                                                  a\r\nReceived: b\r\nDelivered-To: \
+                                                  marie@localhost\r\n\r\nHi \
+                                                  Marie\r\n.\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains("554 5.4.6 Too many hops\r\n"));
+        assert!(payload_rx.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn submission_requires_auth_and_matching_sender() {
+        let (sink, _payload_rx) = channel_sink();
+        let mut store = StaticCredentialStore::new();
+        store.add_password("matt", "secret");
+        let listener = ListenerConfig::new("0.0.0.0:587".parse().unwrap(),
+                                           ListenerRole::Submission);
+        let handler = DefaultConnectionHandler::new(sink)
+                          .with_authenticator(Arc::new(Authenticator::new(Arc::new(store))))
+                          .with_listener(&listener);
+        let mut session = SessionContext::new();
+        session.tls = Some(TlsInfo::new());
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nAUTH PLAIN \
+                                                  AG1hdHQAc2VjcmV0\r\nMAIL FROM: \
+                                                  marie@localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, session);
+
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(!session.contains("STARTTLS"));
+        assert!(session.contains("530 5.7.0 Authentication required\r\n235 "));
+        assert!(session.contains("553 5.7.1 Sender address not owned by user matt\r\n250 OK\r\n"));
+    }
+
+    #[test]
+    pub fn hands_connection_back_for_starttls() {
+        let (sink, _payload_rx) = channel_sink();
+        let listener = ListenerConfig::new("0.0.0.0:587".parse().unwrap(),
+                                           ListenerRole::Submission);
+        let handler = DefaultConnectionHandler::new(sink).with_listener(&listener);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nSTARTTLS\r\n");
+        let session = match handler.handle_connection(&mut stream, SessionContext::new()) {
+            SessionEnd::StartTls(session) => session,
+            SessionEnd::Closed => panic!("expected STARTTLS"),
+        };
+        let output = String::from_utf8(stream.data_out).unwrap();
+        assert!(output.contains("250-8BITMIME\r\n250-DSN\r\n250 STARTTLS\r\n"));
+        assert!(output.contains("530 5.7.0 Must issue a STARTTLS command first\r\n"));
+        assert!(output.ends_with("220 2.0.0 Ready to start TLS\r\n"));
+
+        let mut session = session;
+        session.tls = Some(TlsInfo::new());
+        let mut stream = MockStream::new_session("EHLO localhost\r\nSTARTTLS\r\nQUIT\r\n");
+        handler.resume_after_starttls(&mut stream, session);
+        let output = String::from_utf8(stream.data_out).unwrap();
+        assert!(output.starts_with("250-Hello localhost\r\n250-8BITMIME\r\n250 DSN\r\n"));
+        assert!(output.contains("502 5.5.1 STARTTLS not available\r\n"));
+    }
+
+    #[test]
+    pub fn times_out_idle_clients() {
+        let (sink, _payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
+        let mut stream = MockStream::stalling_session("");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        assert!(String::from_utf8(stream.data_out).unwrap().ends_with("421 4.4.2 Timeout\r\n"));
+
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink);
+        let mut stream = MockStream::stalling_session("EHLO localhost\r\nMAIL FROM: \
+                                                       matt@localhost\r\nRCPT TO: \
+                                                       marie@localhost\r\nDATA\r\nHi\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        assert!(String::from_utf8(stream.data_out).unwrap().ends_with("421 4.4.2 Timeout\r\n"));
+        assert!(payload_rx.try_recv().is_err());
+    }
+
+    #[test]
+    pub fn limits_session_duration() {
+        let (sink, _payload_rx) = channel_sink();
+        let timeouts = Timeouts { session: Duration::from_secs(0), ..Timeouts::default() };
+        let handler = DefaultConnectionHandler::new(sink).with_timeouts(timeouts);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let output = String::from_utf8(stream.data_out).unwrap();
+        assert!(output.starts_with("220 "));
+        assert!(output.ends_with("421 4.4.2 Timeout\r\n"));
+        assert!(!output.contains("250"));
+    }
+
+    #[test]
+    pub fn closes_idle_sessions_on_shutdown() {
+        let (sink, _payload_rx) = channel_sink();
+        let shutdown = ShutdownHandle::new();
+        let handler = DefaultConnectionHandler::new(sink).with_shutdown(shutdown.clone());
+        shutdown.shutdown();
+        let mut stream = MockStream::new_session("EHLO localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        assert_eq!("421 4.3.2 localhost Service shutting down\r\n",
+                   String::from_utf8(stream.data_out).unwrap());
+
+        let (sink, payload_rx) = channel_sink();
+        let shutdown = ShutdownHandle::new();
+        let handler = DefaultConnectionHandler::new(sink).with_shutdown(shutdown.clone());
+        let mut stream = MockStream::stalling_session("EHLO localhost\r\nMAIL FROM: \
+                                                       matt@localhost\r\nRCPT TO: \
+                                                       marie@localhost\r\nDATA\r\nHi \
+                                                       Marie\r\n.\r\n");
+        let trigger = shutdown.clone();
+        spawn(move || {
+            sleep(Duration::from_millis(50));
+            trigger.shutdown();
+        });
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let output = String::from_utf8(stream.data_out).unwrap();
+        assert!(output.ends_with("421 4.3.2 localhost Service shutting down\r\n"));
+        assert!(payload_rx.try_recv().is_ok());
+    }
+
+    #[test]
+    pub fn replies_with_the_sink_result() {
+        let (sink, payload_rx) = channel_sink();
+        let handler = DefaultConnectionHandler::new(sink).with_received_header(false);
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nRCPT TO: \
+                                                  marie@localhost\r\nDATA\r\nHi\r\n..\r\n\
+                                                  .\r\nMAIL FROM: matt@localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let payload = payload_rx.try_recv().unwrap();
+        assert_eq!(b"Hi\r\n.\r\n".to_vec(), payload.data);
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.contains(&format!("250 2.0.0 Ok: queued as {}\r\n250 OK\r\n221",
+                                          payload.envelope.queue_id)));
+        assert!(payload_rx.try_recv().is_err());
+
+        for &(ref error, reply) in &[(SinkError::Temporary("Disk full".to_string()),
+                                      "451 4.3.0 Disk full\r\n"),
+                                     (SinkError::Rejected("Spam".to_string()),
+                                      "554 5.3.0 Spam\r\n")] {
+            let handler = DefaultConnectionHandler::new(Arc::new(FailingSink(error.clone())));
+            let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                      matt@localhost\r\nRCPT TO: \
+                                                      marie@localhost\r\nDATA\r\nHi\r\n\
+                                                      .\r\nQUIT\r\n");
+            handler.handle_connection(&mut stream, SessionContext::new());
+            let session = String::from_utf8(stream.data_out).unwrap();
+            assert!(session.ends_with(&format!("{}221 Bye\r\n", reply)));
+        }
+    }
+
+    #[test]
+    pub fn defers_transactions_while_sink_is_full() {
+        let (sink, receiver) = bounded(1);
+        let handler = DefaultConnectionHandler::new(Arc::new(sink));
+        let mut stream = MockStream::new_session("EHLO localhost\r\nMAIL FROM: \
+                                                  matt@localhost\r\nRCPT TO: \
+                                                  marie@localhost\r\nDATA\r\nHi\r\n\
+                                                  .\r\nMAIL FROM: matt@localhost\r\nQUIT\r\n");
+        handler.handle_connection(&mut stream, SessionContext::new());
+        let session = String::from_utf8(stream.data_out).unwrap();
+        assert!(session.ends_with("451 4.3.1 Insufficient system storage\r\n221 Bye\r\n"));
+        assert!(receiver.recv().is_some());
+    }
 }
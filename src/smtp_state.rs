@@ -1,6 +1,8 @@
 use data::Command;
 use response::Response;
 use payload::Payload;
+use session::SessionContext;
+use envelope::{Envelope, BodyType};
 use std::mem;
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -20,8 +22,10 @@ pub enum SmtpError {
 pub const OK: u16 = 250;
 
 pub trait SmtpStateMachine {
-    fn new() -> Self;
+    fn new(session: SessionContext) -> Self;
     fn state(&self) -> SmtpState;
+    fn session(&self) -> &SessionContext;
+    fn session_mut(&mut self) -> &mut SessionContext;
     fn transition(&mut self, cmd: &Command) -> Result<Response, SmtpError>;
     fn extract_payload(&mut self) -> Payload;
     fn get_payload_mut_ref<'a>(&'a mut self) -> &'a mut Payload;
@@ -30,14 +34,16 @@ pub trait SmtpStateMachine {
 pub struct DefaultStateMachine {
     state: SmtpState,
     current_payload: Payload,
+    session: SessionContext,
 }
 
 
 impl SmtpStateMachine for DefaultStateMachine {
-    fn new() -> DefaultStateMachine {
+    fn new(session: SessionContext) -> DefaultStateMachine {
         DefaultStateMachine {
             state: SmtpState::Start,
             current_payload: Payload::new(),
+            session: session,
         }
     }
 
@@ -45,15 +51,39 @@ impl SmtpStateMachine for DefaultStateMachine {
         self.state
     }
 
+    fn session(&self) -> &SessionContext {
+        &self.session
+    }
+
+    fn session_mut(&mut self) -> &mut SessionContext {
+        &mut self.session
+    }
+
     fn transition(&mut self, cmd: &Command) -> Result<Response, SmtpError> {
         match (self.state, cmd) {
-            (SmtpState::Start, &Command::MAIL_FROM(ref sender)) => {
-                self.current_payload.sender = Some(sender.clone());
+            (SmtpState::Start, &Command::MAIL_FROM(ref sender, ref parameters)) => {
+                let body_type = match parameters.get("BODY") {
+                    None => BodyType::SevenBit,
+                    Some(value) => {
+                        match value.and_then(BodyType::from_parameter) {
+                            Some(body_type) => body_type,
+                            None => return Ok(Response::new(555, "5.5.4 Unsupported BODY type")),
+                        }
+                    }
+                };
+                let mut envelope = Envelope::new();
+                envelope.session = self.session.clone();
+                envelope.mail_parameters = parameters.clone();
+                envelope.body_type = body_type;
+                self.current_payload.sender = sender.clone();
+                self.current_payload.envelope = envelope;
                 self.state = SmtpState::ReadyForRecptTo;
                 Ok(Response::new(OK, "OK"))
             }
-            (SmtpState::ReadyForRecptTo, &Command::RCPT_TO(ref recipient)) => {
+            (SmtpState::ReadyForRecptTo, &Command::RCPT_TO(ref recipient, ref parameters)) |
+            (SmtpState::ReadyForData, &Command::RCPT_TO(ref recipient, ref parameters)) => {
                 self.current_payload.recipients.push(recipient.clone());
+                self.current_payload.envelope.recipient_parameters.push(parameters.clone());
                 self.state = SmtpState::ReadyForData;
                 Ok(Response::new(OK, "OK"))
             }
@@ -0,0 +1,1133 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use libc;
+use address::Address;
+use config::QueueConfig;
+use data::Parameters;
+use dsn::{Action, DeliveryReport, RecipientReport, status_code};
+use envelope::{BodyType, Envelope};
+use payload::Payload;
+use payload_handler::{Transport, RecipientStatus};
+use shutdown::ShutdownHandle;
+use sink::{MessageSink, SinkError};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecipientState {
+    /// Not delivered yet, will be tried again.
+    Pending,
+    Delivered,
+    /// Refused for good or given up on.
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedRecipient {
+    pub address: Address,
+    /// Parameters of its RCPT TO, e.g. `NOTIFY=FAILURE`.
+    pub parameters: Parameters,
+    pub state: RecipientState,
+    /// What the last server to see this recipient said about it.
+    pub last_reply: Option<String>,
+}
+
+/// The envelope of a spooled message and how its delivery is going.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueuedMessage {
+    /// The queue id the message was accepted with.
+    pub id: String,
+    pub sender: Option<Address>,
+    pub mail_parameters: Parameters,
+    pub recipients: Vec<QueuedRecipient>,
+    pub created_at: SystemTime,
+    /// Delivery attempts made so far.
+    pub attempts: u32,
+    pub next_attempt: SystemTime,
+    /// Until when the message is being delivered, see `QueueStore::lease`.
+    pub leased_until: Option<SystemTime>,
+    /// Put on hold by the operator, not delivered until released.
+    pub held: bool,
+    /// Why the last attempt failed as a whole, e.g. `connection refused`.
+    pub last_error: Option<String>,
+}
+
+impl QueuedMessage {
+    /// A message due for delivery at once.
+    pub fn new(payload: &Payload) -> QueuedMessage {
+        let envelope = &payload.envelope;
+        // the spool keeps whole seconds
+        let created_at = UNIX_EPOCH + Duration::from_secs(_seconds(envelope.received_at));
+        let recipients = payload.recipients
+                                .iter()
+                                .enumerate()
+                                .map(|(i, address)| {
+                                    QueuedRecipient {
+                                        address: address.clone(),
+                                        parameters: envelope.recipient_parameters
+                                                            .get(i)
+                                                            .cloned()
+                                                            .unwrap_or(Parameters::new()),
+                                        state: RecipientState::Pending,
+                                        last_reply: None,
+                                    }
+                                })
+                                .collect();
+        QueuedMessage {
+            id: envelope.queue_id.clone(),
+            sender: payload.sender.clone(),
+            mail_parameters: envelope.mail_parameters.clone(),
+            recipients: recipients,
+            created_at: created_at,
+            attempts: 0,
+            next_attempt: created_at,
+            leased_until: None,
+            held: false,
+            last_error: None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.recipients.iter().any(|r| r.state == RecipientState::Pending)
+    }
+
+    /// A payload with `data` for the recipients still pending.
+    pub fn payload(&self, data: Vec<u8>) -> Payload {
+        let mut envelope = Envelope::new();
+        envelope.queue_id = self.id.clone();
+        envelope.received_at = self.created_at;
+        envelope.mail_parameters = self.mail_parameters.clone();
+        envelope.body_type = self.mail_parameters
+                                 .get("BODY")
+                                 .and_then(|value| value.and_then(BodyType::from_parameter))
+                                 .unwrap_or(BodyType::SevenBit);
+        let mut payload = Payload::new();
+        payload.sender = self.sender.clone();
+        payload.data = data;
+        for recipient in self.recipients.iter().filter(|r| r.state == RecipientState::Pending) {
+            payload.add_recipient(recipient.address.clone());
+            envelope.recipient_parameters.push(recipient.parameters.clone());
+        }
+        payload.envelope = envelope;
+        payload
+    }
+
+    fn _to_text(&self) -> String {
+        let mut text = String::new();
+        text.push_str(&format!("id: {}\n", self.id));
+        text.push_str(&format!("created: {}\n", _seconds(self.created_at)));
+        text.push_str(&format!("attempts: {}\n", self.attempts));
+        text.push_str(&format!("next-attempt: {}\n", _seconds(self.next_attempt)));
+        if let Some(leased_until) = self.leased_until {
+            text.push_str(&format!("leased-until: {}\n", _seconds(leased_until)));
+        }
+        if self.held {
+            text.push_str("held: yes\n");
+        }
+        text.push_str(&format!("sender: {}\n",
+                               self.sender.as_ref().map_or(String::new(), |s| s.to_string())));
+        text.push_str(&format!("mail-parameters: {}\n", _format_parameters(&self.mail_parameters)));
+        if let Some(ref error) = self.last_error {
+            text.push_str(&format!("last-error: {}\n", _one_line(error)));
+        }
+        for recipient in self.recipients.iter() {
+            text.push_str(&format!("recipient: {}", recipient.address));
+            if !recipient.parameters.is_empty() {
+                text.push_str(&format!(" {}", _format_parameters(&recipient.parameters)));
+            }
+            text.push('\n');
+            text.push_str(&format!("state: {}\n", _state_name(recipient.state)));
+            if let Some(ref reply) = recipient.last_reply {
+                text.push_str(&format!("reply: {}\n", _one_line(reply)));
+            }
+        }
+        text
+    }
+
+    fn _from_text(text: &str) -> Result<QueuedMessage, ()> {
+        let mut message = QueuedMessage {
+            id: String::new(),
+            sender: None,
+            mail_parameters: Parameters::new(),
+            recipients: Vec::new(),
+            created_at: UNIX_EPOCH,
+            attempts: 0,
+            next_attempt: UNIX_EPOCH,
+            leased_until: None,
+            held: false,
+            last_error: None,
+        };
+        for line in text.lines() {
+            let mut parts = line.splitn(2, ": ");
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key, value),
+                (Some(key), None) if key.ends_with(':') => (&key[..key.len() - 1], ""),
+                _ => return Err(()),
+            };
+            match key {
+                "id" => message.id = value.to_string(),
+                "created" => message.created_at = try!(_time(value).ok_or(())),
+                "attempts" => message.attempts = try!(value.parse().map_err(|_| ())),
+                "next-attempt" => message.next_attempt = try!(_time(value).ok_or(())),
+                "leased-until" => message.leased_until = Some(try!(_time(value).ok_or(()))),
+                "held" => message.held = value == "yes",
+                "sender" if value.is_empty() => message.sender = None,
+                "sender" => message.sender = Some(try!(_parse_address(value).ok_or(()))),
+                "mail-parameters" => message.mail_parameters = _parse_parameters(value),
+                "last-error" => message.last_error = Some(value.to_string()),
+                "recipient" => {
+                    let mut parts = value.splitn(2, ' ');
+                    let address = try!(parts.next().and_then(_parse_address).ok_or(()));
+                    message.recipients.push(QueuedRecipient {
+                        address: address,
+                        parameters: _parse_parameters(parts.next().unwrap_or("")),
+                        state: RecipientState::Pending,
+                        last_reply: None,
+                    });
+                }
+                "state" => {
+                    try!(message.recipients.last_mut().ok_or(())).state =
+                        try!(_parse_state(value).ok_or(()));
+                }
+                "reply" => {
+                    let recipient = try!(message.recipients.last_mut().ok_or(()));
+                    recipient.last_reply = Some(value.to_string());
+                }
+                _ => return Err(()),
+            }
+        }
+        if message.id.is_empty() || message.recipients.is_empty() {
+            return Err(());
+        }
+        Ok(message)
+    }
+}
+
+/// Where queued messages are kept between delivery attempts. `enqueue` must
+/// not return before the message is durable, as the client is told it was
+/// accepted right after. Unknown ids are reported as `NotFound`.
+pub trait QueueStore: Send + Sync {
+    /// Stores `payload` with all recipients pending, due at once.
+    fn enqueue(&self, payload: &Payload) -> io::Result<QueuedMessage>;
+
+    /// All messages in the store, oldest first.
+    fn messages(&self) -> io::Result<Vec<QueuedMessage>>;
+
+    fn get(&self, id: &str) -> io::Result<QueuedMessage>;
+
+    /// The message data, as received.
+    fn data(&self, id: &str) -> io::Result<Vec<u8>>;
+
+    /// Takes the oldest message that is due at `now`, not held and not leased, and
+    /// keeps it from being leased again before `now + duration`. A lease
+    /// that is neither deferred nor removed expires, so the message is not
+    /// lost if its delivery crashes.
+    fn lease(&self, now: SystemTime, duration: Duration) -> io::Result<Option<QueuedMessage>>;
+
+    /// Records what became of the `index`th recipient of message `id`.
+    fn set_recipient_state(&self,
+                           id: &str,
+                           index: usize,
+                           state: RecipientState,
+                           reply: Option<&str>)
+                           -> io::Result<()>;
+
+    /// Counts another attempt at message `id`, records why the attempt
+    /// failed as a whole, if it did, and releases the lease. The message is
+    /// not due before `until`.
+    fn defer_until(&self, id: &str, until: SystemTime, error: Option<&str>) -> io::Result<()>;
+
+    /// Makes message `id` due at `at`, without counting an attempt.
+    fn reschedule(&self, id: &str, at: SystemTime) -> io::Result<()>;
+
+    /// Holds message `id` back from delivery, or releases it.
+    fn set_held(&self, id: &str, held: bool) -> io::Result<()>;
+
+    fn remove(&self, id: &str) -> io::Result<()>;
+
+    /// When the next message that is not held becomes due or its lease
+    /// expires.
+    fn next_due(&self) -> io::Result<Option<SystemTime>> {
+        Ok(try!(self.messages())
+               .iter()
+               .filter(|message| !message.held)
+               .map(|message| {
+                   match message.leased_until {
+                       Some(leased_until) if leased_until > message.next_attempt => leased_until,
+                       _ => message.next_attempt,
+                   }
+               })
+               .min())
+    }
+
+    /// Whether messages were added or changed since the last call, by this
+    /// process or another, so the scheduler need not look for them
+    /// otherwise. Stores that cannot tell say `true`.
+    fn take_changes(&self) -> bool {
+        true
+    }
+}
+
+/// Accepted messages on disk, one `<id>.msg` file with the data and one
+/// `<id>.env` file with the envelope and delivery state each. Both are
+/// written to a temporary file, synced and renamed into place, so a crash
+/// leaves either the old or the new version behind. The data is written
+/// before the envelope and removed after it, so every envelope has its data.
+///
+/// Changes to envelopes hold an exclusive lock on `spool.lock`, so other
+/// processes, e.g. `smtp queue`, may change the spool while the server runs.
+pub struct Spool {
+    directory: PathBuf,
+    new_messages: AtomicBool,
+    /// When the directory was last seen changed, to notice other processes
+    /// changing envelopes.
+    seen_modified: Mutex<Option<SystemTime>>,
+}
+
+impl Spool {
+    /// Opens the spool in `directory`, creating it if needed, and cleans up
+    /// after writes that were interrupted.
+    pub fn open<P: Into<PathBuf>>(directory: P) -> io::Result<Spool> {
+        let directory = directory.into();
+        try!(fs::create_dir_all(&directory));
+        // waits for messages being enqueued by other processes
+        let _lock = try!(_lock(&directory, true));
+        for entry in try!(fs::read_dir(&directory)) {
+            let path = try!(entry).path();
+            let orphan = match path.extension().and_then(|e| e.to_str()) {
+                Some("tmp") => true,
+                Some("msg") => !path.with_extension("env").exists(),
+                _ => false,
+            };
+            if orphan {
+                try!(fs::remove_file(&path));
+            }
+        }
+        Ok(Spool {
+            directory: directory,
+            new_messages: AtomicBool::new(true),
+            seen_modified: Mutex::new(None),
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    fn _ids(&self) -> io::Result<Vec<String>> {
+        let mut ids = Vec::new();
+        for entry in try!(fs::read_dir(&self.directory)) {
+            let path = try!(entry).path();
+            if path.extension().and_then(|e| e.to_str()) == Some("env") {
+                if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                    ids.push(id.to_string());
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+
+    /// Reads, changes and writes back the envelope of message `id`.
+    fn _modify<F>(&self, id: &str, change: F) -> io::Result<()>
+        where F: FnOnce(&mut QueuedMessage)
+    {
+        let _lock = try!(_lock(&self.directory, true));
+        let mut message = try!(self.get(id));
+        change(&mut message);
+        try!(self._write(id, "env", message._to_text().as_bytes()));
+        self._sync_directory()
+    }
+
+    fn _path(&self, id: &str, extension: &str) -> io::Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("invalid queue id {:?}", id)));
+        }
+        Ok(self.directory.join(format!("{}.{}", id, extension)))
+    }
+
+    fn _write(&self, id: &str, extension: &str, contents: &[u8]) -> io::Result<()> {
+        let path = try!(self._path(id, extension));
+        let tmp_path = path.with_extension(format!("{}.tmp", extension));
+        let mut file = try!(File::create(&tmp_path));
+        try!(file.write_all(contents));
+        try!(file.sync_all());
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Renames the files of message `id` to `<id>.env.bad` and
+    /// `<id>.msg.bad`, so that it no longer holds up the queue but can still
+    /// be looked at.
+    fn _set_aside(&self, id: &str, err: &io::Error) {
+        error!("{}: setting aside unreadable envelope: {}", id, err);
+        for extension in &["env", "msg"] {
+            let path = self.directory.join(format!("{}.{}", id, extension));
+            match fs::rename(&path, self.directory.join(format!("{}.{}.bad", id, extension))) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => error!("{}: could not set {} aside: {}", id, path.display(), err),
+                Ok(()) => (),
+            }
+        }
+    }
+
+    /// Makes renames and removals durable.
+    fn _sync_directory(&self) -> io::Result<()> {
+        File::open(&self.directory).and_then(|directory| directory.sync_all())
+    }
+}
+
+impl QueueStore for Spool {
+    fn enqueue(&self, payload: &Payload) -> io::Result<QueuedMessage> {
+        let message = QueuedMessage::new(payload);
+        let _lock = try!(_lock(&self.directory, false));
+        try!(self._write(&message.id, "msg", &payload.data));
+        try!(self._write(&message.id, "env", message._to_text().as_bytes()));
+        try!(self._sync_directory());
+        self.new_messages.store(true, Ordering::SeqCst);
+        Ok(message)
+    }
+
+    fn messages(&self) -> io::Result<Vec<QueuedMessage>> {
+        let mut messages = Vec::new();
+        for id in try!(self._ids()) {
+            match self.get(&id) {
+                Ok(message) => messages.push(message),
+                // removed since the directory was read
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                // one broken envelope must not hide the other messages
+                Err(err) => error!("{}: skipping unreadable envelope: {}", id, err),
+            }
+        }
+        Ok(messages)
+    }
+
+    fn get(&self, id: &str) -> io::Result<QueuedMessage> {
+        let mut text = String::new();
+        try!(try!(File::open(try!(self._path(id, "env")))).read_to_string(&mut text));
+        match QueuedMessage::_from_text(&text) {
+            Ok(ref message) if message.id != id => {
+                Err(io::Error::new(io::ErrorKind::InvalidData,
+                                   format!("{}: envelope is for {}", id, message.id)))
+            }
+            Ok(message) => Ok(message),
+            Err(()) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{}: malformed envelope", id))),
+        }
+    }
+
+    fn data(&self, id: &str) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        try!(try!(File::open(try!(self._path(id, "msg")))).read_to_end(&mut data));
+        Ok(data)
+    }
+
+    fn lease(&self, now: SystemTime, duration: Duration) -> io::Result<Option<QueuedMessage>> {
+        let _lock = try!(_lock(&self.directory, true));
+        for id in try!(self._ids()) {
+            let mut message = match self.get(&id) {
+                Ok(message) => message,
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    self._set_aside(&id, &err);
+                    continue;
+                }
+            };
+            if message.next_attempt > now || message.held ||
+               message.leased_until.map_or(false, |l| l > now) {
+                continue;
+            }
+            message.leased_until = Some(now + duration);
+            try!(self._write(&id, "env", message._to_text().as_bytes()));
+            try!(self._sync_directory());
+            return Ok(Some(message));
+        }
+        Ok(None)
+    }
+
+    fn set_recipient_state(&self,
+                           id: &str,
+                           index: usize,
+                           state: RecipientState,
+                           reply: Option<&str>)
+                           -> io::Result<()> {
+        let mut found = false;
+        try!(self._modify(id, |message| {
+            if let Some(recipient) = message.recipients.get_mut(index) {
+                recipient.state = state;
+                recipient.last_reply = reply.map(|r| r.to_string());
+                found = true;
+            }
+        }));
+        if found {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::NotFound,
+                               format!("{}: no recipient {}", id, index)))
+        }
+    }
+
+    fn defer_until(&self, id: &str, until: SystemTime, error: Option<&str>) -> io::Result<()> {
+        self._modify(id, |message| {
+            message.attempts += 1;
+            message.next_attempt = until;
+            message.leased_until = None;
+            message.last_error = error.map(|e| e.to_string());
+        })
+    }
+
+    fn reschedule(&self, id: &str, at: SystemTime) -> io::Result<()> {
+        self._modify(id, |message| message.next_attempt = at)
+    }
+
+    fn set_held(&self, id: &str, held: bool) -> io::Result<()> {
+        self._modify(id, |message| message.held = held)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        let _lock = try!(_lock(&self.directory, true));
+        try!(fs::remove_file(try!(self._path(id, "env"))));
+        match fs::remove_file(try!(self._path(id, "msg"))) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            other => try!(other),
+        }
+        self._sync_directory()
+    }
+
+    fn take_changes(&self) -> bool {
+        // envelopes are renamed into place, which touches the directory
+        let modified = fs::metadata(&self.directory).and_then(|m| m.modified()).ok();
+        let mut seen_modified = self.seen_modified.lock().unwrap();
+        let changed = modified.is_none() || *seen_modified != modified;
+        *seen_modified = modified;
+        self.new_messages.swap(false, Ordering::SeqCst) || changed
+    }
+}
+
+/// Locks the spool in `directory` until the returned file is closed. Shared
+/// locks are for adding messages, exclusive ones for changing or cleaning
+/// up after them.
+fn _lock(directory: &Path, exclusive: bool) -> io::Result<File> {
+    let file = try!(OpenOptions::new().create(true).write(true).open(directory.join("spool.lock")));
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Puts accepted messages into a queue store.
+pub struct QueueSink {
+    store: Arc<QueueStore>,
+}
+
+impl QueueSink {
+    pub fn new(store: Arc<QueueStore>) -> QueueSink {
+        QueueSink { store: store }
+    }
+}
+
+impl MessageSink for QueueSink {
+    fn commit(&self, payload: Payload) -> Result<(), SinkError> {
+        match self.store.enqueue(&payload) {
+            Ok(_) => {
+                info!("{}: queued for {} recipients",
+                      payload.envelope.queue_id,
+                      payload.recipients.len());
+                Ok(())
+            }
+            Err(err) => {
+                error!("{}: could not queue: {}", payload.envelope.queue_id, err);
+                Err(SinkError::Temporary("Could not queue message".to_string()))
+            }
+        }
+    }
+}
+
+/// Delivers queued messages when they are due, and retries those that
+/// could not be delivered with exponential backoff until they expire.
+/// Senders are told about failed recipients with a delivery status
+/// notification, which is queued like any other message.
+pub struct Scheduler {
+    store: Arc<QueueStore>,
+    transport: Arc<Transport>,
+    config: QueueConfig,
+    hostname: String,
+}
+
+impl Scheduler {
+    pub fn new(store: Arc<QueueStore>, transport: Arc<Transport>, config: QueueConfig) -> Scheduler {
+        Scheduler {
+            store: store,
+            transport: transport,
+            config: config,
+            hostname: "localhost".to_string(),
+        }
+    }
+
+    /// Our name in delivery status notifications.
+    pub fn with_hostname(mut self, hostname: &str) -> Scheduler {
+        self.hostname = hostname.to_string();
+        self
+    }
+
+    /// Makes an attempt at every message due at `now`. Returns when the next
+    /// message is due, if any is left.
+    pub fn run_due(&self, now: SystemTime) -> io::Result<Option<SystemTime>> {
+        // longer than any attempt may take, see `PayloadHandler`
+        let lease = Duration::from_secs(60 * 60);
+        while let Some(message) = try!(self.store.lease(now, lease)) {
+            try!(self.attempt(message, now));
+        }
+        self.store.next_due()
+    }
+
+    /// Tries the pending recipients of `message` once and records the
+    /// outcome in the store.
+    pub fn attempt(&self, message: QueuedMessage, now: SystemTime) -> io::Result<()> {
+        let id = message.id.clone();
+        match self._attempt(message, now) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                info!("{}: removed from the queue during delivery", id);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn _attempt(&self, message: QueuedMessage, now: SystemTime) -> io::Result<()> {
+        let mut message = message;
+        let payload = message.payload(try!(self.store.data(&message.id)));
+        let pending = message.recipients
+                             .iter()
+                             .enumerate()
+                             .filter(|&(_, r)| r.state == RecipientState::Pending)
+                             .map(|(i, _)| i)
+                             .collect::<Vec<_>>();
+        let mut error = None;
+        // recipients to notify the sender about, with the action and the
+        // status to report if the reply has none
+        let mut reports = Vec::new();
+        match self.transport.deliver(&payload) {
+            Ok(statuses) => {
+                for (&i, (address, status)) in pending.iter().zip(statuses) {
+                    let (state, reply) = match status {
+                        RecipientStatus::Delivered => {
+                            info!("{}: delivered to {}", message.id, address);
+                            reports.push((i, Action::Delivered, "2.0.0"));
+                            (RecipientState::Delivered, None)
+                        }
+                        RecipientStatus::Relayed => {
+                            info!("{}: relayed to {}", message.id, address);
+                            reports.push((i, Action::Relayed, "2.0.0"));
+                            (RecipientState::Delivered, None)
+                        }
+                        // the next server reports from now on
+                        RecipientStatus::RelayedWithDsn => {
+                            info!("{}: relayed to {}", message.id, address);
+                            (RecipientState::Delivered, None)
+                        }
+                        RecipientStatus::Deferred(reply) => {
+                            info!("{}: deferred {}: {}", message.id, address, reply);
+                            (RecipientState::Pending, Some(reply.to_string()))
+                        }
+                        RecipientStatus::Failed(reply) => {
+                            warn!("{}: failed {}: {}", message.id, address, reply);
+                            reports.push((i, Action::Failed, "5.0.0"));
+                            (RecipientState::Failed, Some(reply.to_string()))
+                        }
+                    };
+                    try!(self._set_state(&mut message, i, state, reply));
+                }
+            }
+            Err(ref err) if err.is_transient() => {
+                info!("{}: deferred: {}", message.id, err);
+                error = Some(err.to_string());
+            }
+            Err(err) => {
+                warn!("{}: failed: {}", message.id, err);
+                for &i in pending.iter() {
+                    try!(self._set_state(&mut message, i, RecipientState::Failed,
+                                         Some(err.to_string())));
+                    reports.push((i, Action::Failed, "5.0.0"));
+                }
+            }
+        }
+
+        if message.is_pending() && now >= message.created_at + self.config.max_lifetime {
+            warn!("{}: giving up after {} attempts", message.id, message.attempts + 1);
+            let reason = error.clone().unwrap_or("no more retries".to_string());
+            for i in 0..message.recipients.len() {
+                if message.recipients[i].state == RecipientState::Pending {
+                    let reply = message.recipients[i].last_reply.clone().unwrap_or(reason.clone());
+                    try!(self._set_state(&mut message, i, RecipientState::Failed, Some(reply)));
+                    // delivery time expired (RFC 3463)
+                    reports.push((i, Action::Failed, "4.4.7"));
+                }
+            }
+        }
+        if !reports.is_empty() {
+            try!(self._notify(&message, &payload.data, &reports, now));
+        }
+        if !message.is_pending() {
+            return self.store.remove(&message.id);
+        }
+        let next_attempt = now + self.retry_delay(message.attempts + 1);
+        self.store.defer_until(&message.id, next_attempt, error.as_ref().map(|e| &e[..]))
+    }
+
+    /// Queues a delivery status notification for the sender, if one is due.
+    fn _notify(&self,
+               message: &QueuedMessage,
+               data: &[u8],
+               reports: &[(usize, Action, &str)],
+               now: SystemTime)
+               -> io::Result<()> {
+        let report = DeliveryReport {
+            reporting_mta: self.hostname.clone(),
+            queue_id: message.id.clone(),
+            sender: message.sender.clone(),
+            mail_parameters: message.mail_parameters.clone(),
+            arrival_date: message.created_at,
+            recipients: reports.iter()
+                               .map(|&(i, action, status)| {
+                                   let recipient = &message.recipients[i];
+                                   let reply = recipient.last_reply.clone();
+                                   RecipientReport {
+                                       recipient: recipient.address.clone(),
+                                       parameters: recipient.parameters.clone(),
+                                       action: action,
+                                       status: reply.as_ref()
+                                                    .and_then(|reply| status_code(reply))
+                                                    .unwrap_or(status.to_string()),
+                                       diagnostic: reply,
+                                   }
+                               })
+                               .collect(),
+        };
+        if let Some(notification) = report.notification(data, now) {
+            let queued = try!(self.store.enqueue(&notification));
+            info!("{}: queued delivery status notification {} for {}",
+                  message.id,
+                  queued.id,
+                  notification.recipients[0]);
+        }
+        Ok(())
+    }
+
+    fn _set_state(&self,
+                  message: &mut QueuedMessage,
+                  index: usize,
+                  state: RecipientState,
+                  reply: Option<String>)
+                  -> io::Result<()> {
+        try!(self.store.set_recipient_state(&message.id,
+                                            index,
+                                            state,
+                                            reply.as_ref().map(|r| &r[..])));
+        message.recipients[index].state = state;
+        message.recipients[index].last_reply = reply;
+        Ok(())
+    }
+
+    /// How long to wait after the `attempts`th failed attempt: the initial
+    /// delay, doubled after each attempt up to the maximum.
+    pub fn retry_delay(&self, attempts: u32) -> Duration {
+        let mut delay = self.config.initial_retry;
+        for _ in 1..attempts {
+            if delay >= self.config.max_retry {
+                break;
+            }
+            delay = delay * 2;
+        }
+        if delay > self.config.max_retry {
+            self.config.max_retry
+        } else {
+            delay
+        }
+    }
+
+    /// Delivers messages as they become due until `shutdown` is requested.
+    /// A delivery in progress is finished first.
+    pub fn run(&self, shutdown: &ShutdownHandle) {
+        let mut next_due = None;
+        while !shutdown.is_shutting_down() {
+            let now = SystemTime::now();
+            if self.store.take_changes() || next_due.map_or(false, |due| due <= now) {
+                next_due = match self.run_due(now) {
+                    Ok(next_due) => next_due,
+                    Err(err) => {
+                        error!("Could not run the queue: {}", err);
+                        Some(now + Duration::from_secs(60))
+                    }
+                };
+            }
+            sleep(Duration::from_secs(1));
+        }
+    }
+}
+
+fn _state_name(state: RecipientState) -> &'static str {
+    match state {
+        RecipientState::Pending => "pending",
+        RecipientState::Delivered => "delivered",
+        RecipientState::Failed => "failed",
+    }
+}
+
+fn _parse_state(name: &str) -> Option<RecipientState> {
+    match name {
+        "pending" => Some(RecipientState::Pending),
+        "delivered" => Some(RecipientState::Delivered),
+        "failed" => Some(RecipientState::Failed),
+        _ => None,
+    }
+}
+
+fn _seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn _time(seconds: &str) -> Option<SystemTime> {
+    seconds.parse().ok().map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+fn _one_line(text: &str) -> String {
+    text.chars().map(|c| if c.is_control() { ' ' } else { c }).collect()
+}
+
+fn _format_parameters(parameters: &Parameters) -> String {
+    parameters.0
+              .iter()
+              .map(|&(ref keyword, ref value)| {
+                  match *value {
+                      Some(ref value) => format!("{}={}", keyword, value),
+                      None => keyword.clone(),
+                  }
+              })
+              .collect::<Vec<_>>()
+              .join(" ")
+}
+
+fn _parse_parameters(text: &str) -> Parameters {
+    Parameters(text.split(' ')
+                   .filter(|p| !p.is_empty())
+                   .map(|p| {
+                       let mut parts = p.splitn(2, '=');
+                       (parts.next().unwrap_or("").to_string(), parts.next().map(|v| v.to_string()))
+                   })
+                   .collect())
+}
+
+fn _parse_address(text: &str) -> Option<Address> {
+    let mut parts = text.rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) => Some(Address::new(local, domain)),
+        _ => None,
+    }
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use address::Address;
+    use client::Reply;
+    use config::QueueConfig;
+    use data::Parameters;
+    use envelope::BodyType;
+    use payload::Payload;
+    use payload_handler::{Transport, RecipientStatus, RelayError};
+    use spool::{QueueStore, Spool, Scheduler, RecipientState};
+
+    fn spool_directory(name: &str) -> PathBuf {
+        let directory = env::temp_dir().join(format!("nibbler-spool-test-{}", name));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn payload() -> Payload {
+        let mut payload = Payload::new();
+        payload.sender = Some(Address::new("matt", "example.org"));
+        payload.add_recipient(Address::new("marie", "example.net"));
+        payload.add_recipient(Address::new("busy", "example.net"));
+        payload.envelope.mail_parameters = Parameters(vec![("BODY".to_string(),
+                                                            Some("8BITMIME".to_string()))]);
+        payload.envelope.body_type = BodyType::EightBitMime;
+        payload.envelope.recipient_parameters =
+            vec![Parameters(vec![("NOTIFY".to_string(), Some("FAILURE".to_string()))]),
+                 Parameters::new()];
+        payload.data = b"Subject: hi\r\n\r\nHi\r\n".to_vec();
+        payload
+    }
+
+    /// Answers every attempt with the next entry of `results`, and records
+    /// the recipients it was asked to deliver to.
+    struct StubTransport {
+        results: Mutex<Vec<Result<Vec<RecipientStatus>, RelayError>>>,
+        attempts: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Transport for StubTransport {
+        fn deliver(&self, payload: &Payload)
+                   -> Result<Vec<(Address, RecipientStatus)>, RelayError> {
+            self.attempts
+                .lock()
+                .unwrap()
+                .push(payload.recipients.iter().map(|r| r.to_string()).collect());
+            self.results.lock().unwrap().remove(0).map(|statuses| {
+                payload.recipients.iter().cloned().zip(statuses).collect()
+            })
+        }
+    }
+
+    fn deferred() -> RecipientStatus {
+        RecipientStatus::Deferred(Reply {
+            code: 452,
+            lines: vec!["4.2.2 Mailbox full".to_string()],
+        })
+    }
+
+    /// What every `QueueStore` has to do, given an empty store.
+    pub fn check_store(store: &QueueStore) {
+        let mut first = payload();
+        first.envelope.queue_id = "A00001".to_string();
+        let mut second = payload();
+        second.envelope.queue_id = "B00002".to_string();
+        second.sender = None;
+        let first = store.enqueue(&first).unwrap();
+        let second = store.enqueue(&second).unwrap();
+        assert_eq!(vec![first.clone(), second.clone()], store.messages().unwrap());
+        assert_eq!(second, store.get("B00002").unwrap());
+        assert_eq!(payload().data, store.data("A00001").unwrap());
+        assert_eq!(io::ErrorKind::NotFound, store.get("C00003").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::NotFound, store.data("C00003").unwrap_err().kind());
+
+        // both are due at once, in order, and leased only once
+        let now = first.created_at;
+        let hour = Duration::from_secs(3600);
+        assert_eq!("A00001", store.lease(now, hour).unwrap().unwrap().id);
+        let leased = store.lease(now, hour).unwrap().unwrap();
+        assert_eq!("B00002", leased.id);
+        assert_eq!(Some(now + hour), leased.leased_until);
+        assert_eq!(None, store.lease(now, hour).unwrap());
+        assert_eq!(Some(now + hour), store.next_due().unwrap());
+        // an expired lease is given out again
+        assert_eq!("A00001", store.lease(now + hour, hour).unwrap().unwrap().id);
+
+        store.set_recipient_state("B00002", 1, RecipientState::Failed, Some("550 5.1.1 Unknown"))
+             .unwrap();
+        store.set_recipient_state("B00002", 0, RecipientState::Delivered, None).unwrap();
+        let later = now + Duration::from_secs(60);
+        store.defer_until("B00002", later, Some("connection refused")).unwrap();
+        let deferred = store.get("B00002").unwrap();
+        assert_eq!(1, deferred.attempts);
+        assert_eq!(later, deferred.next_attempt);
+        assert_eq!(None, deferred.leased_until);
+        assert_eq!(Some("connection refused".to_string()), deferred.last_error);
+        assert_eq!(RecipientState::Delivered, deferred.recipients[0].state);
+        assert_eq!(RecipientState::Failed, deferred.recipients[1].state);
+        assert_eq!(Some("550 5.1.1 Unknown".to_string()), deferred.recipients[1].last_reply);
+        assert_eq!(None, store.lease(now + Duration::from_secs(59), hour).unwrap());
+        assert_eq!(Some(later), store.next_due().unwrap());
+        assert_eq!("B00002", store.lease(later, hour).unwrap().unwrap().id);
+        assert!(store.set_recipient_state("B00002", 2, RecipientState::Failed, None).is_err());
+
+        // held messages are not due until released
+        store.defer_until("B00002", later, None).unwrap();
+        store.set_held("B00002", true).unwrap();
+        assert!(store.get("B00002").unwrap().held);
+        store.reschedule("B00002", now).unwrap();
+        assert_eq!(now, store.get("B00002").unwrap().next_attempt);
+        assert_eq!(2, store.get("B00002").unwrap().attempts);
+        assert_eq!(None, store.lease(later, hour).unwrap());
+        store.set_held("B00002", false).unwrap();
+        assert_eq!("B00002", store.lease(later, hour).unwrap().unwrap().id);
+        assert_eq!(io::ErrorKind::NotFound,
+                   store.set_held("C00003", true).unwrap_err().kind());
+
+        store.remove("A00001").unwrap();
+        assert_eq!(io::ErrorKind::NotFound, store.remove("A00001").unwrap_err().kind());
+        assert_eq!(vec!["B00002"],
+                   store.messages().unwrap().iter().map(|m| &m.id[..]).collect::<Vec<_>>());
+        assert_eq!(io::ErrorKind::NotFound,
+                   store.defer_until("A00001", later, None).unwrap_err().kind());
+        store.remove("B00002").unwrap();
+        assert_eq!(None, store.next_due().unwrap());
+    }
+
+    #[test]
+    fn spool_conforms() {
+        let directory = spool_directory("conformance");
+        check_store(&Spool::open(&directory).unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let directory = spool_directory("restart");
+        let payload = payload();
+        let queued = Spool::open(&directory).unwrap().enqueue(&payload).unwrap();
+        fs::write(directory.join("LEFTOVER.msg.tmp"), b"half written").unwrap();
+        fs::write(directory.join("ORPHAN.msg"), b"no envelope").unwrap();
+
+        let spool = Spool::open(&directory).unwrap();
+        assert_eq!(vec![queued.clone()], spool.messages().unwrap());
+        assert_eq!(payload.data, spool.data(&queued.id).unwrap());
+        assert!(!directory.join("LEFTOVER.msg.tmp").exists());
+        assert!(!directory.join("ORPHAN.msg").exists());
+        let restored = queued.payload(spool.data(&queued.id).unwrap());
+        assert_eq!(payload.recipients, restored.recipients);
+        assert_eq!(payload.envelope.recipient_parameters,
+                   restored.envelope.recipient_parameters);
+        assert_eq!(payload.envelope.body_type, restored.envelope.body_type);
+
+        spool.remove(&queued.id).unwrap();
+        assert_eq!(0, spool.messages().unwrap().len());
+        assert!(spool.get("../etc/passwd").is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn sets_aside_corrupt_envelopes() {
+        let directory = spool_directory("corrupt");
+        let spool = Spool::open(&directory).unwrap();
+        let queued = spool.enqueue(&payload()).unwrap();
+        // sorts before the good one
+        fs::write(directory.join("0.env"), b"not an envelope").unwrap();
+        fs::write(directory.join("0.msg"), b"Subject: hi\r\n\r\nHi\r\n").unwrap();
+
+        assert_eq!(vec![queued.id.clone()],
+                   spool.messages().unwrap().into_iter().map(|m| m.id).collect::<Vec<_>>());
+        let leased = spool.lease(queued.next_attempt, Duration::from_secs(60)).unwrap();
+        assert_eq!(Some(queued.id), leased.map(|m| m.id));
+        assert!(directory.join("0.env.bad").exists());
+        assert!(directory.join("0.msg.bad").exists());
+        assert!(!directory.join("0.env").exists());
+        // the data of the bad message survives a restart
+        Spool::open(&directory).unwrap();
+        assert!(directory.join("0.msg.bad").exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn retries_with_backoff() {
+        let directory = spool_directory("backoff");
+        let spool = Arc::new(Spool::open(&directory).unwrap());
+        let id = spool.enqueue(&payload()).unwrap().id;
+        let transport = Arc::new(StubTransport {
+            results: Mutex::new(vec![Ok(vec![RecipientStatus::Delivered, deferred()]),
+                                     Err(RelayError::StartTlsUnavailable),
+                                     Ok(vec![RecipientStatus::Delivered])]),
+            attempts: Mutex::new(Vec::new()),
+        });
+        let mut config = QueueConfig::new(&directory);
+        config.initial_retry = Duration::from_secs(60);
+        config.max_retry = Duration::from_secs(100);
+        let scheduler = Scheduler::new(spool.clone(), transport.clone(), config);
+        assert_eq!(vec![60, 100, 100],
+                   (1..4).map(|n| scheduler.retry_delay(n).as_secs()).collect::<Vec<_>>());
+
+        let start = spool.get(&id).unwrap().created_at;
+        assert_eq!(Some(start + Duration::from_secs(60)), scheduler.run_due(start).unwrap());
+        let message = spool.get(&id).unwrap();
+        assert_eq!(1, message.attempts);
+        assert_eq!(RecipientState::Delivered, message.recipients[0].state);
+        assert_eq!(RecipientState::Pending, message.recipients[1].state);
+        assert_eq!(Some("452 4.2.2 Mailbox full".to_string()),
+                   message.recipients[1].last_reply);
+
+        // not due yet
+        assert!(scheduler.run_due(start + Duration::from_secs(30)).unwrap().is_some());
+        let later = start + Duration::from_secs(60);
+        assert_eq!(Some(later + Duration::from_secs(100)),
+                   scheduler.run_due(later).unwrap());
+        assert_eq!(Some("relay does not offer STARTTLS".to_string()),
+                   spool.get(&id).unwrap().last_error);
+
+        assert_eq!(None, scheduler.run_due(later + Duration::from_secs(100)).unwrap());
+        assert_eq!(0, spool.messages().unwrap().len());
+        assert_eq!(vec![vec!["marie@example.net", "busy@example.net"],
+                        vec!["busy@example.net"],
+                        vec!["busy@example.net"]],
+                   *transport.attempts.lock().unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reports_delivered_and_relayed_recipients() {
+        let directory = spool_directory("success");
+        let spool = Arc::new(Spool::open(&directory).unwrap());
+        let mut payload = payload();
+        payload.add_recipient(Address::new("max", "example.net"));
+        let notify = Parameters(vec![("NOTIFY".to_string(), Some("SUCCESS".to_string()))]);
+        payload.envelope.recipient_parameters = vec![notify.clone(), notify.clone(), notify];
+        let id = spool.enqueue(&payload).unwrap().id;
+        let transport = Arc::new(StubTransport {
+            results: Mutex::new(vec![Ok(vec![RecipientStatus::Delivered,
+                                             RecipientStatus::Relayed,
+                                             RecipientStatus::RelayedWithDsn])]),
+            attempts: Mutex::new(Vec::new()),
+        });
+        let scheduler = Scheduler::new(spool.clone(), transport, QueueConfig::new(&directory));
+        let message = spool.get(&id).unwrap();
+        let now = message.created_at;
+        scheduler.attempt(message, now).unwrap();
+        assert!(spool.get(&id).is_err());
+
+        // max@example.net hears from the next server
+        let notifications = spool.messages().unwrap();
+        assert_eq!(1, notifications.len());
+        let text = String::from_utf8(spool.data(&notifications[0].id).unwrap()).unwrap();
+        assert!(text.contains("Final-Recipient: rfc822; marie@example.net\r\nAction: \
+                               delivered\r\nStatus: 2.0.0\r\n"));
+        assert!(text.contains("Final-Recipient: rfc822; busy@example.net\r\nAction: \
+                               relayed\r\nStatus: 2.0.0\r\n"));
+        assert!(!text.contains("max@example.net"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn gives_up_after_max_lifetime() {
+        let directory = spool_directory("expiry");
+        let spool = Arc::new(Spool::open(&directory).unwrap());
+        let id = spool.enqueue(&payload()).unwrap().id;
+        let transport = Arc::new(StubTransport {
+            results: Mutex::new(vec![Ok(vec![deferred(), deferred()])]),
+            attempts: Mutex::new(Vec::new()),
+        });
+        let mut config = QueueConfig::new(&directory);
+        config.max_lifetime = Duration::from_secs(3600);
+        let scheduler = Scheduler::new(spool.clone(), transport, config);
+        let message = spool.get(&id).unwrap();
+        let expired = message.created_at + Duration::from_secs(3600);
+        scheduler.attempt(message, expired).unwrap();
+        assert!(spool.get(&id).is_err());
+
+        // the sender is told, and nobody would be told about that failing
+        let notifications = spool.messages().unwrap();
+        assert_eq!(1, notifications.len());
+        assert_eq!(None, notifications[0].sender);
+        assert_eq!(Address::new("matt", "example.org"),
+                   notifications[0].recipients[0].address);
+        let text = String::from_utf8(spool.data(&notifications[0].id).unwrap()).unwrap();
+        assert!(text.contains("Final-Recipient: rfc822; busy@example.net\r\nAction: \
+                               failed\r\nStatus: 4.2.2\r\n"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rusqlite::{self, Connection, OptionalExtension, Row};
+use payload::Payload;
+use spool::{QueueStore, QueuedMessage, QueuedRecipient, RecipientState, _format_parameters,
+            _parse_parameters, _parse_address, _seconds, _state_name, _parse_state};
+
+const SCHEMA: &'static str = "
+    PRAGMA journal_mode = WAL;
+    PRAGMA synchronous = FULL;
+    PRAGMA foreign_keys = ON;
+    CREATE TABLE IF NOT EXISTS messages (
+        id TEXT PRIMARY KEY,
+        sender TEXT,
+        mail_parameters TEXT NOT NULL,
+        data BLOB NOT NULL,
+        created_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL,
+        next_attempt INTEGER NOT NULL,
+        leased_until INTEGER,
+        held INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT
+    );
+    CREATE INDEX IF NOT EXISTS messages_next_attempt ON messages (next_attempt);
+    CREATE TABLE IF NOT EXISTS recipients (
+        message_id TEXT NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        address TEXT NOT NULL,
+        parameters TEXT NOT NULL,
+        state TEXT NOT NULL,
+        last_reply TEXT,
+        PRIMARY KEY (message_id, position)
+    );
+";
+
+/// Queued messages in an SQLite database, data included. Every change is
+/// one transaction, synced before it returns. Unlike `Spool` the queue can
+/// be inspected with SQL, e.g. `SELECT * FROM recipients WHERE state =
+/// 'failed'`.
+pub struct SqliteSpool {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteSpool {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<SqliteSpool> {
+        let connection = try!(Connection::open(path).map_err(_sql_error));
+        // `smtp queue` may be using it at the same time
+        try!(connection.busy_timeout(Duration::from_secs(10)).map_err(_sql_error));
+        try!(connection.execute_batch(SCHEMA).map_err(_sql_error));
+        Ok(SqliteSpool { connection: Mutex::new(connection) })
+    }
+}
+
+impl QueueStore for SqliteSpool {
+    fn enqueue(&self, payload: &Payload) -> io::Result<QueuedMessage> {
+        let message = QueuedMessage::new(payload);
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = try!(connection.transaction().map_err(_sql_error));
+        try!(transaction.execute("INSERT INTO messages (id, sender, mail_parameters, data, \
+                                  created_at, attempts, next_attempt) VALUES (?, ?, ?, ?, ?, \
+                                  0, ?)",
+                                 params![message.id,
+                                         message.sender.as_ref().map(|s| s.to_string()),
+                                         _format_parameters(&message.mail_parameters),
+                                         payload.data,
+                                         _seconds(message.created_at) as i64,
+                                         _seconds(message.next_attempt) as i64])
+                        .map_err(_sql_error));
+        for (position, recipient) in message.recipients.iter().enumerate() {
+            try!(transaction.execute("INSERT INTO recipients (message_id, position, address, \
+                                      parameters, state) VALUES (?, ?, ?, ?, ?)",
+                                     params![message.id,
+                                             position as i64,
+                                             recipient.address.to_string(),
+                                             _format_parameters(&recipient.parameters),
+                                             _state_name(recipient.state)])
+                            .map_err(_sql_error));
+        }
+        try!(transaction.commit().map_err(_sql_error));
+        Ok(message)
+    }
+
+    fn messages(&self) -> io::Result<Vec<QueuedMessage>> {
+        let connection = self.connection.lock().unwrap();
+        let ids = {
+            let mut statement = try!(connection.prepare("SELECT id FROM messages ORDER BY id")
+                                               .map_err(_sql_error));
+            let ids = try!(statement.query_map(params![], |row| row.get::<_, String>(0))
+                                    .map_err(_sql_error));
+            try!(ids.collect::<Result<Vec<_>, _>>().map_err(_sql_error))
+        };
+        ids.iter().map(|id| _load(&connection, id)).collect()
+    }
+
+    fn get(&self, id: &str) -> io::Result<QueuedMessage> {
+        _load(&self.connection.lock().unwrap(), id)
+    }
+
+    fn data(&self, id: &str) -> io::Result<Vec<u8>> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT data FROM messages WHERE id = ?",
+                       params![id],
+                       |row| row.get(0))
+            .map_err(_sql_error)
+    }
+
+    fn lease(&self, now: SystemTime, duration: Duration) -> io::Result<Option<QueuedMessage>> {
+        let mut connection = self.connection.lock().unwrap();
+        let now_seconds = _seconds(now) as i64;
+        let id = {
+            let transaction = try!(connection.transaction().map_err(_sql_error));
+            let id = try!(transaction.query_row("SELECT id FROM messages WHERE next_attempt <= \
+                                                 ?1 AND NOT held AND (leased_until IS NULL OR \
+                                                 leased_until <= ?1) ORDER BY id LIMIT 1",
+                                                params![now_seconds],
+                                                |row| row.get::<_, String>(0))
+                                     .optional()
+                                     .map_err(_sql_error));
+            let id = match id {
+                Some(id) => id,
+                None => return Ok(None),
+            };
+            try!(transaction.execute("UPDATE messages SET leased_until = ? WHERE id = ?",
+                                     params![_seconds(now + duration) as i64, id])
+                            .map_err(_sql_error));
+            try!(transaction.commit().map_err(_sql_error));
+            id
+        };
+        _load(&connection, &id).map(Some)
+    }
+
+    fn set_recipient_state(&self,
+                           id: &str,
+                           index: usize,
+                           state: RecipientState,
+                           reply: Option<&str>)
+                           -> io::Result<()> {
+        let changed = try!(self.connection
+                               .lock()
+                               .unwrap()
+                               .execute("UPDATE recipients SET state = ?, last_reply = ? WHERE \
+                                         message_id = ? AND position = ?",
+                                        params![_state_name(state), reply, id, index as i64])
+                               .map_err(_sql_error));
+        _changed(changed, id)
+    }
+
+    fn defer_until(&self, id: &str, until: SystemTime, error: Option<&str>) -> io::Result<()> {
+        let changed = try!(self.connection
+                               .lock()
+                               .unwrap()
+                               .execute("UPDATE messages SET attempts = attempts + 1, \
+                                         next_attempt = ?, leased_until = NULL, last_error = ? \
+                                         WHERE id = ?",
+                                        params![_seconds(until) as i64, error, id])
+                               .map_err(_sql_error));
+        _changed(changed, id)
+    }
+
+    fn reschedule(&self, id: &str, at: SystemTime) -> io::Result<()> {
+        let changed = try!(self.connection
+                               .lock()
+                               .unwrap()
+                               .execute("UPDATE messages SET next_attempt = ? WHERE id = ?",
+                                        params![_seconds(at) as i64, id])
+                               .map_err(_sql_error));
+        _changed(changed, id)
+    }
+
+    fn set_held(&self, id: &str, held: bool) -> io::Result<()> {
+        let changed = try!(self.connection
+                               .lock()
+                               .unwrap()
+                               .execute("UPDATE messages SET held = ? WHERE id = ?",
+                                        params![held, id])
+                               .map_err(_sql_error));
+        _changed(changed, id)
+    }
+
+    fn remove(&self, id: &str) -> io::Result<()> {
+        let changed = try!(self.connection
+                               .lock()
+                               .unwrap()
+                               .execute("DELETE FROM messages WHERE id = ?", params![id])
+                               .map_err(_sql_error));
+        _changed(changed, id)
+    }
+
+    fn next_due(&self) -> io::Result<Option<SystemTime>> {
+        let due = try!(self.connection
+                           .lock()
+                           .unwrap()
+                           .query_row("SELECT MIN(MAX(next_attempt, COALESCE(leased_until, \
+                                       next_attempt))) FROM messages WHERE NOT held",
+                                      params![],
+                                      |row| row.get::<_, Option<i64>>(0))
+                           .map_err(_sql_error));
+        Ok(due.map(_time))
+    }
+}
+
+fn _load(connection: &Connection, id: &str) -> io::Result<QueuedMessage> {
+    let mut message = try!(connection.query_row("SELECT id, sender, mail_parameters, \
+                                                 created_at, attempts, next_attempt, \
+                                                 leased_until, held, last_error FROM messages \
+                                                 WHERE id = ?",
+                                                params![id],
+                                                _message)
+                                     .map_err(_sql_error));
+    let mut statement = try!(connection.prepare("SELECT address, parameters, state, last_reply \
+                                                 FROM recipients WHERE message_id = ? ORDER BY \
+                                                 position")
+                                       .map_err(_sql_error));
+    let recipients = try!(statement.query_map(params![id], _recipient).map_err(_sql_error));
+    for recipient in recipients {
+        message.recipients.push(try!(try!(recipient.map_err(_sql_error))));
+    }
+    Ok(message)
+}
+
+fn _message(row: &Row) -> rusqlite::Result<QueuedMessage> {
+    let sender = try!(row.get::<_, Option<String>>(1));
+    Ok(QueuedMessage {
+        id: try!(row.get(0)),
+        sender: sender.as_ref().and_then(|sender| _parse_address(sender)),
+        mail_parameters: _parse_parameters(&try!(row.get::<_, String>(2))),
+        recipients: Vec::new(),
+        created_at: _time(try!(row.get(3))),
+        attempts: try!(row.get::<_, i64>(4)) as u32,
+        next_attempt: _time(try!(row.get(5))),
+        leased_until: try!(row.get::<_, Option<i64>>(6)).map(_time),
+        held: try!(row.get(7)),
+        last_error: try!(row.get(8)),
+    })
+}
+
+/// Fails with the outer result if the row cannot be read and with the inner
+/// one if it holds something the spool would not have written.
+fn _recipient(row: &Row) -> rusqlite::Result<io::Result<QueuedRecipient>> {
+    let address = try!(row.get::<_, String>(0));
+    let state = try!(row.get::<_, String>(2));
+    let (address, state) = match (_parse_address(&address), _parse_state(&state)) {
+        (Some(address), Some(state)) => (address, state),
+        _ => {
+            return Ok(Err(io::Error::new(io::ErrorKind::InvalidData,
+                                         format!("malformed recipient {} ({})", address, state))))
+        }
+    };
+    Ok(Ok(QueuedRecipient {
+        address: address,
+        parameters: _parse_parameters(&try!(row.get::<_, String>(1))),
+        state: state,
+        last_reply: try!(row.get(3)),
+    }))
+}
+
+fn _time(seconds: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(seconds as u64)
+}
+
+fn _changed(rows: usize, id: &str) -> io::Result<()> {
+    if rows == 0 {
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: not in the queue", id)))
+    } else {
+        Ok(())
+    }
+}
+
+fn _sql_error(err: rusqlite::Error) -> io::Error {
+    match err {
+        rusqlite::Error::QueryReturnedNoRows => {
+            io::Error::new(io::ErrorKind::NotFound, "not in the queue")
+        }
+        err => io::Error::new(io::ErrorKind::Other, err.to_string()),
+    }
+}
+
+pub mod tests {
+    use std::env;
+    use std::fs;
+    use spool::sqlite::SqliteSpool;
+    use spool::tests::check_store;
+
+    #[test]
+    fn sqlite_spool_conforms() {
+        let directory = env::temp_dir().join("nibbler-sqlite-spool-test");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        check_store(&SqliteSpool::open(directory.join("queue.sqlite")).unwrap());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
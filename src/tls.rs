@@ -0,0 +1,66 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use native_tls::{self, Identity, TlsStream};
+use auth::ChannelBinding;
+use config::TlsConfig;
+use session::TlsInfo;
+use smtp::Connection;
+
+/// Server side of TLS, for SMTPS listeners and STARTTLS (RFC 3207).
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    acceptor: native_tls::TlsAcceptor,
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TlsAcceptor")
+    }
+}
+
+impl TlsAcceptor {
+    pub fn from_config(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+        let certificate = try!(_read_file(&config.certificate));
+        let private_key = try!(_read_file(&config.private_key));
+        let identity = try!(Identity::from_pkcs8(&certificate, &private_key).map_err(_io_error));
+        let acceptor = try!(native_tls::TlsAcceptor::new(identity).map_err(_io_error));
+        Ok(TlsAcceptor { acceptor: acceptor })
+    }
+
+    /// Performs the handshake. The `TlsInfo` carries the `tls-server-end-point`
+    /// channel binding (RFC 5929) for SCRAM-SHA-256-PLUS.
+    pub fn accept<S: Read + Write>(&self, stream: S) -> io::Result<(TlsStream<S>, TlsInfo)> {
+        let stream = match self.acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(native_tls::HandshakeError::Failure(err)) => return Err(_io_error(err)),
+            Err(native_tls::HandshakeError::WouldBlock(_)) => {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "TLS handshake interrupted"))
+            }
+        };
+        let mut info = TlsInfo::new();
+        if let Ok(Some(end_point)) = stream.tls_server_end_point() {
+            info.channel_binding = Some(ChannelBinding::new("tls-server-end-point", end_point));
+        }
+        Ok((stream, info))
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.get_ref().set_read_timeout(timeout)
+    }
+}
+
+fn _read_file(path: &::std::path::Path) -> io::Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    try!(File::open(path).and_then(|mut file| file.read_to_end(&mut contents)));
+    Ok(contents)
+}
+
+fn _io_error(err: native_tls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
@@ -0,0 +1,101 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// The parsed contents of a file, read again whenever its modification time
+/// changes. If the file can not be read again the old contents are kept.
+pub struct WatchedFile<T> {
+    path: PathBuf,
+    /// What the file is, for log messages.
+    description: &'static str,
+    read: fn(&Path) -> io::Result<T>,
+    contents: RwLock<(Option<SystemTime>, Arc<T>)>,
+}
+
+impl<T> WatchedFile<T> {
+    /// Fails if the file can not be read.
+    pub fn open<P: AsRef<Path>>(path: P,
+                                description: &'static str,
+                                read: fn(&Path) -> io::Result<T>)
+                                -> io::Result<WatchedFile<T>> {
+        let path = path.as_ref().to_path_buf();
+        let modified = try!(fs::metadata(&path)).modified().ok();
+        let contents = try!(read(&path));
+        Ok(WatchedFile {
+            path: path,
+            description: description,
+            read: read,
+            contents: RwLock::new((modified, Arc::new(contents))),
+        })
+    }
+
+    /// The current contents, after reading the file again if it changed.
+    pub fn get(&self) -> Arc<T> {
+        self._reload_if_changed();
+        self.contents.read().unwrap().1.clone()
+    }
+
+    fn _reload_if_changed(&self) {
+        let modified = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata.modified().ok(),
+            Err(err) => {
+                error!("Cannot stat {} {}: {}", self.description, self.path.display(), err);
+                return;
+            }
+        };
+        if self.contents.read().unwrap().0 == modified {
+            return;
+        }
+
+        match (self.read)(&self.path) {
+            Ok(contents) => {
+                info!("Reloaded {} {}", self.description, self.path.display());
+                *self.contents.write().unwrap() = (modified, Arc::new(contents));
+            }
+            Err(err) => {
+                error!("Cannot reload {} {}: {}", self.description, self.path.display(), err)
+            }
+        }
+    }
+}
+
+pub mod tests {
+    use std::env;
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::io;
+    use std::io::{Read, Write};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+    use std::process;
+    use libc;
+    use watched_file::WatchedFile;
+
+    fn read_string(path: &Path) -> io::Result<String> {
+        let mut contents = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut contents));
+        Ok(contents)
+    }
+
+    #[test]
+    fn reloads_changed_files() {
+        let path = env::temp_dir().join(format!("nibbler-watched-file-test-{}", process::id()));
+        File::create(&path).unwrap().write_all(b"first").unwrap();
+        // back-date the file instead of waiting for the clock to move on,
+        // modification times may only have a resolution of one second
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let times = [libc::timeval { tv_sec: 1000, tv_usec: 0 }; 2];
+        assert_eq!(0, unsafe { libc::utimes(c_path.as_ptr(), times.as_ptr()) });
+        let file = WatchedFile::open(&path, "test file", read_string).unwrap();
+        assert_eq!("first", &file.get()[..]);
+
+        File::create(&path).unwrap().write_all(b"second").unwrap();
+        assert_eq!("second", &file.get()[..]);
+        // keeps the contents it has
+        fs::remove_file(&path).unwrap();
+        assert_eq!("second", &file.get()[..]);
+        assert!(WatchedFile::open(&path, "test file", read_string).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{spawn, JoinHandle};
+
+type Job = Box<FnMut() + Send>;
+
+/// A fixed number of threads running jobs in the order they were queued.
+pub struct WorkerPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> WorkerPool {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+                          .map(|_| {
+                              let receiver = receiver.clone();
+                              spawn(move || _work(receiver))
+                          })
+                          .collect();
+        WorkerPool {
+            sender: Some(sender),
+            workers: workers,
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let mut job = Some(job);
+        let job: Job = Box::new(move || {
+            if let Some(job) = job.take() {
+                job()
+            }
+        });
+        if let Some(ref sender) = self.sender {
+            let _ = sender.send(job);
+        }
+    }
+}
+
+fn _work(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        // a panicking session must not take the worker with it
+        if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+            error!("Worker job panicked");
+        }
+    }
+}
+
+// Waits for all queued jobs to finish.
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use worker_pool::WorkerPool;
+
+    #[test]
+    fn runs_jobs_on_a_fixed_number_of_threads() {
+        let running = Arc::new(Mutex::new((0, 0)));
+        {
+            let pool = WorkerPool::new(2);
+            for _ in 0..6 {
+                let running = running.clone();
+                pool.execute(move || {
+                    {
+                        let mut running = running.lock().unwrap();
+                        running.0 += 1;
+                        running.1 = ::std::cmp::max(running.1, running.0);
+                    }
+                    sleep(Duration::from_millis(20));
+                    running.lock().unwrap().0 -= 1;
+                });
+            }
+        }
+        let running = running.lock().unwrap();
+        assert_eq!(0, running.0);
+        assert_eq!(2, running.1);
+    }
+
+    #[test]
+    fn survives_panicking_jobs() {
+        let finished = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = WorkerPool::new(1);
+            pool.execute(|| panic!("session failed"));
+            for _ in 0..3 {
+                let finished = finished.clone();
+                pool.execute(move || {
+                    finished.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(3, finished.load(Ordering::SeqCst));
+    }
+}